@@ -0,0 +1,22 @@
+//! Times solving the zebra puzzle: `cargo bench --bench zebra`
+
+use std::time::{Duration, Instant};
+
+use esgueva::machine::Machine;
+
+const RUNS: u32 = 20;
+
+fn main() {
+    let mut machine = Machine::new();
+    machine.consult(include_str!("../zebra.pl")).expect("zebra.pl loads");
+    let mut times = Vec::new();
+    for _ in 0..RUNS {
+	let start = Instant::now();
+	// All the answers, so the whole search space is explored
+	let answers = machine.query("zebra(H, W, Z).").count();
+	assert_eq!(answers, 1);
+	times.push(start.elapsed());
+    }
+    let best = times.iter().min().expect("timed runs");
+    println!("zebra: {:?} per run, best {:?}", times.iter().sum::<Duration>() / RUNS, best);
+}
@@ -0,0 +1,322 @@
+use std::cmp::Ordering;
+
+use crate::atom::Atom;
+use crate::error::{PrologError, instantiation_error, type_error, evaluation_error, indicator};
+use crate::integer::Integer;
+use crate::term::Term;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Int(Integer),
+    Float(f64),
+}
+
+impl Number {
+    pub fn to_term(self) -> Term {
+	match self {
+	    Number::Int(x) => Term::Int(x),
+	    Number::Float(x) => Term::Float(x),
+	}
+    }
+
+    fn to_float(&self) -> f64 {
+	match self {
+	    Number::Int(x) => x.to_f64(),
+	    Number::Float(x) => *x,
+	}
+    }
+}
+
+impl From<i64> for Number {
+    fn from(x: i64) -> Self {
+	Number::Int(Integer::from(x))
+    }
+}
+
+/// A step of evaluating an expression with an explicit stack
+enum EvalStep<'t> {
+    /// Evaluate this term
+    Visit(&'t Term),
+    /// Apply the function with this name to the last values evaluated
+    Apply(&'t Atom, usize),
+}
+
+/// Evaluates an arithmetic expression. The term must have its bindings
+/// already substituted. It uses an explicit stack, so very long
+/// expressions like `1+1+...+1` don't exhaust the Rust stack.
+pub fn eval(term: &Term) -> Result<Number, PrologError> {
+    let mut pending = vec![EvalStep::Visit(term)];
+    let mut values = Vec::new();
+    while let Some(step) = pending.pop() {
+	let value = match step {
+	    EvalStep::Visit(term) => match term {
+		Term::Int(x) => Number::Int(x.clone()),
+		Term::Float(x) => Number::Float(*x),
+		Term::Var(_) => return Err(instantiation_error()),
+		Term::Atom(name) => eval_constant(&name.name())?,
+		Term::Str(name, args) if args.len() == 1 || args.len() == 2 => {
+		    // The arguments are evaluated from the first one
+		    pending.push(EvalStep::Apply(name, args.len()));
+		    pending.extend(args.iter().rev().map(EvalStep::Visit));
+		    continue;
+		}
+		Term::Str(name, args) => return Err(type_error("evaluable", indicator(&name.name(), args.len()))),
+	    },
+	    EvalStep::Apply(name, 1) => {
+		let x = values.pop().expect("evaluated argument");
+		eval_unary(&name.name(), x)?
+	    }
+	    EvalStep::Apply(name, _) => {
+		let y = values.pop().expect("evaluated argument");
+		let x = values.pop().expect("evaluated argument");
+		eval_binary(&name.name(), x, y)?
+	    }
+	};
+	values.push(value);
+    }
+    Ok(values.pop().expect("evaluated expression"))
+}
+
+/// Compares two numbers by value, so `1 =:= 1.0`
+pub fn compare(x: &Number, y: &Number) -> Ordering {
+    match (x, y) {
+	(Number::Int(x), Number::Int(y)) => x.cmp(y),
+	(x, y) => x.to_float().partial_cmp(&y.to_float()).unwrap_or(Ordering::Equal),
+    }
+}
+
+fn eval_constant(name: &str) -> Result<Number, PrologError> {
+    match name {
+	"pi" => Ok(Number::Float(std::f64::consts::PI)),
+	"e" => Ok(Number::Float(std::f64::consts::E)),
+	"inf" | "infinite" => Ok(Number::Float(f64::INFINITY)),
+	"nan" => Ok(Number::Float(f64::NAN)),
+	"epsilon" => Ok(Number::Float(f64::EPSILON)),
+	"max_tagged_integer" => Ok(Number::from(i64::MAX)),
+	"min_tagged_integer" => Ok(Number::from(i64::MIN)),
+	"[]" => Err(type_error("evaluable", Term::Atom(name.into()))),
+	_ => Err(type_error("evaluable", indicator(name, 0))),
+    }
+}
+
+fn eval_unary(name: &str, x: Number) -> Result<Number, PrologError> {
+    match (name, x) {
+	("-", Number::Int(x)) => int_result(x.checked_neg()),
+	("-", Number::Float(x)) => Ok(Number::Float(-x)),
+	("+", x) => Ok(x),
+	("abs", Number::Int(x)) => int_result(x.checked_abs()),
+	("abs", Number::Float(x)) => Ok(Number::Float(x.abs())),
+	("sign", Number::Int(x)) => Ok(Number::from(x.signum())),
+	("sign", Number::Float(x)) => Ok(Number::Float(if x == 0.0 { 0.0 } else { x.signum() })),
+	("float", x) => float_function(x.to_float()),
+	("float_integer_part", x) => Ok(Number::Float(x.to_float().trunc())),
+	("float_fractional_part", x) => Ok(Number::Float(x.to_float().fract())),
+	("integer" | "truncate" | "round" | "ceiling" | "floor", Number::Int(x)) => Ok(Number::Int(x)),
+	("integer" | "round", Number::Float(x)) => float_to_int(x.round()),
+	("truncate", Number::Float(x)) => float_to_int(x.trunc()),
+	("ceiling", Number::Float(x)) => float_to_int(x.ceil()),
+	("floor", Number::Float(x)) => float_to_int(x.floor()),
+	("sqrt", x) => float_function(x.to_float().sqrt()),
+	("sin", x) => float_function(x.to_float().sin()),
+	("cos", x) => float_function(x.to_float().cos()),
+	("tan", x) => float_function(x.to_float().tan()),
+	("asin", x) => float_function(x.to_float().asin()),
+	("acos", x) => float_function(x.to_float().acos()),
+	("atan", x) => float_function(x.to_float().atan()),
+	("sinh", x) => float_function(x.to_float().sinh()),
+	("cosh", x) => float_function(x.to_float().cosh()),
+	("tanh", x) => float_function(x.to_float().tanh()),
+	("asinh", x) => float_function(x.to_float().asinh()),
+	("acosh", x) => float_function(x.to_float().acosh()),
+	("atanh", x) => float_function(x.to_float().atanh()),
+	("exp", x) => float_function(x.to_float().exp()),
+	("log", x) if x.to_float() <= 0.0 => Err(evaluation_error("undefined")),
+	("log", x) => float_function(x.to_float().ln()),
+	("log2", x) if x.to_float() <= 0.0 => Err(evaluation_error("undefined")),
+	("log2", x) => float_function(x.to_float().log2()),
+	("\\", Number::Int(x)) => Ok(Number::Int(x.not())),
+	("msb", Number::Int(x)) => match x.msb() {
+	    Some(msb) => Ok(Number::from(msb as i64)),
+	    None => Err(evaluation_error("undefined")),
+	}
+	("succ", Number::Int(x)) => int_result(x.checked_add(&Integer::from(1))),
+	("\\" | "msb" | "succ", Number::Float(x)) => Err(type_error("integer", Term::Float(x))),
+	_ => Err(type_error("evaluable", indicator(name, 1))),
+    }
+}
+
+fn eval_binary(name: &str, x: Number, y: Number) -> Result<Number, PrologError> {
+    match (name, x, y) {
+	("+", Number::Int(x), Number::Int(y)) => int_result(x.checked_add(&y)),
+	("-", Number::Int(x), Number::Int(y)) => int_result(x.checked_sub(&y)),
+	("*", Number::Int(x), Number::Int(y)) => int_result(x.checked_mul(&y)),
+	("+", x, y) => float_function(x.to_float() + y.to_float()),
+	("-", x, y) => float_function(x.to_float() - y.to_float()),
+	("*", x, y) => float_function(x.to_float() * y.to_float()),
+	("/" | "//" | "mod" | "rem" | "div", Number::Int(_), Number::Int(y)) if y.is_zero() => Err(evaluation_error("zero_divisor")),
+	("/", Number::Int(x), Number::Int(y)) if x.rem_trunc(&y).is_zero() => int_result(x.div_trunc(&y)),
+	("/", _, y) if y.to_float() == 0.0 => Err(evaluation_error("zero_divisor")),
+	("/", x, y) => float_function(x.to_float() / y.to_float()),
+	("//", Number::Int(x), Number::Int(y)) => int_result(x.div_trunc(&y)),
+	("rem", Number::Int(x), Number::Int(y)) => Ok(Number::Int(x.rem_trunc(&y))),
+	("mod", Number::Int(x), Number::Int(y)) => Ok(Number::Int(x.mod_floor(&y))),
+	("div", Number::Int(x), Number::Int(y)) => int_result(x.div_floor(&y)),
+	("min", x, y) => Ok(if compare(&x, &y) == Ordering::Greater { y } else { x }),
+	("max", x, y) => Ok(if compare(&x, &y) == Ordering::Less { y } else { x }),
+	("**", Number::Int(x), Number::Int(y)) if y.signum() >= 0 => int_pow(x, y),
+	("**", x, y) => float_function(x.to_float().powf(y.to_float())),
+	("^", Number::Int(x), Number::Int(y)) if y.signum() >= 0 => int_pow(x, y),
+	("^", Number::Int(x), Number::Int(y)) => match x.to_i64() {
+	    Some(1) => Ok(Number::from(1)),
+	    Some(-1) => Ok(Number::from(if y.rem_trunc(&Integer::from(2)).is_zero() { 1 } else { -1 })),
+	    Some(0) => Err(evaluation_error("zero_divisor")),
+	    _ => Err(type_error("float", Term::Int(x))),
+	}
+	("^", x, y) => float_function(x.to_float().powf(y.to_float())),
+	("atan2" | "atan", x, y) => float_function(x.to_float().atan2(y.to_float())),
+	("copysign", x, y) => float_function(x.to_float().copysign(y.to_float())),
+	("log", x, y) if x.to_float() <= 0.0 || y.to_float() <= 0.0 => Err(evaluation_error("undefined")),
+	("log", x, y) => float_function(y.to_float().ln() / x.to_float().ln()),
+	(">>", Number::Int(x), Number::Int(y)) => Ok(Number::Int(x.shr(shift_amount(&y)))),
+	("<<", Number::Int(x), Number::Int(y)) => int_result(x.checked_shl(shift_amount(&y))),
+	("/\\", Number::Int(x), Number::Int(y)) => Ok(Number::Int(x.bitand(&y))),
+	("\\/", Number::Int(x), Number::Int(y)) => Ok(Number::Int(x.bitor(&y))),
+	("xor", Number::Int(x), Number::Int(y)) => Ok(Number::Int(x.bitxor(&y))),
+	("gcd", Number::Int(x), Number::Int(y)) => int_result(x.gcd(&y)),
+	("//" | "mod" | "rem" | "div" | ">>" | "<<" | "/\\" | "\\/" | "xor" | "gcd", Number::Float(x), _) => Err(type_error("integer", Term::Float(x))),
+	("//" | "mod" | "rem" | "div" | ">>" | "<<" | "/\\" | "\\/" | "xor" | "gcd", _, Number::Float(y)) => Err(type_error("integer", Term::Float(y))),
+	_ => Err(type_error("evaluable", indicator(name, 2))),
+    }
+}
+
+fn int_result(x: Option<Integer>) -> Result<Number, PrologError> {
+    x.map(Number::Int).ok_or_else(|| evaluation_error("int_overflow"))
+}
+
+/// Float results must be numbers: functions that would return NaN are
+/// undefined for their arguments and infinities mean the result overflowed
+fn float_function(x: f64) -> Result<Number, PrologError> {
+    if x.is_nan() {
+	Err(evaluation_error("undefined"))
+    } else if x.is_infinite() {
+	Err(evaluation_error("float_overflow"))
+    } else {
+	Ok(Number::Float(x))
+    }
+}
+
+fn float_to_int(x: f64) -> Result<Number, PrologError> {
+    int_result(Integer::from_f64(x))
+}
+
+/// Shifts bigger than a machine word are clamped: they either clear every
+/// bit or don't fit in memory anyway
+fn shift_amount(y: &Integer) -> i64 {
+    y.to_i64().unwrap_or(if y.signum() < 0 { i64::MIN } else { i64::MAX })
+}
+
+fn int_pow(x: Integer, y: Integer) -> Result<Number, PrologError> {
+    match (x.to_i64(), u32::try_from(shift_amount(&y))) {
+	(Some(0), _) if !y.is_zero() => Ok(Number::from(0)),
+	(Some(1), _) => Ok(Number::from(1)),
+	(Some(-1), _) => Ok(Number::from(if y.rem_trunc(&Integer::from(2)).is_zero() { 1 } else { -1 })),
+	(_, Ok(y)) => int_result(x.checked_pow(y)),
+	(_, Err(_)) => Err(evaluation_error("int_overflow")),
+    }
+}
+
+#[cfg(test)]
+fn eval_str(input: &str) -> Result<Number, PrologError> {
+    let goals = crate::parser::clause_body(&format!("is(X, {}).", input)).unwrap();
+    match &goals[0] {
+	Term::Str(_, args) => eval(&args[1]),
+	_ => unreachable!(),
+    }
+}
+
+#[test]
+fn eval_integers() {
+    assert_eq!(eval_str("+(1, *(2, 3))"), Ok(Number::from(7)));
+    assert_eq!(eval_str("-(3)"), Ok(Number::from(-3)));
+    assert_eq!(eval_str("//(-7, 2)"), Ok(Number::from(-3)));
+    assert_eq!(eval_str("div(-7, 2)"), Ok(Number::from(-4)));
+    assert_eq!(eval_str("mod(-7, 2)"), Ok(Number::from(1)));
+    assert_eq!(eval_str("rem(-7, 2)"), Ok(Number::from(-1)));
+    assert_eq!(eval_str("/(6, 3)"), Ok(Number::from(2)));
+    assert_eq!(eval_str("**(2, 10)"), Ok(Number::from(1024)));
+    assert_eq!(eval_str("^(-1, -3)"), Ok(Number::from(-1)));
+    assert_eq!(eval_str("<<(1, 4)"), Ok(Number::from(16)));
+    assert_eq!(eval_str(">>(-16, 2)"), Ok(Number::from(-4)));
+    assert_eq!(eval_str("/\\(12, 10)"), Ok(Number::from(8)));
+    assert_eq!(eval_str("\\/(12, 10)"), Ok(Number::from(14)));
+    assert_eq!(eval_str("xor(12, 10)"), Ok(Number::from(6)));
+    assert_eq!(eval_str("\\(5)"), Ok(Number::from(-6)));
+    assert_eq!(eval_str("gcd(12, -18)"), Ok(Number::from(6)));
+    assert_eq!(eval_str("msb(1000)"), Ok(Number::from(9)));
+    assert_eq!(eval_str("max(1, 2.0)"), Ok(Number::Float(2.0)));
+    assert_eq!(eval_str("abs(-3)"), Ok(Number::from(3)));
+    assert_eq!(eval_str("sign(-3)"), Ok(Number::from(-1)));
+}
+
+#[test]
+fn eval_floats() {
+    assert_eq!(eval_str("/(7, 2)"), Ok(Number::Float(3.5)));
+    assert_eq!(eval_str("+(1, 0.5)"), Ok(Number::Float(1.5)));
+    assert_eq!(eval_str("**(2, -1)"), Ok(Number::Float(0.5)));
+    assert_eq!(eval_str("sqrt(16)"), Ok(Number::Float(4.0)));
+    assert_eq!(eval_str("float(3)"), Ok(Number::Float(3.0)));
+    assert_eq!(eval_str("truncate(-3.7)"), Ok(Number::from(-3)));
+    assert_eq!(eval_str("round(2.5)"), Ok(Number::from(3)));
+    assert_eq!(eval_str("ceiling(2.1)"), Ok(Number::from(3)));
+    assert_eq!(eval_str("floor(-2.1)"), Ok(Number::from(-3)));
+    assert_eq!(eval_str("float_integer_part(-2.5)"), Ok(Number::Float(-2.0)));
+    assert_eq!(eval_str("float_fractional_part(2.5)"), Ok(Number::Float(0.5)));
+    assert_eq!(eval_str("pi"), Ok(Number::Float(std::f64::consts::PI)));
+}
+
+#[test]
+fn eval_errors() {
+    assert_eq!(eval_str("foo"), Err(type_error("evaluable", indicator("foo", 0))));
+    assert_eq!(eval_str("foo(1, 2, 3)"), Err(type_error("evaluable", indicator("foo", 3))));
+    assert_eq!(eval_str("+(a, 1)"), Err(type_error("evaluable", indicator("a", 0))));
+    assert_eq!(eval_str("+(X, 1)"), Err(instantiation_error()));
+    assert_eq!(eval_str("/(1, 0)"), Err(evaluation_error("zero_divisor")));
+    assert_eq!(eval_str("mod(1, 0)"), Err(evaluation_error("zero_divisor")));
+    assert_eq!(eval_str("mod(1.0, 2)"), Err(type_error("integer", Term::Float(1.0))));
+    assert_eq!(eval_str("sqrt(-1)"), Err(evaluation_error("undefined")));
+}
+
+#[test]
+fn eval_deep_expressions() {
+    let sum = vec!["1"; 100000].join("+");
+    assert_eq!(eval_str(&sum), Ok(Number::from(100000)));
+    let mut negations = Term::Int(1.into());
+    for _ in 0..100001 {
+	negations = Term::Str("-".into(), vec![negations].into());
+    }
+    assert_eq!(eval(&negations), Ok(Number::from(-1)));
+}
+
+#[test]
+fn eval_big_integers() {
+    let int = |text: &str| Ok(Number::Int(Integer::parse(text, 10).unwrap()));
+    let mut factorial = "25".to_string();
+    for i in (1..25).rev() {
+	factorial = format!("*({}, {})", i, factorial);
+    }
+    if cfg!(feature = "bigint") {
+	assert_eq!(eval_str("*(9223372036854775807, 2)"), int("18446744073709551614"));
+	assert_eq!(eval_str(&factorial), int("15511210043330985984000000"));
+	assert_eq!(eval_str("**(2, 100)"), int("1267650600228229401496703205376"));
+	assert_eq!(eval_str("^(-3, 41)"), int("-36472996377170786403"));
+	assert_eq!(eval_str("-(+(9223372036854775807, 1), 1)"), int("9223372036854775807"));
+	assert_eq!(eval_str("gcd(**(2, 100), **(6, 50))"), int("1125899906842624"));
+	assert_eq!(eval_str("msb(**(2, 100))"), Ok(Number::from(100)));
+	assert_eq!(eval_str("//(**(10, 30), **(10, 28))"), Ok(Number::from(100)));
+	assert_eq!(eval_str("<<(1, 70)"), int("1180591620717411303424"));
+	assert_eq!(eval_str("truncate(1.0e20)"), int("100000000000000000000"));
+	assert_eq!(compare(&eval_str("**(2, 64)").unwrap(), &Number::from(i64::MAX)), Ordering::Greater);
+    } else {
+	assert_eq!(eval_str("*(9223372036854775807, 2)"), Err(evaluation_error("int_overflow")));
+    }
+}
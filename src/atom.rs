@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// An interned atom: a number that stands for its name in the atom table,
+/// so atoms are compared and hashed as integers and every name is stored
+/// once.
+///
+/// The table counts the references to each atom. When the last one is
+/// dropped the atom is removed and its number reused, so atoms created
+/// while running don't pile up.
+///
+/// Each thread has its own table, so an atom only means something in the
+/// thread that made it and can't be sent to another one:
+///
+/// ```compile_fail
+/// fn send<T: Send>(_: T) {}
+/// send(esgueva::atom::Atom::new("a"));
+/// ```
+pub struct Atom(u32, PhantomData<Rc<()>>);
+
+macro_rules! known_atoms {
+    ($($known:ident = $name:literal,)*) => {
+	/// The atoms the prover and the builtins look for. Every table starts
+	/// with them, numbered in this order, and never removes them, so they
+	/// are recognized by their number without looking at their names.
+	#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+	pub(crate) enum Known { $($known),* }
+
+	const KNOWN: &[(Known, &str)] = &[$((Known::$known, $name)),*];
+    };
+}
+
+known_atoms! {
+    Cut = "!",
+    Comma = ",",
+    Semicolon = ";",
+    IfThen = "->",
+    SoftIfThen = "*->",
+    Catch = "catch",
+    Throw = "throw",
+    Call = "call",
+    NotProvable = "\\+",
+    Colon = ":",
+    Nil = "[]",
+    Dot = ".",
+    Minus = "-",
+    Neck = ":-",
+    Slash = "/",
+    True = "true",
+    Fail = "fail",
+}
+
+struct Entry {
+    name: Rc<str>,
+    references: usize,
+}
+
+struct Table {
+    entries: Vec<Option<Entry>>,
+    ids: HashMap<Rc<str>, u32>,
+    free: Vec<u32>,
+}
+
+thread_local! {
+    static TABLE: RefCell<Table> = RefCell::new(Table::default());
+}
+
+impl Atom {
+    pub fn new(name: &str) -> Self {
+	TABLE.with(|table| {
+	    let mut table = table.borrow_mut();
+	    if let Some(&id) = table.ids.get(name) {
+		table.entry(id).references += 1;
+		return Atom::numbered(id);
+	    }
+	    let name: Rc<str> = name.into();
+	    let entry = Some(Entry { name: name.clone(), references: 1 });
+	    let id = match table.free.pop() {
+		Some(id) => {
+		    table.entries[id as usize] = entry;
+		    id
+		}
+		None => {
+		    table.entries.push(entry);
+		    (table.entries.len() - 1) as u32
+		}
+	    };
+	    table.ids.insert(name, id);
+	    Atom::numbered(id)
+	})
+    }
+
+    /// The atom with a number, which must be referenced in the table
+    fn numbered(id: u32) -> Self {
+	Atom(id, PhantomData)
+    }
+
+    /// Which of the well-known atoms this is, if any
+    pub(crate) fn known(&self) -> Option<Known> {
+	KNOWN.get(self.0 as usize).map(|&(known, _)| known)
+    }
+
+    /// The number of the atom, which stays the same while it is referenced
+    pub(crate) fn number(&self) -> u32 {
+	self.0
+    }
+
+    pub fn name(&self) -> Rc<str> {
+	TABLE.with(|table| table.borrow_mut().entry(self.0).name.clone())
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+	let mut table = Table { entries: Vec::new(), ids: HashMap::new(), free: Vec::new() };
+	for &(known, name) in KNOWN {
+	    let name: Rc<str> = name.into();
+	    // The table holds a reference of its own, so they are never removed
+	    table.entries.push(Some(Entry { name: name.clone(), references: 1 }));
+	    table.ids.insert(name, known as u32);
+	}
+	table
+    }
+}
+
+impl Table {
+    fn entry(&mut self, id: u32) -> &mut Entry {
+	self.entries[id as usize].as_mut().expect("atom in the table")
+    }
+}
+
+/// The number of atoms in the table
+pub fn atom_count() -> usize {
+    TABLE.with(|table| table.borrow().ids.len())
+}
+
+impl Clone for Atom {
+    fn clone(&self) -> Self {
+	TABLE.with(|table| table.borrow_mut().entry(self.0).references += 1);
+	Atom::numbered(self.0)
+    }
+}
+
+impl Drop for Atom {
+    fn drop(&mut self) {
+	// The table may be gone already when the thread is exiting
+	let _ = TABLE.try_with(|table| {
+	    let mut table = table.borrow_mut();
+	    let entry = table.entry(self.0);
+	    entry.references -= 1;
+	    if entry.references == 0 {
+		let name = entry.name.clone();
+		table.ids.remove(&name);
+		table.entries[self.0 as usize] = None;
+		table.free.push(self.0);
+	    }
+	});
+    }
+}
+
+impl PartialEq for Atom {
+    fn eq(&self, other: &Self) -> bool {
+	self.0 == other.0
+    }
+}
+
+impl Eq for Atom {}
+
+impl Hash for Atom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+	self.0.hash(state);
+    }
+}
+
+impl PartialEq<Known> for Atom {
+    fn eq(&self, other: &Known) -> bool {
+	self.0 == *other as u32
+    }
+}
+
+impl PartialEq<Known> for &Atom {
+    fn eq(&self, other: &Known) -> bool {
+	**self == *other
+    }
+}
+
+impl PartialEq<str> for Atom {
+    fn eq(&self, other: &str) -> bool {
+	TABLE.with(|table| *table.borrow_mut().entry(self.0).name == *other)
+    }
+}
+
+impl PartialEq<&str> for Atom {
+    fn eq(&self, other: &&str) -> bool {
+	*self == **other
+    }
+}
+
+impl From<Known> for Atom {
+    fn from(known: Known) -> Self {
+	TABLE.with(|table| table.borrow_mut().entry(known as u32).references += 1);
+	Atom::numbered(known as u32)
+    }
+}
+
+impl From<&str> for Atom {
+    fn from(name: &str) -> Self {
+	Atom::new(name)
+    }
+}
+
+impl From<String> for Atom {
+    fn from(name: String) -> Self {
+	Atom::new(&name)
+    }
+}
+
+impl fmt::Debug for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{:?}", &*self.name())
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{}", self.name())
+    }
+}
+
+#[test]
+fn intern_atoms() {
+    let x = Atom::new("intern_atoms_test");
+    let y = Atom::from("intern_atoms_test".to_string());
+    assert_eq!(x, y);
+    assert_eq!(x.0, y.0);
+    assert!(x == "intern_atoms_test");
+    assert_ne!(x, Atom::new("other_atom"));
+    assert_eq!(x.to_string(), "intern_atoms_test");
+}
+
+#[test]
+fn known_atoms() {
+    for &(known, name) in KNOWN {
+	let atom = Atom::new(name);
+	assert_eq!(atom.known(), Some(known));
+	assert!(atom == known);
+	assert_eq!(Atom::from(known), atom);
+    }
+    assert_eq!(Atom::new("known_atoms_test").known(), None);
+    // Dropping every reference doesn't remove them
+    let before = atom_count();
+    drop(Atom::from(Known::Catch));
+    assert_eq!(atom_count(), before);
+    assert!(Atom::new("catch") == Known::Catch);
+}
+
+#[test]
+fn collect_unused_atoms() {
+    let before = atom_count();
+    let atoms: Vec<Atom> = (0..100).map(|i| Atom::new(&format!("dynamic_{}", i))).collect();
+    let copies = atoms.clone();
+    assert_eq!(atom_count(), before + 100);
+    drop(atoms);
+    assert_eq!(atom_count(), before + 100);
+    drop(copies);
+    assert_eq!(atom_count(), before);
+    // The numbers of the removed atoms are reused
+    let id = Atom::new("dynamic_again").0;
+    assert!(id < (before + 100) as u32);
+}
@@ -0,0 +1,1510 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::arith;
+use crate::atom::{Atom, Known};
+use crate::database::Predicate;
+use crate::flags::OccursCheck;
+use crate::integer::Integer;
+use crate::error::{PrologError, instantiation_error, type_error, domain_error, permission_error, representation_error, resource_error, syntax_error, indicator};
+use crate::ops::OpType;
+use crate::parser;
+use crate::prover::{Solutions, find_variables_in_goals, number_variables};
+use crate::machine::Machine;
+use crate::term::{self, Term};
+
+/// A builtin predicate: it receives its arguments and returns whether it
+/// succeeded, binding variables through the solver. Nondeterministic
+/// builtins leave their other answers with `Solutions::unify_alternatives`.
+pub(crate) type Builtin = fn(&mut Solutions, &[Term]) -> Result<bool, PrologError>;
+
+/// The builtins by name and arity
+const BUILTINS: &[(&str, usize, Builtin)] = &[
+    ("true", 0, succeed),
+    ("fail", 0, fail),
+    ("false", 0, fail),
+    ("=", 2, unify),
+    ("\\=", 2, not_unifiable),
+    ("unify_with_occurs_check", 2, unify_with_occurs_check),
+    ("var", 1, var),
+    ("nonvar", 1, nonvar),
+    ("atom", 1, atom),
+    ("number", 1, number),
+    ("integer", 1, integer),
+    ("float", 1, float),
+    ("atomic", 1, atomic),
+    ("compound", 1, compound),
+    ("callable", 1, callable),
+    ("is_list", 1, is_list),
+    ("ground", 1, ground),
+    ("is", 2, is),
+    ("=:=", 2, arith_equal),
+    ("=\\=", 2, arith_not_equal),
+    ("<", 2, arith_less),
+    (">", 2, arith_greater),
+    ("=<", 2, arith_less_equal),
+    (">=", 2, arith_greater_equal),
+    ("==", 2, identical),
+    ("\\==", 2, not_identical),
+    ("@<", 2, term_less),
+    ("@>", 2, term_greater),
+    ("@=<", 2, term_less_equal),
+    ("@>=", 2, term_greater_equal),
+    ("compare", 3, compare),
+    ("sort", 2, sort),
+    ("msort", 2, msort),
+    ("sort", 4, sort_on_key),
+    ("keysort", 2, keysort),
+    ("predsort", 3, predsort),
+    ("functor", 3, functor),
+    ("arg", 3, arg),
+    ("=..", 2, univ),
+    ("copy_term", 2, copy_term),
+    ("setarg", 3, setarg),
+    ("nb_setarg", 3, nb_setarg),
+    ("term_variables", 2, term_variables),
+    ("atom_codes", 2, atom_codes),
+    ("atom_chars", 2, atom_chars),
+    ("char_code", 2, char_code),
+    ("atom_length", 2, atom_length),
+    ("atom_concat", 3, atom_concat),
+    ("sub_atom", 5, sub_atom),
+    ("upcase_atom", 2, upcase_atom),
+    ("number_codes", 2, number_codes),
+    ("atom_number", 2, atom_number),
+    ("atomic_list_concat", 2, atomic_list_concat),
+    ("atomic_list_concat", 3, atomic_list_concat),
+    ("term_to_atom", 2, term_to_atom),
+    ("length", 2, length),
+    ("between", 3, between),
+    ("op", 3, op),
+    ("current_op", 3, current_op),
+    ("set_prolog_flag", 2, set_prolog_flag),
+    ("current_prolog_flag", 2, current_prolog_flag),
+    ("predicate_property", 2, predicate_property),
+    ("assert", 1, assertz),
+    ("assertz", 1, assertz),
+    ("asserta", 1, asserta),
+    ("retract", 1, retract),
+    ("abolish", 1, abolish),
+    ("dynamic", 1, dynamic),
+    ("clause", 2, clause),
+    ("current_predicate", 1, current_predicate),
+    ("portray_clause", 1, portray_clause),
+    ("listing", 1, listing),
+    ("findall", 3, findall),
+    ("findall", 4, findall),
+    ("bagof", 3, bagof),
+    ("setof", 3, setof),
+    ("aggregate_all", 3, aggregate_all),
+    ("module", 2, module),
+    ("use_module", 1, use_module),
+    ("use_module", 2, use_module),
+    ("consult", 1, consult),
+    (".", 2, consult),
+    ("ensure_loaded", 1, ensure_loaded),
+    ("initialization", 1, initialization),
+    ("discontiguous", 1, discontiguous),
+    ("multifile", 1, multifile),
+    ("meta_predicate", 1, meta_predicate),
+];
+
+/// The builtins by the number of their name, which the table keeps alive
+struct BuiltinTable {
+    builtins: HashMap<(u32, usize), Builtin>,
+    _names: Vec<Atom>,
+}
+
+thread_local! {
+    static BUILTIN_TABLE: BuiltinTable = {
+	let names: Vec<Atom> = BUILTINS.iter().map(|&(name, _, _)| Atom::new(name)).collect();
+	let builtins = names.iter().zip(BUILTINS)
+	    .map(|(name, &(_, arity, builtin))| ((name.number(), arity), builtin))
+	    .collect();
+	BuiltinTable { builtins, _names: names }
+    };
+}
+
+pub(crate) fn find_builtin(name: &Atom, arity: usize) -> Option<Builtin> {
+    BUILTIN_TABLE.with(|table| table.builtins.get(&(name.number(), arity)).copied())
+}
+
+/// Whether a predicate is a control construct or a builtin. They can't be
+/// modified.
+pub(crate) fn is_builtin(name: &Atom, arity: usize) -> bool {
+    let control = matches!((name.known(), arity),
+	(Some(Known::Cut), 0) | (Some(Known::Comma | Known::Semicolon | Known::IfThen | Known::SoftIfThen), 2)
+	| (Some(Known::Catch), 3) | (Some(Known::Throw | Known::NotProvable), 1))
+	|| (*name == Known::Call && arity >= 1);
+    control || find_builtin(name, arity).is_some()
+}
+
+fn succeed(_: &mut Solutions, _: &[Term]) -> Result<bool, PrologError> {
+    Ok(true)
+}
+
+fn fail(_: &mut Solutions, _: &[Term]) -> Result<bool, PrologError> {
+    Ok(false)
+}
+
+fn unify(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let occurs_check = solutions.machine().flags().occurs_check;
+    solutions.unify_with(&args[0], &args[1], occurs_check)
+}
+
+fn not_unifiable(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(!solutions.unifiable(&args[0], &args[1])?)
+}
+
+fn unify_with_occurs_check(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    solutions.unify_with(&args[0], &args[1], OccursCheck::True)
+}
+
+fn var(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Var(_)))
+}
+
+fn nonvar(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(!matches!(solutions.deref(args[0].clone()), Term::Var(_)))
+}
+
+fn atom(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Atom(_)))
+}
+
+fn number(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Int(_) | Term::Float(_)))
+}
+
+fn integer(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Int(_)))
+}
+
+fn float(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Float(_)))
+}
+
+fn atomic(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Atom(_) | Term::Int(_) | Term::Float(_)))
+}
+
+fn compound(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Str(..)))
+}
+
+fn callable(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(matches!(solutions.deref(args[0].clone()), Term::Atom(_) | Term::Str(..)))
+}
+
+fn is_list(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let mut list = solutions.deref(args[0].clone());
+    loop {
+	match list {
+	    Term::Atom(name) if name == Known::Nil => return Ok(true),
+	    Term::Str(name, args) if name == Known::Dot && args.len() == 2 => list = solutions.deref(args[1].clone()),
+	    _ => return Ok(false),
+	}
+    }
+}
+
+fn ground(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(find_variables_in_goals(&[solutions.substitute(&args[0])]).is_empty())
+}
+
+fn is(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let value = arith::eval(&solutions.substitute(&args[1]))?;
+    Ok(solutions.unify(args[0].clone(), value.to_term()))
+}
+
+fn arith_compare(solutions: &mut Solutions, args: &[Term]) -> Result<Ordering, PrologError> {
+    let x = arith::eval(&solutions.substitute(&args[0]))?;
+    let y = arith::eval(&solutions.substitute(&args[1]))?;
+    Ok(arith::compare(&x, &y))
+}
+
+fn arith_equal(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(arith_compare(solutions, args)? == Ordering::Equal)
+}
+
+fn arith_not_equal(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(arith_compare(solutions, args)? != Ordering::Equal)
+}
+
+fn arith_less(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(arith_compare(solutions, args)? == Ordering::Less)
+}
+
+fn arith_greater(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(arith_compare(solutions, args)? == Ordering::Greater)
+}
+
+fn arith_less_equal(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(arith_compare(solutions, args)? != Ordering::Greater)
+}
+
+fn arith_greater_equal(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(arith_compare(solutions, args)? != Ordering::Less)
+}
+
+/// Compares two terms in the standard order
+fn term_compare(solutions: &mut Solutions, args: &[Term]) -> Ordering {
+    solutions.substitute(&args[0]).cmp(&solutions.substitute(&args[1]))
+}
+
+fn identical(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(term_compare(solutions, args) == Ordering::Equal)
+}
+
+fn not_identical(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(term_compare(solutions, args) != Ordering::Equal)
+}
+
+fn term_less(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(term_compare(solutions, args) == Ordering::Less)
+}
+
+fn term_greater(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(term_compare(solutions, args) == Ordering::Greater)
+}
+
+fn term_less_equal(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(term_compare(solutions, args) != Ordering::Greater)
+}
+
+fn term_greater_equal(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    Ok(term_compare(solutions, args) != Ordering::Less)
+}
+
+/// The atom `<`, `=` or `>` that stands for an ordering
+fn order_atom(ordering: Ordering) -> Term {
+    let name = match ordering {
+	Ordering::Less => "<",
+	Ordering::Equal => "=",
+	Ordering::Greater => ">",
+    };
+    Term::Atom(name.into())
+}
+
+fn compare(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let order = solutions.substitute(&args[0]);
+    match &order {
+	Term::Var(_) => (),
+	Term::Atom(name) if matches!(&*name.name(), "<" | "=" | ">") => (),
+	Term::Atom(_) => return Err(domain_error("order", order)),
+	_ => return Err(type_error("atom", order)),
+    }
+    let ordering = term_compare(solutions, &args[1..]);
+    Ok(solutions.unify(order, order_atom(ordering)))
+}
+
+/// Reads the list to sort, checking that the sorted one can be a list
+fn sort_input(solutions: &mut Solutions, list: &Term, sorted: &Term) -> Result<Vec<Term>, PrologError> {
+    let list = list_to_vec(&solutions.substitute(list))?;
+    let sorted = solutions.substitute(sorted);
+    let mut tail = &sorted;
+    loop {
+	match tail {
+	    Term::Str(name, args) if *name == Known::Dot && args.len() == 2 => tail = &args[1],
+	    Term::Var(_) => return Ok(list),
+	    Term::Atom(name) if *name == Known::Nil => return Ok(list),
+	    _ => return Err(type_error("list", sorted)),
+	}
+    }
+}
+
+/// Sorts a list in the standard order, removing the duplicates
+fn sort(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let mut list = sort_input(solutions, &args[0], &args[1])?;
+    sort_unique(&mut list);
+    Ok(solutions.unify(args[1].clone(), vec_to_list(list)))
+}
+
+/// Sorts a list in the standard order, keeping the duplicates
+fn msort(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let mut list = sort_input(solutions, &args[0], &args[1])?;
+    list.sort();
+    Ok(solutions.unify(args[1].clone(), vec_to_list(list)))
+}
+
+/// `sort(Key, Order, List, Sorted)`: sorts on an argument of the elements,
+/// or on the whole elements if `Key` is 0. `Order` is `@<` or `@>` to
+/// remove the elements with equal keys, or `@=<` or `@>=` to keep them.
+/// The sort is stable.
+fn sort_on_key(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let key = match solutions.substitute(&args[0]) {
+	Term::Var(_) => return Err(instantiation_error()),
+	Term::Int(key) => match key.to_i64() {
+	    Some(key) if key >= 0 => key as usize,
+	    _ => return Err(domain_error("not_less_than_zero", Term::Int(key))),
+	},
+	key => return Err(type_error("integer", key)),
+    };
+    let (descending, unique) = match solutions.substitute(&args[1]) {
+	Term::Var(_) => return Err(instantiation_error()),
+	Term::Atom(order) => match &*order.name() {
+	    "@<" => (false, true),
+	    "@=<" => (false, false),
+	    "@>" => (true, true),
+	    "@>=" => (true, false),
+	    _ => return Err(domain_error("order", Term::Atom(order.clone()))),
+	},
+	order => return Err(type_error("atom", order)),
+    };
+    let list = sort_input(solutions, &args[2], &args[3])?;
+    let mut keyed = Vec::with_capacity(list.len());
+    for element in list {
+	let sort_key = match &element {
+	    _ if key == 0 => element.clone(),
+	    Term::Str(_, args) if args.len() >= key => args[key - 1].clone(),
+	    Term::Var(_) => return Err(instantiation_error()),
+	    _ => return Err(type_error("compound", element)),
+	};
+	keyed.push((sort_key, element));
+    }
+    if descending {
+	keyed.sort_by(|(x, _), (y, _)| y.cmp(x));
+    } else {
+	keyed.sort_by(|(x, _), (y, _)| x.cmp(y));
+    }
+    if unique {
+	keyed.dedup_by(|(x, _), (y, _)| x == y);
+    }
+    let sorted = keyed.into_iter().map(|(_, element)| element).collect();
+    Ok(solutions.unify(args[3].clone(), vec_to_list(sorted)))
+}
+
+/// Sorts a list of `Key-Value` pairs on their keys, keeping the order of
+/// the pairs with equal keys
+fn keysort(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let list = sort_input(solutions, &args[0], &args[1])?;
+    let mut pairs = Vec::with_capacity(list.len());
+    for pair in list {
+	match &pair {
+	    Term::Str(name, args) if *name == Known::Minus && args.len() == 2 => pairs.push((args[0].clone(), pair.clone())),
+	    Term::Var(_) => return Err(instantiation_error()),
+	    _ => return Err(type_error("pair", pair)),
+	}
+    }
+    pairs.sort_by(|(x, _), (y, _)| x.cmp(y));
+    let sorted = pairs.into_iter().map(|(_, pair)| pair).collect();
+    Ok(solutions.unify(args[1].clone(), vec_to_list(sorted)))
+}
+
+/// Sorts a list calling `call(Pred, Order, X, Y)` to compare elements. An
+/// element that compares `=` to an earlier one is removed.
+fn predsort(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let predicate = solutions.substitute(&args[0]);
+    let list = list_to_vec(&solutions.substitute(&args[1]))?;
+    match merge_sort(solutions, &predicate, list)? {
+	Some(sorted) => Ok(solutions.unify(args[2].clone(), vec_to_list(sorted))),
+	None => Ok(false),
+    }
+}
+
+/// Merge sort for `predsort/3`. Returns `None` if a comparison fails.
+fn merge_sort(solutions: &mut Solutions, predicate: &Term, mut list: Vec<Term>) -> Result<Option<Vec<Term>>, PrologError> {
+    if list.len() < 2 {
+	return Ok(Some(list));
+    }
+    let right = list.split_off(list.len() / 2);
+    let (left, right) = match (merge_sort(solutions, predicate, list)?, merge_sort(solutions, predicate, right)?) {
+	(Some(left), Some(right)) => (left, right),
+	_ => return Ok(None),
+    };
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(x), Some(y)) = (left.peek(), right.peek()) {
+	let order = solutions.fresh_variable();
+	let call = Term::Str("call".into(), vec![predicate.clone(), order.clone(), x.clone(), y.clone()].into());
+	let goal = Term::Str(",".into(), vec![call, Term::Atom("!".into())].into());
+	let order = match solutions.find_all(&order, goal)?.pop() {
+	    Some(order) => order,
+	    None => return Ok(None),
+	};
+	match order {
+	    Term::Atom(name) if name == "<" => merged.extend(left.next()),
+	    Term::Atom(name) if name == ">" => merged.extend(right.next()),
+	    Term::Atom(name) if name == "=" => {
+		right.next();
+	    }
+	    Term::Var(_) => return Err(instantiation_error()),
+	    order => return Err(domain_error("order", order)),
+	}
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(Some(merged))
+}
+
+/// Relates a term with its name and arity, building a term with fresh
+/// arguments if it is unbound
+fn functor(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match solutions.deref(args[0].clone()) {
+	Term::Var(_) => (),
+	Term::Str(name, term_args) => {
+	    let arity = Term::Int((term_args.len() as i64).into());
+	    return Ok(solutions.unify(args[1].clone(), Term::Atom(name)) && solutions.unify(args[2].clone(), arity));
+	}
+	term => return Ok(solutions.unify(args[1].clone(), term) && solutions.unify(args[2].clone(), Term::Int(0.into()))),
+    }
+    let name = solutions.deref(args[1].clone());
+    let arity = match solutions.deref(args[2].clone()) {
+	Term::Int(arity) => match arity.to_i64() {
+	    Some(arity) if arity >= 0 => arity as usize,
+	    _ => return Err(domain_error("not_less_than_zero", Term::Int(arity))),
+	},
+	Term::Var(_) => return Err(instantiation_error()),
+	arity => return Err(type_error("integer", arity)),
+    };
+    let term = match name {
+	Term::Var(_) => return Err(instantiation_error()),
+	Term::Str(_, _) => return Err(type_error("atomic", name)),
+	name if arity == 0 => name,
+	Term::Atom(name) => Term::Str(name, (0..arity).map(|_| solutions.fresh_variable()).collect::<Vec<_>>().into()),
+	name => return Err(type_error("atom", name)),
+    };
+    Ok(solutions.unify(args[0].clone(), term))
+}
+
+/// Gives the Nth argument of a compound term, or each argument and its
+/// position if N is unbound
+fn arg(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let term_args = match solutions.deref(args[1].clone()) {
+	Term::Str(_, term_args) => term_args,
+	Term::Var(_) => return Err(instantiation_error()),
+	term => return Err(type_error("compound", term)),
+    };
+    match solutions.deref(args[0].clone()) {
+	Term::Int(n) => match n.to_i64() {
+	    Some(n) if n >= 1 && n as usize <= term_args.len() => Ok(solutions.unify(args[2].clone(), term_args[n as usize - 1].clone())),
+	    _ => Ok(false),
+	},
+	Term::Var(_) => {
+	    let candidates = term_args.iter().enumerate()
+		.map(|(i, arg)| Term::Str("-".into(), vec![Term::Int((i as i64 + 1).into()), arg.clone()].into()))
+		.collect();
+	    Ok(solutions.unify_alternatives(Term::Str("-".into(), vec![args[0].clone(), args[2].clone()].into()), candidates))
+	}
+	n => Err(type_error("integer", n)),
+    }
+}
+
+/// `Term =.. [Name|Arguments]`
+fn univ(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match solutions.deref(args[0].clone()) {
+	Term::Var(_) => (),
+	Term::Str(name, term_args) => {
+	    let list = vec_to_list(std::iter::once(Term::Atom(name)).chain(term_args.iter().cloned()).collect());
+	    return Ok(solutions.unify(args[1].clone(), list));
+	}
+	term => return Ok(solutions.unify(args[1].clone(), vec_to_list(vec![term]))),
+    }
+    let list = solutions.substitute(&args[1]);
+    let mut elements = list_to_vec(&list)?;
+    if elements.is_empty() {
+	return Err(domain_error("non_empty_list", list));
+    }
+    let term_args = elements.split_off(1);
+    let term = match elements.pop().expect("the name") {
+	Term::Var(_) => return Err(instantiation_error()),
+	name @ Term::Str(_, _) => return Err(type_error("atomic", name)),
+	name if term_args.is_empty() => name,
+	Term::Atom(name) => Term::Str(name, term_args.into()),
+	name => return Err(type_error("atom", name)),
+    };
+    Ok(solutions.unify(args[0].clone(), term))
+}
+
+fn copy_term(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let copy = solutions.fresh_copy(&args[0]);
+    Ok(solutions.unify(args[1].clone(), copy))
+}
+
+fn setarg(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    set_argument(solutions, args, true)
+}
+
+fn nb_setarg(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    set_argument(solutions, args, false)
+}
+
+/// Replaces the Nth argument of a compound term for `setarg/3`, undone on
+/// backtracking, or `nb_setarg/3`, which keeps a copy of the value. The
+/// term can only change if it is the value of a variable.
+fn set_argument(solutions: &mut Solutions, args: &[Term], backtrackable: bool) -> Result<bool, PrologError> {
+    let arity = match solutions.deref(args[1].clone()) {
+	Term::Str(_, term_args) => term_args.len(),
+	Term::Var(_) => return Err(instantiation_error()),
+	term => return Err(type_error("compound", term)),
+    };
+    let index = match solutions.deref(args[0].clone()) {
+	Term::Int(n) => match n.to_i64() {
+	    Some(n) if n >= 1 && n as usize <= arity => n as usize - 1,
+	    _ => return Ok(false),
+	},
+	Term::Var(_) => return Err(instantiation_error()),
+	n => return Err(type_error("integer", n)),
+    };
+    let value = if backtrackable { args[2].clone() } else { solutions.fresh_copy(&args[2]) };
+    solutions.set_argument(args[1].clone(), index, value, backtrackable);
+    Ok(true)
+}
+
+/// The variables of a term, in depth-first order from left to right
+fn term_variables(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let term = solutions.substitute(&args[0]);
+    let variables = find_variables_in_goals(&[term]).into_iter().map(Term::Var).collect();
+    Ok(solutions.unify(args[1].clone(), vec_to_list(variables)))
+}
+
+/// The text of an atom or a number
+fn atomic_text(term: &Term) -> Result<String, PrologError> {
+    match term {
+	Term::Var(_) => Err(instantiation_error()),
+	Term::Atom(name) => Ok(name.name().to_string()),
+	Term::Int(x) => Ok(x.to_string()),
+	Term::Float(x) => Ok(term::format_float(*x)),
+	term => Err(type_error("atomic", term.clone())),
+    }
+}
+
+fn text_to_codes(text: &str) -> Term {
+    vec_to_list(text.chars().map(|c| Term::Int((c as i64).into())).collect())
+}
+
+fn text_to_chars(text: &str) -> Term {
+    vec_to_list(text.chars().map(|c| Term::Atom(c.to_string().into())).collect())
+}
+
+/// The text of a proper list of character codes
+fn codes_to_text(list: &Term) -> Result<String, PrologError> {
+    list_to_vec(list)?.into_iter()
+	.map(|code| match code {
+	    Term::Var(_) => Err(instantiation_error()),
+	    Term::Int(code) => code_to_char(&code),
+	    code => Err(type_error("integer", code)),
+	})
+	.collect()
+}
+
+/// The text of a proper list of one-character atoms
+fn chars_to_text(list: &Term) -> Result<String, PrologError> {
+    list_to_vec(list)?.into_iter()
+	.map(|c| match c {
+	    Term::Var(_) => Err(instantiation_error()),
+	    Term::Atom(ref name) => single_char(name).ok_or_else(|| type_error("character", c.clone())),
+	    c => Err(type_error("character", c)),
+	})
+	.collect()
+}
+
+fn code_to_char(code: &Integer) -> Result<char, PrologError> {
+    code.to_i64()
+	.and_then(|code| u32::try_from(code).ok())
+	.and_then(char::from_u32)
+	.ok_or_else(|| representation_error("character_code"))
+}
+
+/// The character of an atom with just one
+fn single_char(name: &Atom) -> Option<char> {
+    let name = name.name();
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+	(Some(c), None) => Some(c),
+	_ => None,
+    }
+}
+
+/// Converts between an atom and the list of its characters as `to_list` and
+/// `from_list` do, in the direction the instantiation of the atom says
+fn atom_to_list(solutions: &mut Solutions, args: &[Term], to_list: fn(&str) -> Term, from_list: fn(&Term) -> Result<String, PrologError>) -> Result<bool, PrologError> {
+    match solutions.deref(args[0].clone()) {
+	Term::Var(_) => {
+	    let text = from_list(&solutions.substitute(&args[1]))?;
+	    Ok(solutions.unify(args[0].clone(), Term::Atom(text.into())))
+	}
+	atom => {
+	    let text = atomic_text(&atom)?;
+	    Ok(solutions.unify(args[1].clone(), to_list(&text)))
+	}
+    }
+}
+
+fn atom_codes(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    atom_to_list(solutions, args, text_to_codes, codes_to_text)
+}
+
+fn atom_chars(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    atom_to_list(solutions, args, text_to_chars, chars_to_text)
+}
+
+fn char_code(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match solutions.deref(args[0].clone()) {
+	Term::Var(_) => match solutions.deref(args[1].clone()) {
+	    Term::Var(_) => Err(instantiation_error()),
+	    Term::Int(code) => {
+		let c = code_to_char(&code)?;
+		Ok(solutions.unify(args[0].clone(), Term::Atom(c.to_string().into())))
+	    }
+	    code => Err(type_error("integer", code)),
+	},
+	Term::Atom(name) => match single_char(&name) {
+	    Some(c) => Ok(solutions.unify(args[1].clone(), Term::Int((c as i64).into()))),
+	    None => Err(type_error("character", Term::Atom(name))),
+	},
+	c => Err(type_error("character", c)),
+    }
+}
+
+fn atom_length(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let text = atomic_text(&solutions.deref(args[0].clone()))?;
+    match solutions.deref(args[1].clone()) {
+	Term::Int(length) if length.signum() < 0 => Err(domain_error("not_less_than_zero", Term::Int(length))),
+	length @ (Term::Var(_) | Term::Int(_)) => Ok(solutions.unify(length, Term::Int((text.chars().count() as i64).into()))),
+	length => Err(type_error("integer", length)),
+    }
+}
+
+fn atom_concat(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let first = solutions.deref(args[0].clone());
+    let second = solutions.deref(args[1].clone());
+    if !matches!(first, Term::Var(_)) && !matches!(second, Term::Var(_)) {
+	let text = atomic_text(&first)? + &atomic_text(&second)?;
+	return Ok(solutions.unify(args[2].clone(), Term::Atom(text.into())));
+    }
+    // Split the whole atom in every possible way
+    let whole = atomic_text(&solutions.deref(args[2].clone()))?;
+    let candidates = whole.char_indices().map(|(i, _)| i).chain(std::iter::once(whole.len()))
+	.map(|i| Term::Str("-".into(), vec![Term::Atom(whole[..i].into()), Term::Atom(whole[i..].into())].into()))
+	.collect();
+    Ok(solutions.unify_alternatives(Term::Str("-".into(), vec![first, second].into()), candidates))
+}
+
+/// The value of a bound integer argument of `sub_atom/5`, or `None` if it
+/// isn't bound. Negative ones match no position.
+fn sub_atom_position(term: Term) -> Result<Option<i64>, PrologError> {
+    match term {
+	Term::Var(_) => Ok(None),
+	Term::Int(x) => Ok(Some(x.to_i64().unwrap_or(-1))),
+	term => Err(type_error("integer", term)),
+    }
+}
+
+/// Enumerates the answers of `sub_atom/5` as they are needed. With `Sub`
+/// bound only its occurrences are tried, and any two bound positions
+/// leave a single answer.
+fn sub_atom(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let chars: Rc<[char]> = atomic_text(&solutions.deref(args[0].clone()))?.chars().collect();
+    let before = sub_atom_position(solutions.deref(args[1].clone()))?;
+    let length = sub_atom_position(solutions.deref(args[2].clone()))?;
+    let after = sub_atom_position(solutions.deref(args[3].clone()))?;
+    let sub: Option<Vec<char>> = match solutions.deref(args[4].clone()) {
+	Term::Var(_) => None,
+	sub => Some(atomic_text(&sub)?.chars().collect()),
+    };
+    let length = match &sub {
+	Some(sub) if length.is_some_and(|length| length != sub.len() as i64) => return Ok(false),
+	Some(sub) => Some(sub.len() as i64),
+	None => length,
+    };
+    let n = chars.len() as i64;
+    let starts = match (before, length, after) {
+	(Some(before), _, _) => before..=before,
+	(None, Some(length), Some(after)) => n.saturating_sub(length).saturating_sub(after)..=n.saturating_sub(length).saturating_sub(after),
+	_ => 0..=n,
+    };
+    let text = chars.clone();
+    let candidates = starts.filter(move |b| (0..=n).contains(b))
+	.flat_map(move |b| {
+	    let lengths = match (length, after) {
+		(Some(length), _) => length..=length,
+		(None, Some(after)) => n - b - after..=n - b - after,
+		_ => 0..=n - b,
+	    };
+	    lengths.filter(move |l| *l >= 0 && *l <= n - b && after.is_none_or(|after| after == n - b - l))
+		.map(move |l| (b as usize, l as usize))
+	})
+	.filter(move |&(b, l)| sub.as_ref().is_none_or(|sub| sub[..] == text[b..b + l]))
+	.map(move |(b, l)| {
+	    let sub: String = chars[b..b + l].iter().collect();
+	    let positions = [b, l, chars.len() - b - l].map(|x| Term::Int((x as i64).into()));
+	    Term::Str("sub".into(), positions.into_iter().chain([Term::Atom(sub.into())]).collect::<Vec<_>>().into())
+	});
+    Ok(solutions.unify_lazily(Term::Str("sub".into(), args[1..].to_vec().into()), candidates))
+}
+
+fn upcase_atom(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let text = atomic_text(&solutions.deref(args[0].clone()))?;
+    Ok(solutions.unify(args[1].clone(), Term::Atom(text.to_uppercase().into())))
+}
+
+/// Reads a number from a text with the syntax of Prolog
+fn parse_number(solutions: &mut Solutions, text: &str) -> Result<Term, PrologError> {
+    match solutions.read_term(text)? {
+	number @ (Term::Int(_) | Term::Float(_)) => Ok(number),
+	_ => Err(syntax_error("illegal_number")),
+    }
+}
+
+fn number_codes(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match solutions.deref(args[0].clone()) {
+	Term::Var(_) => {
+	    let text = codes_to_text(&solutions.substitute(&args[1]))?;
+	    let number = parse_number(solutions, &text)?;
+	    Ok(solutions.unify(args[0].clone(), number))
+	}
+	number @ (Term::Int(_) | Term::Float(_)) => Ok(solutions.unify(args[1].clone(), text_to_codes(&atomic_text(&number)?))),
+	number => Err(type_error("number", number)),
+    }
+}
+
+fn atom_number(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match solutions.deref(args[0].clone()) {
+	Term::Var(_) => match solutions.deref(args[1].clone()) {
+	    Term::Var(_) => Err(instantiation_error()),
+	    number @ (Term::Int(_) | Term::Float(_)) => Ok(solutions.unify(args[0].clone(), Term::Atom(atomic_text(&number)?.into()))),
+	    number => Err(type_error("number", number)),
+	},
+	// Atoms that aren't numbers fail instead of raising a syntax error
+	Term::Atom(name) => match parse_number(solutions, &name.name()) {
+	    Ok(number) => Ok(solutions.unify(args[1].clone(), number)),
+	    Err(_) => Ok(false),
+	},
+	atom => Err(type_error("atom", atom)),
+    }
+}
+
+/// `atomic_list_concat(List, Atom)` and `atomic_list_concat(List, Separator,
+/// Atom)`. With a separator, it splits the atom if the list isn't known.
+fn atomic_list_concat(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let (list, whole) = (&args[0], &args[args.len() - 1]);
+    let separator = match args {
+	[_, separator, _] => Some(solutions.deref(separator.clone())),
+	_ => None,
+    };
+    let separator_text = match &separator {
+	Some(separator) => atomic_text(separator)?,
+	None => String::new(),
+    };
+    let texts = list_to_vec(&solutions.substitute(list))
+	.and_then(|elements| elements.iter().map(atomic_text).collect::<Result<Vec<String>, PrologError>>());
+    match (texts, separator) {
+	(Ok(texts), _) => Ok(solutions.unify(whole.clone(), Term::Atom(texts.join(&separator_text).into()))),
+	(Err(error), Some(separator)) if error == instantiation_error() && !matches!(solutions.deref(whole.clone()), Term::Var(_)) => {
+	    if separator_text.is_empty() {
+		return Err(domain_error("non_empty_atom", separator));
+	    }
+	    let text = atomic_text(&solutions.deref(whole.clone()))?;
+	    let parts = text.split(&separator_text).map(|part| Term::Atom(part.into())).collect();
+	    Ok(solutions.unify(list.clone(), vec_to_list(parts)))
+	}
+	(Err(error), _) => Err(error),
+    }
+}
+
+fn term_to_atom(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match solutions.deref(args[1].clone()) {
+	Term::Var(_) => {
+	    let term = solutions.substitute(&args[0]);
+	    if let Term::Var(_) = term {
+		return Err(instantiation_error());
+	    }
+	    let text = term::write_term(&term, solutions.machine().operators(), 1200);
+	    Ok(solutions.unify(args[1].clone(), Term::Atom(text.into())))
+	}
+	atom => {
+	    let term = solutions.read_term(&atomic_text(&atom)?)?;
+	    Ok(solutions.unify(args[0].clone(), term))
+	}
+    }
+}
+
+fn length(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    // Walk the list up to its tail, which is [] for a proper list
+    let mut count = 0;
+    let mut tail = solutions.deref(args[0].clone());
+    while let Term::Str(ref name, ref cell) = tail {
+	if name != Known::Dot || cell.len() != 2 {
+	    break;
+	}
+	count += 1;
+	tail = solutions.deref(cell[1].clone());
+    }
+    match (tail, solutions.deref(args[1].clone())) {
+	(Term::Atom(ref nil), length @ (Term::Var(_) | Term::Int(_))) if nil == Known::Nil => {
+	    Ok(solutions.unify(length, Term::Int(count.into())))
+	}
+	(Term::Atom(ref nil), length) if nil == Known::Nil => Err(type_error("integer", length)),
+	(tail @ Term::Var(_), Term::Int(length)) => {
+	    let length = match length.to_i64() {
+		Some(length) if length < 0 => return Err(domain_error("not_less_than_zero", Term::Int(length.into()))),
+		Some(length) => length,
+		None if length.signum() < 0 => return Err(domain_error("not_less_than_zero", Term::Int(length))),
+		None => return Err(resource_error("memory")),
+	    };
+	    if length < count {
+		return Ok(false);
+	    }
+	    let list = (count..length).fold(Term::Atom(Known::Nil.into()), |list, _| {
+		Term::Str(Known::Dot.into(), vec![solutions.fresh_variable(), list].into())
+	    });
+	    Ok(solutions.unify(tail, list))
+	}
+	(tail @ Term::Var(_), length @ Term::Var(_)) => {
+	    // Enumerate the lengths from the elements already there on,
+	    // building the rest of the list for each one
+	    let rest = solutions.fresh_variable();
+	    solutions.push_goal(Term::Str("length".into(), vec![tail, rest.clone()].into()));
+	    let difference = Term::Str("-".into(), vec![length.clone(), Term::Int(count.into())].into());
+	    solutions.push_goal(Term::Str("is".into(), vec![rest, difference].into()));
+	    Ok(solutions.unify_range(length, count.into(), None))
+	}
+	(Term::Var(_), length) => Err(type_error("integer", length)),
+	_ => Err(type_error("list", solutions.substitute(&args[0]))),
+    }
+}
+
+fn between(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let low = match solutions.deref(args[0].clone()) {
+	Term::Int(low) => low,
+	Term::Var(_) => return Err(instantiation_error()),
+	low => return Err(type_error("integer", low)),
+    };
+    let high = match solutions.deref(args[1].clone()) {
+	Term::Int(high) => Some(high),
+	Term::Atom(ref name) if name == "inf" || name == "infinite" => None,
+	Term::Var(_) => return Err(instantiation_error()),
+	high => return Err(type_error("integer", high)),
+    };
+    match solutions.deref(args[2].clone()) {
+	Term::Int(x) => Ok(low <= x && high.is_none_or(|high| x <= high)),
+	x @ Term::Var(_) => Ok(solutions.unify_range(x, low, high)),
+	x => Err(type_error("integer", x)),
+    }
+}
+
+/// The elements of a proper list
+fn list_to_vec(list: &Term) -> Result<Vec<Term>, PrologError> {
+    let mut elements = Vec::new();
+    let mut list = list;
+    loop {
+	match list {
+	    Term::Atom(name) if name == Known::Nil => return Ok(elements),
+	    Term::Str(name, args) if name == Known::Dot && args.len() == 2 => {
+		elements.push(args[0].clone());
+		list = &args[1];
+	    }
+	    Term::Var(_) => return Err(instantiation_error()),
+	    _ => return Err(type_error("list", list.clone())),
+	}
+    }
+}
+
+fn vec_to_list(elements: Vec<Term>) -> Term {
+    elements.into_iter().rev().fold(Term::Atom(Known::Nil.into()), |list, element| Term::Str(Known::Dot.into(), vec![element, list].into()))
+}
+
+fn op(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let priority = match solutions.substitute(&args[0]) {
+	Term::Var(_) => return Err(instantiation_error()),
+	Term::Int(priority) => match priority.to_i64() {
+	    Some(priority @ 0..=1200) => priority as u32,
+	    _ => return Err(domain_error("operator_priority", Term::Int(priority))),
+	},
+	priority => return Err(type_error("integer", priority)),
+    };
+    let op_type = match solutions.substitute(&args[1]) {
+	Term::Var(_) => return Err(instantiation_error()),
+	Term::Atom(name) => match OpType::from_name(&name.name()) {
+	    Some(op_type) => op_type,
+	    None => return Err(domain_error("operator_specifier", Term::Atom(name))),
+	},
+	op_type => return Err(type_error("atom", op_type)),
+    };
+    let names = match solutions.substitute(&args[2]) {
+	Term::Atom(name) if name != Known::Nil => vec![Term::Atom(name)],
+	names => list_to_vec(&names)?,
+    };
+    let mut atoms = Vec::new();
+    for name in names {
+	match name {
+	    Term::Var(_) => return Err(instantiation_error()),
+	    Term::Atom(ref atom) if atom == Known::Comma => return Err(permission_error("modify", "operator", name)),
+	    Term::Atom(ref atom) if atom == "|" && priority != 0 && (priority < 1001 || !matches!(op_type, OpType::Xfx | OpType::Xfy | OpType::Yfx)) => {
+		return Err(permission_error("create", "operator", name));
+	    }
+	    Term::Atom(atom) => atoms.push(atom),
+	    name => return Err(type_error("atom", name)),
+	}
+    }
+    let operators = solutions.machine().operators_mut();
+    for atom in atoms {
+	operators.add(priority, op_type, &atom.name());
+    }
+    Ok(true)
+}
+
+fn current_op(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match solutions.substitute(&args[0]) {
+	Term::Var(_) => (),
+	Term::Int(ref priority) if matches!(priority.to_i64(), Some(0..=1200)) => (),
+	priority => return Err(domain_error("operator_priority", priority)),
+    }
+    match solutions.substitute(&args[1]) {
+	Term::Var(_) => (),
+	Term::Atom(ref name) if OpType::from_name(&name.name()).is_some() => (),
+	op_type => return Err(domain_error("operator_specifier", op_type)),
+    }
+    match solutions.substitute(&args[2]) {
+	Term::Var(_) | Term::Atom(_) => (),
+	name => return Err(type_error("atom", name)),
+    }
+    let mut ops: Vec<(u32, OpType, String)> = solutions.machine().operators().iter()
+	.map(|(priority, op_type, name)| (priority, op_type, name.to_string()))
+	.collect();
+    ops.sort_by(|x, y| (&x.2, x.0).cmp(&(&y.2, y.0)));
+    let candidates = ops.into_iter()
+	.map(|(priority, op_type, name)| {
+	    Term::Str("op".into(), vec![Term::Int((priority as i64).into()), Term::Atom(op_type.name().into()), Term::Atom(name.into())].into())
+	})
+	.collect();
+    Ok(solutions.unify_alternatives(Term::Str("op".into(), args.to_vec().into()), candidates))
+}
+
+fn set_prolog_flag(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let flag = solutions.substitute(&args[0]);
+    let value = solutions.substitute(&args[1]);
+    solutions.machine().flags_mut().set(&flag, &value)?;
+    Ok(true)
+}
+
+fn current_prolog_flag(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let values = solutions.machine().flags().values();
+    match solutions.substitute(&args[0]) {
+	Term::Var(_) => (),
+	Term::Atom(ref name) if values.iter().any(|(flag, _)| name == flag) => (),
+	Term::Atom(name) => return Err(domain_error("prolog_flag", Term::Atom(name))),
+	flag => return Err(type_error("atom", flag)),
+    }
+    let candidates = values.into_iter()
+	.map(|(flag, value)| Term::Str("flag".into(), vec![Term::Atom(flag.into()), value].into()))
+	.collect();
+    Ok(solutions.unify_alternatives(Term::Str("flag".into(), args.to_vec().into()), candidates))
+}
+
+/// The properties of the predicates whose head unifies with the first
+/// argument: `built_in`, `dynamic` or `static`, `defined`,
+/// `discontiguous`, `multifile`, `number_of_clauses(N)` and
+/// `indexed(Arguments)`, the positions of the arguments that calls have
+/// indexed
+fn predicate_property(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let (module, head) = solutions.strip_module(solutions.substitute(&args[0]));
+    let database = solutions.machine().database();
+    let mut predicates: Vec<Predicate> = match Predicate::from_term(&head) {
+	Some(predicate) => vec![database.resolve(&module, &predicate.name, predicate.arity()).unwrap_or(predicate)],
+	None if matches!(head, Term::Var(_)) => database.predicates().filter(|predicate| predicate.module == module).cloned().collect(),
+	None => return Err(type_error("callable", head)),
+    };
+    predicates.sort_by_key(|predicate| (predicate.name.name(), predicate.arity()));
+    let mut candidates = Vec::new();
+    for predicate in predicates {
+	let database = solutions.machine().database();
+	let mut properties = Vec::new();
+	if is_builtin(&predicate.name, predicate.arity()) {
+	    properties.extend(["built_in", "static", "defined"].map(|property| Term::Atom(property.into())));
+	} else if let Some(clauses) = database.get_clauses(&predicate) {
+	    let kind = if database.is_dynamic(&predicate) { "dynamic" } else { "static" };
+	    properties.push(Term::Atom(kind.into()));
+	    properties.push(Term::Atom("defined".into()));
+	    if database.is_discontiguous(&predicate) {
+		properties.push(Term::Atom("discontiguous".into()));
+	    }
+	    if database.is_multifile(&predicate) {
+		properties.push(Term::Atom("multifile".into()));
+	    }
+	    properties.push(Term::Str("number_of_clauses".into(), vec![Term::Int((clauses.len() as i64).into())].into()));
+	    let indexed = database.indexed_arguments(&predicate);
+	    if !indexed.is_empty() {
+		let indexed = indexed.into_iter().map(|argument| Term::Int((argument as i64).into())).collect();
+		properties.push(Term::Str("indexed".into(), vec![vec_to_list(indexed)].into()));
+	    }
+	}
+	let head = predicate_head(solutions, &predicate);
+	candidates.extend(properties.into_iter().map(|property| Term::Str("-".into(), vec![head.clone(), property].into())));
+    }
+    Ok(solutions.unify_alternatives(Term::Str("-".into(), vec![head, args[1].clone()].into()), candidates))
+}
+
+/// The most general goal of a predicate, with fresh variables as arguments
+fn predicate_head(solutions: &mut Solutions, predicate: &Predicate) -> Term {
+    match predicate.arity() {
+	0 => Term::Atom(predicate.name.clone()),
+	arity => Term::Str(predicate.name.clone(), (0..arity).map(|_| solutions.fresh_variable()).collect::<Vec<_>>().into()),
+    }
+}
+
+fn clause(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let (module, head) = solutions.strip_module(solutions.substitute(&args[0]));
+    let body = solutions.substitute(&args[1]);
+    let predicate = match Predicate::from_term(&head) {
+	Some(predicate) => solutions.machine().database().resolve(&module, &predicate.name, predicate.arity())
+	    .unwrap_or(predicate.in_module(module)),
+	None if matches!(head, Term::Var(_)) => return Err(instantiation_error()),
+	None => return Err(type_error("callable", head)),
+    };
+    if let Term::Int(_) | Term::Float(_) = body {
+	return Err(type_error("callable", body));
+    }
+    if is_builtin(&predicate.name, predicate.arity()) {
+	return Err(permission_error("access", "private_procedure", indicator(&predicate.name.name(), predicate.arity())));
+    }
+    let candidates = solutions.clauses(&predicate, &head);
+    Ok(solutions.unify_alternatives(Term::Str(":-".into(), vec![head, body].into()), candidates))
+}
+
+/// Enumerates the predicates defined in a module as `Name/Arity`
+fn current_predicate(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let (module, spec) = solutions.strip_module(solutions.substitute(&args[0]));
+    match &spec {
+	Term::Var(_) => (),
+	Term::Str(name, args) if name == Known::Slash && args.len() == 2
+	    && matches!(args[0], Term::Var(_) | Term::Atom(_))
+	    && matches!(args[1], Term::Var(_) | Term::Int(_)) => (),
+	_ => return Err(type_error("predicate_indicator", spec)),
+    }
+    let machine = solutions.machine();
+    let mut predicates: Vec<Predicate> = machine.database().predicates()
+	.filter(|predicate| predicate.module == module && !machine.is_library(predicate))
+	.cloned()
+	.collect();
+    predicates.sort_by_key(|predicate| (predicate.name.name(), predicate.arity()));
+    let candidates = predicates.iter().map(|predicate| indicator(&predicate.name.name(), predicate.arity())).collect();
+    Ok(solutions.unify_alternatives(spec, candidates))
+}
+
+fn portray_clause(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let clause = solutions.substitute(&args[0]);
+    let text = term::portray_clause(&clause, solutions.machine().operators());
+    solutions.machine().print(&format!("{}\n", text));
+    Ok(true)
+}
+
+/// Prints the clauses of the predicates with a name, or of a `Name/Arity`
+fn listing(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let (module, spec) = solutions.strip_module(solutions.substitute(&args[0]));
+    let machine = solutions.machine();
+    let mut predicates: Vec<Predicate> = match spec {
+	Term::Atom(name) => machine.database().predicates()
+	    .filter(|predicate| predicate.name == name && predicate.module == module)
+	    .cloned()
+	    .collect(),
+	spec => vec![predicate_indicator(&spec)?.in_module(module)],
+    };
+    predicates.sort_by_key(|predicate| predicate.arity());
+    for predicate in predicates {
+	let text = listing_text(machine, &predicate);
+	machine.print(&text);
+    }
+    Ok(true)
+}
+
+/// What `listing/1` prints for a predicate
+fn listing_text(machine: &Machine, predicate: &Predicate) -> String {
+    let database = machine.database();
+    let mut text = String::new();
+    if database.is_dynamic(predicate) {
+	let indicator = indicator(&predicate.name.name(), predicate.arity());
+	text.push_str(&format!(":- dynamic {}.\n\n", term::write_term(&indicator, machine.operators(), 999)));
+    }
+    for clause in database.get_clauses(predicate).unwrap_or_default() {
+	text.push_str(&term::portray_clause(&clause.to_term(), machine.operators()));
+	text.push('\n');
+    }
+    text.push('\n');
+    text
+}
+
+/// Reads a `Name/Arity` predicate indicator
+fn predicate_indicator(term: &Term) -> Result<Predicate, PrologError> {
+    match term {
+	Term::Var(_) => Err(instantiation_error()),
+	Term::Str(name, args) if name == Known::Slash && args.len() == 2 => match (&args[0], &args[1]) {
+	    (Term::Var(_), _) | (_, Term::Var(_)) => Err(instantiation_error()),
+	    (Term::Atom(name), Term::Int(arity)) => match arity.to_i64() {
+		Some(arity) if arity >= 0 => Ok(Predicate::new(name.clone(), arity as usize)),
+		_ => Err(domain_error("not_less_than_zero", args[1].clone())),
+	    },
+	    (Term::Atom(_), arity) => Err(type_error("integer", arity.clone())),
+	    (name, _) => Err(type_error("atom", name.clone())),
+	},
+	term => Err(type_error("predicate_indicator", term.clone())),
+    }
+}
+
+/// Fails with a permission error if the clauses of a predicate can't be
+/// changed: it is a builtin or it was consulted without being declared
+/// dynamic
+fn check_modifiable(solutions: &mut Solutions, predicate: &Predicate) -> Result<(), PrologError> {
+    if is_builtin(&predicate.name, predicate.arity()) || solutions.machine().database().is_static(predicate) {
+	Err(permission_error("modify", "static_procedure", predicate.indicator()))
+    } else {
+	Ok(())
+    }
+}
+
+fn add_clause(solutions: &mut Solutions, args: &[Term], first: bool) -> Result<bool, PrologError> {
+    let (module, clause) = match solutions.strip_module(solutions.substitute(&args[0])) {
+	(module, Term::Str(name, args)) if name == Known::Neck && args.len() == 2 => {
+	    let (module, head) = solutions.strip_module_in(module, args[0].clone());
+	    (module, Term::Str(name, vec![head, args[1].clone()].into()))
+	}
+	qualified => qualified,
+    };
+    let clause = parser::term_to_clause(clause)?;
+    let predicate = Predicate::from_clause(&clause).expect("the head is callable").in_module(module.clone());
+    check_modifiable(solutions, &predicate)?;
+    solutions.machine().database_mut().assert_clause(&module, number_variables(&clause), first);
+    Ok(true)
+}
+
+fn assertz(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    add_clause(solutions, args, false)
+}
+
+fn asserta(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    add_clause(solutions, args, true)
+}
+
+fn retract(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let (module, head, body) = match solutions.strip_module(solutions.substitute(&args[0])) {
+	(module, Term::Str(name, args)) if name == Known::Neck && args.len() == 2 => {
+	    let (module, head) = solutions.strip_module_in(module, args[0].clone());
+	    (module, head, args[1].clone())
+	}
+	(module, head) => (module, head, Term::Atom("true".into())),
+    };
+    let predicate = match Predicate::from_term(&head) {
+	Some(predicate) => predicate.in_module(module),
+	None if matches!(head, Term::Var(_)) => return Err(instantiation_error()),
+	None => return Err(type_error("callable", head)),
+    };
+    check_modifiable(solutions, &predicate)?;
+    Ok(solutions.retract(head, body, predicate))
+}
+
+fn abolish(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let (module, spec) = solutions.strip_module(solutions.substitute(&args[0]));
+    let predicate = predicate_indicator(&spec)?.in_module(module);
+    check_modifiable(solutions, &predicate)?;
+    solutions.machine().database_mut().clear_predicate(&predicate);
+    Ok(true)
+}
+
+/// Collects the instances of a template for every solution of a goal.
+/// `findall/4` gives the list with a tail.
+fn findall(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let goal = solutions.substitute(&args[1]);
+    let results = solutions.find_all(&args[0], goal)?;
+    let tail = args.get(3).cloned().unwrap_or_else(|| Term::Atom(Known::Nil.into()));
+    let list = results.into_iter().rev().fold(tail, |list, result| Term::Str(Known::Dot.into(), vec![result, list].into()));
+    Ok(solutions.unify(args[2].clone(), list))
+}
+
+fn bagof(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    solution_groups(solutions, args, false)
+}
+
+fn setof(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    solution_groups(solutions, args, true)
+}
+
+/// Finds the solutions of `bagof/3` and `setof/3`. The variables of the
+/// goal that are not in the template nor quantified with `Var^Goal` are
+/// free: there is a list of solutions for each of their bindings, given
+/// on backtracking. `setof/3` sorts them and removes the duplicates.
+fn solution_groups(solutions: &mut Solutions, args: &[Term], sorted: bool) -> Result<bool, PrologError> {
+    let template = solutions.substitute(&args[0]);
+    let (module, mut goal) = solutions.strip_module(solutions.substitute(&args[1]));
+    let mut bound = vec![template.clone()];
+    loop {
+	match goal {
+	    Term::Str(name, args) if name == "^" && args.len() == 2 => {
+		bound.push(args[0].clone());
+		goal = args[1].clone();
+	    }
+	    _ => break,
+	}
+    }
+    let bound = find_variables_in_goals(&bound);
+    let free = find_variables_in_goals(&[goal.clone()]).into_iter()
+	.filter(|var| !bound.contains(var))
+	.map(Term::Var)
+	.collect();
+    let witness = vec_to_list(free);
+    let goal = Term::Str(":".into(), vec![Term::Atom(module), goal].into());
+    let mut results: Vec<(Term, Term)> = solutions.find_all(&Term::Str("-".into(), vec![witness.clone(), template].into()), goal)?
+	.into_iter()
+	.map(|result| match result {
+	    Term::Str(_, args) => (args[0].clone(), args[1].clone()),
+	    _ => unreachable!("a witness-template pair"),
+	})
+	.collect();
+    results.sort_by(|(x, _), (y, _)| x.cmp(y));
+    let mut groups: Vec<(Term, Vec<Term>)> = Vec::new();
+    for (key, result) in results {
+	match groups.last_mut() {
+	    Some((last, group)) if term::is_variant(last, &key) => group.push(result),
+	    _ => groups.push((key, vec![result])),
+	}
+    }
+    let candidates = groups.into_iter()
+	.map(|(key, mut group)| {
+	    if sorted {
+		sort_unique(&mut group);
+	    }
+	    Term::Str("-".into(), vec![key, vec_to_list(group)].into())
+	})
+	.collect();
+    Ok(solutions.unify_alternatives(Term::Str("-".into(), vec![witness, args[2].clone()].into()), candidates))
+}
+
+/// Sorts terms in the standard order, removing the duplicates
+fn sort_unique(terms: &mut Vec<Term>) {
+    terms.sort();
+    terms.dedup();
+}
+
+/// Aggregates the solutions of a goal: `count`, `sum(Expr)`, `max(Expr)`,
+/// `min(Expr)`, `bag(Template)` or `set(Template)`. `max` and `min` fail
+/// if there are no solutions, and compare in the standard order of terms
+/// when some value is not a number.
+fn aggregate_all(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let spec = solutions.substitute(&args[0]);
+    let goal = solutions.substitute(&args[1]);
+    let (kind, template) = match &spec {
+	Term::Var(_) => return Err(instantiation_error()),
+	Term::Atom(name) if *name == "count" => (name.name(), Term::Atom("true".into())),
+	Term::Str(name, args) if args.len() == 1 && matches!(&*name.name(), "sum" | "max" | "min" | "bag" | "set") => {
+	    (name.name(), args[0].clone())
+	}
+	_ => return Err(domain_error("aggregate_spec", spec)),
+    };
+    let mut results = solutions.find_all(&template, goal)?;
+    let value = match &*kind {
+	"count" => Term::Int((results.len() as i64).into()),
+	"sum" => {
+	    let mut sum = arith::Number::from(0);
+	    for result in results {
+		sum = arith::eval(&Term::Str("+".into(), vec![sum.to_term(), result].into()))?;
+	    }
+	    sum.to_term()
+	}
+	"max" | "min" => {
+	    let wanted = if &*kind == "max" { Ordering::Greater } else { Ordering::Less };
+	    // Values that aren't all numbers are compared in the standard order
+	    let best = match results.iter().map(arith::eval).collect::<Result<Vec<_>, _>>() {
+		Ok(values) => values.into_iter().reduce(|best, value| if arith::compare(&value, &best) == wanted { value } else { best })
+		    .map(|best| best.to_term()),
+		Err(_) => results.into_iter().reduce(|best, result| if result.cmp(&best) == wanted { result } else { best }),
+	    };
+	    match best {
+		Some(best) => best,
+		None => return Ok(false),
+	    }
+	}
+	"bag" => vec_to_list(results),
+	_ => {
+	    sort_unique(&mut results);
+	    vec_to_list(results)
+	}
+    };
+    Ok(solutions.unify(args[2].clone(), value))
+}
+
+/// Reads the predicates of a declaration like `dynamic/1`: an indicator,
+/// or a list or conjunction of them, like `dynamic((counter/1, seen/2))`
+fn declared_predicates(solutions: &mut Solutions, spec: &Term) -> Result<Vec<Predicate>, PrologError> {
+    let mut predicates = Vec::new();
+    let mut pending = vec![solutions.substitute(spec)];
+    while let Some(spec) = pending.pop() {
+	match spec {
+	    Term::Str(name, args) if (name == Known::Comma || name == Known::Dot) && args.len() == 2 => {
+		pending.push(args[1].clone());
+		pending.push(args[0].clone());
+	    }
+	    Term::Atom(name) if name == Known::Nil => (),
+	    spec => {
+		let (module, spec) = solutions.strip_module(spec);
+		predicates.push(predicate_indicator(&spec)?.in_module(module));
+	    }
+	}
+    }
+    Ok(predicates)
+}
+
+fn dynamic(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    for predicate in declared_predicates(solutions, &args[0])? {
+	check_modifiable(solutions, &predicate)?;
+	solutions.machine().database_mut().set_dynamic(&predicate);
+    }
+    Ok(true)
+}
+
+/// Declares that the clauses of predicates may be apart in their file
+fn discontiguous(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    for predicate in declared_predicates(solutions, &args[0])? {
+	solutions.machine().database_mut().set_discontiguous(&predicate);
+    }
+    Ok(true)
+}
+
+/// Declares that the clauses of predicates may come from several files
+fn multifile(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    for predicate in declared_predicates(solutions, &args[0])? {
+	solutions.machine().database_mut().set_multifile(&predicate);
+    }
+    Ok(true)
+}
+
+/// Reads a list of `Name/Arity` or `Name//Arity` indicators, as a module
+/// exports or imports them. Operators in an export list are defined.
+fn import_list(solutions: &mut Solutions, list: &Term) -> Result<Vec<(Atom, usize)>, PrologError> {
+    let mut predicates = Vec::new();
+    for spec in list_to_vec(list)? {
+	let spec = solutions.substitute(&spec);
+	match &spec {
+	    Term::Str(name, args) if name == "op" && args.len() == 3 => {
+		op(solutions, args)?;
+	    }
+	    Term::Str(name, args) if name == "//" && args.len() == 2 => {
+		let predicate = predicate_indicator(&Term::Str("/".into(), args.clone()))?;
+		predicates.push((predicate.name.clone(), predicate.arity() + 2));
+	    }
+	    spec => {
+		let predicate = predicate_indicator(spec)?;
+		predicates.push((predicate.name.clone(), predicate.arity()));
+	    }
+	}
+    }
+    Ok(predicates)
+}
+
+/// Starts a module, as the first directive of a file: the clauses that
+/// follow are defined in it
+fn module(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let name = match solutions.substitute(&args[0]) {
+	Term::Atom(name) => name,
+	Term::Var(_) => return Err(instantiation_error()),
+	name => return Err(type_error("atom", name)),
+    };
+    let exports = import_list(solutions, &solutions.substitute(&args[1]))?;
+    let machine = solutions.machine();
+    machine.database_mut().define_module(&name, exports);
+    machine.set_context_module(name);
+    Ok(true)
+}
+
+/// Loads a file, or each file of a list, as `consult/1`,
+/// `ensure_loaded/1` and `use_module/1,2` do. The errors found while
+/// loading are reported and don't stop it.
+fn load_files(solutions: &mut Solutions, files: &Term, reload: bool, only: Option<&[(Atom, usize)]>) -> Result<bool, PrologError> {
+    let files = match solutions.substitute(files) {
+	Term::Atom(name) if name == Known::Nil => Vec::new(),
+	Term::Str(name, args) if name == Known::Dot && args.len() == 2 => list_to_vec(&Term::Str(name, args))?,
+	file => vec![file],
+    };
+    for file in files {
+	let errors = solutions.machine().load_file(&file, reload, only)?;
+	solutions.machine().report_errors(errors);
+    }
+    Ok(true)
+}
+
+/// Loads files, replacing the clauses loaded from them before. The
+/// top level reads `[File]` as a call to it.
+fn consult(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    match args {
+	[file] => load_files(solutions, file, true, None),
+	_ => load_files(solutions, &Term::Str(Known::Dot.into(), args.to_vec().into()), true, None),
+    }
+}
+
+/// Loads files that aren't loaded yet
+fn ensure_loaded(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    load_files(solutions, &args[0], false, None)
+}
+
+/// Loads a module file and imports its exports, or those listed in the
+/// second argument
+fn use_module(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let only = match args.get(1) {
+	Some(list) => Some(import_list(solutions, &solutions.substitute(list))?),
+	None => None,
+    };
+    load_files(solutions, &args[0], false, only.as_deref())
+}
+
+/// Runs a goal once the file being loaded is loaded, or at once if no
+/// file is loading
+fn initialization(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let goal = solutions.substitute(&args[0]);
+    if let Term::Var(_) = goal {
+	return Err(instantiation_error());
+    }
+    let goal = Term::Str("call".into(), vec![goal].into());
+    if !solutions.machine().add_initialization(goal.clone()) {
+	solutions.push_goal(goal);
+    }
+    Ok(true)
+}
+
+/// Declares which arguments of predicates are goals or closures, like
+/// `meta_predicate maplist(2, ?, ?)`. Those given by callers from other
+/// modules are qualified with the caller's module, so they run there.
+fn meta_predicate(solutions: &mut Solutions, args: &[Term]) -> Result<bool, PrologError> {
+    let mut pending = vec![solutions.substitute(&args[0])];
+    while let Some(spec) = pending.pop() {
+	match spec {
+	    Term::Str(name, args) if (name == Known::Comma || name == Known::Dot) && args.len() == 2 => {
+		pending.push(args[1].clone());
+		pending.push(args[0].clone());
+	    }
+	    Term::Atom(name) if name == Known::Nil => (),
+	    spec => {
+		let (module, spec) = solutions.strip_module(spec);
+		let (name, specifiers) = match spec {
+		    Term::Str(name, args) => (name, args),
+		    Term::Var(_) => return Err(instantiation_error()),
+		    spec => return Err(type_error("compound", spec)),
+		};
+		let meta_arguments = specifiers.iter()
+		    .map(|specifier| match specifier {
+			Term::Int(n) => n.to_i64().is_some_and(|n| (0..=9).contains(&n)),
+			Term::Atom(name) => *name == Known::Colon || *name == "^" || *name == "//",
+			_ => false,
+		    })
+		    .collect();
+		let predicate = Predicate::new(name, specifiers.len()).in_module(module);
+		solutions.machine().database_mut().set_meta_arguments(&predicate, meta_arguments);
+	    }
+	}
+    }
+    Ok(true)
+}
+
+#[test]
+fn list_predicates() {
+    let mut machine = Machine::new();
+    machine.consult(":- dynamic(stack/1). stack([]). push(X) :- retract(stack(S)), assertz(stack([X|S])). 'Odd name'(\"x\").").unwrap();
+    assert_eq!(machine.query("push(a), push(b).").count(), 1);
+    assert_eq!(listing_text(&machine, &Predicate::new("stack".into(), 1)), ":- dynamic stack/1.\n\nstack([b,a]).\n\n");
+    assert_eq!(listing_text(&machine, &Predicate::new("push".into(), 1)), "push(A) :-\n    retract(stack(B)),\n    assertz(stack([A|B])).\n\n");
+    assert_eq!(listing_text(&machine, &Predicate::new("Odd name".into(), 1)), "'Odd name'([120]).\n\n");
+    let output = std::rc::Rc::new(std::cell::RefCell::new(String::new()));
+    let sink = output.clone();
+    machine.set_output(move |text| sink.borrow_mut().push_str(text));
+    assert_eq!(machine.query("listing(stack), portray_clause((p(X) :- q(X, _))).").count(), 1);
+    assert_eq!(*output.borrow(), ":- dynamic stack/1.\n\nstack([b,a]).\n\np(A) :-\n    q(A,_).\n");
+}
@@ -1,6 +1,13 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
 
+use crate::atom::Atom;
+use crate::error::indicator;
+use crate::integer::Integer;
+use crate::parser::goals_to_conjunction;
 use crate::term::Term;
+use crate::unify::{Bindings, walk};
 
 #[derive(PartialEq, Debug)]
 pub struct Clause {
@@ -8,57 +15,415 @@ pub struct Clause {
     pub body: Vec<Term>,
 }
 
+impl Clause {
+    /// The clause as a `Head :- Body` term
+    pub fn to_term(&self) -> Term {
+	let body = goals_to_conjunction(self.body.clone());
+	Term::Str(":-".into(), vec![self.head.clone(), body].into())
+    }
+}
+
+/// A predicate: a name and arity in a module. The clauses of a program
+/// that doesn't use modules are in `user`.
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct Predicate {
-    pub name: String,
+    pub module: Atom,
+    pub name: Atom,
     arity: usize,
 }
 impl Predicate {
+    pub fn new(name: Atom, arity: usize) -> Self {
+	Predicate { module: "user".into(), name, arity }
+    }
+
+    pub fn arity(&self) -> usize {
+	self.arity
+    }
+
+    /// The same predicate in another module
+    pub fn in_module(self, module: Atom) -> Self {
+	Predicate { module, ..self }
+    }
+
     pub fn from_clause(clause: &Clause) -> Option<Predicate> {
 	Self::from_term(&clause.head)
     }
 
+    /// The predicate of a goal or clause head, in `user`
     pub fn from_term(term: &Term) -> Option<Predicate> {
-        match term.clone() {
-	    Term::Str(f, args) => Some(Predicate {
-		name: f.clone(),
-		arity: args.len(),
-	    }),
-	    Term::Atom(f) => Some(Predicate {
-		name: f.clone(),
-		arity: 0,
-	    }),
+	match term {
+	    Term::Str(f, args) => Some(Predicate::new(f.clone(), args.len())),
+	    Term::Atom(f) => Some(Predicate::new(f.clone(), 0)),
 	    _ => None
 	}
     }
+
+    /// `Name/Arity`, qualified with the module if it isn't `user`
+    pub fn indicator(&self) -> Term {
+	let indicator = indicator(&self.name.name(), self.arity);
+	if self.module == "user" {
+	    indicator
+	} else {
+	    Term::Str(":".into(), vec![Term::Atom(self.module.clone()), indicator].into())
+	}
+    }
+}
+
+/// The predicates a module exports, and the ones it imports from others
+#[derive(Default)]
+pub struct Module {
+    pub exports: Vec<(Atom, usize)>,
+    imports: HashMap<(Atom, usize), Atom>,
 }
 
+/// An argument of a clause head or a goal, as far as indexing cares: its
+/// atom, number or functor
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+enum Key {
+    Atom(Atom),
+    Int(Integer),
+    Float(u64),
+    Functor(Atom, usize),
+}
+
+impl Key {
+    /// The key of a term, or `None` if it is a variable
+    fn of(term: &Term) -> Option<Key> {
+	match term {
+	    Term::Atom(name) => Some(Key::Atom(name.clone())),
+	    Term::Int(x) => Some(Key::Int(x.clone())),
+	    Term::Float(x) => Some(Key::Float(x.to_bits())),
+	    Term::Str(name, args) => Some(Key::Functor(name.clone(), args.len())),
+	    Term::Var(_) => None,
+	}
+    }
+}
+
+/// A clause in the database. Retracting it doesn't take it away from the
+/// calls already running: it records the generation of the database when
+/// it was erased, and calls made before still see it.
+pub struct StoredClause {
+    pub clause: Clause,
+    erased: Cell<u64>,
+}
+
+impl StoredClause {
+    /// Whether a call made at `generation` sees the clause
+    pub fn visible(&self, generation: u64) -> bool {
+	generation < self.erased.get()
+    }
+
+    pub fn is_erased(&self) -> bool {
+	self.erased.get() != u64::MAX
+    }
+}
+
+/// Clauses in order, shared by the database and the calls going through
+/// them. Changing a list that a call is using copies it first, so the call
+/// goes on with the clauses there were when it started. Otherwise clauses
+/// are added at either end in constant time, for `asserta/1` as well.
+pub type ClauseList = Rc<VecDeque<Rc<StoredClause>>>;
+
+/// The clauses that can match each value of an argument: those with that
+/// value there and those with a variable
+#[derive(Default)]
+struct ArgumentIndex {
+    keys: HashMap<Key, ClauseList>,
+    /// The clauses with a variable in the argument, which match any key
+    /// that is not in the index
+    unindexed: ClauseList,
+}
+
+impl ArgumentIndex {
+    fn build(clauses: &VecDeque<Rc<StoredClause>>, argument: usize) -> Self {
+	let mut index = ArgumentIndex::default();
+	for clause in clauses {
+	    index.add(clause, argument, false);
+	}
+	index
+    }
+
+    fn add(&mut self, clause: &Rc<StoredClause>, argument: usize, first: bool) {
+	match argument_key(&clause.clause.head, argument) {
+	    Some(key) => {
+		let unindexed = &self.unindexed;
+		insert(self.keys.entry(key).or_insert_with(|| unindexed.clone()), clause, first);
+	    }
+	    None => {
+		for clauses in self.keys.values_mut() {
+		    insert(clauses, clause, first);
+		}
+		insert(&mut self.unindexed, clause, first);
+	    }
+	}
+    }
+
+    fn get(&self, key: &Key) -> &ClauseList {
+	self.keys.get(key).unwrap_or(&self.unindexed)
+    }
+}
+
+fn insert(clauses: &mut ClauseList, clause: &Rc<StoredClause>, first: bool) {
+    let clauses = Rc::make_mut(clauses);
+    if first {
+	clauses.push_front(clause.clone());
+    } else {
+	clauses.push_back(clause.clone());
+    }
+}
+
+fn remove_erased(clauses: &mut ClauseList) {
+    Rc::make_mut(clauses).retain(|clause| !clause.is_erased());
+}
+
+fn argument_key(head: &Term, argument: usize) -> Option<Key> {
+    match head {
+	Term::Str(_, args) => Key::of(&args[argument]),
+	_ => None,
+    }
+}
+
+/// The clauses of a predicate, with an index for each argument that calls
+/// have had bound. Indexes are built the first time they could be used and
+/// kept up to date as clauses are added.
+#[derive(Default)]
+struct Procedure {
+    clauses: ClauseList,
+    indexes: Vec<Option<ArgumentIndex>>,
+    dynamic: bool,
+    /// Whether its clauses may be apart in a file
+    discontiguous: bool,
+    /// Whether its clauses may come from several files
+    multifile: bool,
+    /// Which arguments are goals or closures, for a meta-predicate
+    meta_arguments: Vec<bool>,
+    /// How many of the clauses are erased but still in the lists
+    erased: usize,
+}
+
+impl Procedure {
+    fn add(&mut self, clause: Clause, first: bool) {
+	let clause = Rc::new(StoredClause { clause, erased: Cell::new(u64::MAX) });
+	for (argument, index) in self.indexes.iter_mut().enumerate() {
+	    if let Some(index) = index {
+		index.add(&clause, argument, first);
+	    }
+	}
+	insert(&mut self.clauses, &clause, first);
+    }
+
+    /// Erases a clause. The erased clauses are taken out of the lists when
+    /// they are half of them, so each one costs a constant time.
+    fn erase(&mut self, clause: &StoredClause, generation: u64) {
+	clause.erased.set(generation);
+	self.erased += 1;
+	if self.erased * 2 > self.clauses.len() {
+	    remove_erased(&mut self.clauses);
+	    for index in self.indexes.iter_mut().flatten() {
+		index.keys.values_mut().for_each(remove_erased);
+		remove_erased(&mut index.unindexed);
+	    }
+	    self.erased = 0;
+	}
+    }
+
+    /// The clauses that can match a call with these arguments, using the
+    /// index of the bound argument that leaves the fewest
+    fn matching(&mut self, args: &[Term], bindings: &Bindings) -> ClauseList {
+	if self.indexes.len() < args.len() {
+	    self.indexes.resize_with(args.len(), || None);
+	}
+	let mut best = None;
+	let mut fewest = self.clauses.len();
+	for (argument, arg) in args.iter().enumerate() {
+	    if fewest <= 1 {
+		break;
+	    }
+	    let key = match Key::of(&walk(arg.clone(), bindings)) {
+		Some(key) => key,
+		None => continue,
+	    };
+	    let clauses = &self.clauses;
+	    let index = self.indexes[argument].get_or_insert_with(|| ArgumentIndex::build(clauses, argument));
+	    let count = index.get(&key).len();
+	    if count < fewest {
+		fewest = count;
+		best = Some((argument, key));
+	    }
+	}
+	match best {
+	    Some((argument, key)) => self.indexes[argument].as_ref().expect("index was built").get(&key).clone(),
+	    None => self.clauses.clone(),
+	}
+    }
+}
+
+#[derive(Default)]
 pub struct Database {
-    data: HashMap<Predicate, Vec<Clause>>
+    data: HashMap<Predicate, Procedure>,
+    modules: HashMap<Atom, Module>,
+    /// Incremented every time a clause is erased
+    generation: u64,
 }
 
 impl Database {
     pub fn new() -> Self {
-	Database {
-	    data: HashMap::new()
-	}
+	Database::default()
     }
 
     pub fn add_clause(&mut self, clause: Clause) {
-	if let Some(predicate_key) = Predicate::from_clause(&clause) {
-            {
+	self.add_clause_to(&"user".into(), clause);
+    }
 
-		self.data.entry(predicate_key.clone()).or_insert(Vec::new());
-	    }
-	    {
-		let predicate = self.data.get_mut(&predicate_key).unwrap();
-		predicate.push(clause);
-	    }
+    /// Adds a clause to a predicate of a module
+    pub fn add_clause_to(&mut self, module: &Atom, clause: Clause) {
+	if let Some(predicate) = Predicate::from_clause(&clause) {
+	    self.data.entry(predicate.in_module(module.clone())).or_default().add(clause, false);
+	}
+    }
+
+    /// Adds a clause as `asserta/1` (`first`) or `assertz/1` do. The
+    /// predicate becomes dynamic if it didn't exist.
+    pub fn assert_clause(&mut self, module: &Atom, clause: Clause, first: bool) {
+	if let Some(predicate) = Predicate::from_clause(&clause) {
+	    self.data.entry(predicate.in_module(module.clone()))
+		.or_insert_with(|| Procedure { dynamic: true, ..Procedure::default() })
+		.add(clause, first);
+	}
+    }
+
+    /// Declares a predicate as dynamic, creating it without clauses if it
+    /// doesn't exist
+    pub fn set_dynamic(&mut self, predicate: &Predicate) {
+	self.data.entry(predicate.clone()).or_default().dynamic = true;
+    }
+
+    pub fn is_dynamic(&self, predicate: &Predicate) -> bool {
+	self.data.get(predicate).is_some_and(|procedure| procedure.dynamic)
+    }
+
+    /// Whether a predicate exists and can't be modified
+    pub fn is_static(&self, predicate: &Predicate) -> bool {
+	self.data.get(predicate).is_some_and(|procedure| !procedure.dynamic)
+    }
+
+    pub fn set_discontiguous(&mut self, predicate: &Predicate) {
+	self.data.entry(predicate.clone()).or_default().discontiguous = true;
+    }
+
+    pub fn is_discontiguous(&self, predicate: &Predicate) -> bool {
+	self.data.get(predicate).is_some_and(|procedure| procedure.discontiguous)
+    }
+
+    pub fn set_multifile(&mut self, predicate: &Predicate) {
+	self.data.entry(predicate.clone()).or_default().multifile = true;
+    }
+
+    pub fn is_multifile(&self, predicate: &Predicate) -> bool {
+	self.data.get(predicate).is_some_and(|procedure| procedure.multifile)
+    }
+
+    /// Declares which arguments of a predicate are goals or closures, which
+    /// are called in the module of the caller
+    pub fn set_meta_arguments(&mut self, predicate: &Predicate, meta_arguments: Vec<bool>) {
+	self.data.entry(predicate.clone()).or_default().meta_arguments = meta_arguments;
+    }
+
+    pub fn meta_arguments(&self, predicate: &Predicate) -> &[bool] {
+	self.data.get(predicate).map_or(&[], |procedure| &procedure.meta_arguments)
+    }
+
+    pub fn generation(&self) -> u64 {
+	self.generation
+    }
+
+    /// Erases a clause of a predicate, as `retract/1` does
+    pub fn erase(&mut self, predicate: &Predicate, clause: &StoredClause) {
+	if let Some(procedure) = self.data.get_mut(predicate) {
+	    self.generation += 1;
+	    procedure.erase(clause, self.generation);
+	}
+    }
+
+    pub fn get_clauses(&self, predicate: &Predicate) -> Option<Vec<&Clause>> {
+	let procedure = self.data.get(predicate)?;
+	Some(procedure.clauses.iter().filter(|clause| !clause.is_erased()).map(|clause| &clause.clause).collect())
+    }
+
+    /// The clauses of a predicate that can match a goal, in order. Only the
+    /// indexed arguments are looked at, so some of them may still not
+    /// match, and some may be erased already.
+    pub fn matching_clauses(&mut self, predicate: &Predicate, goal: &Term, bindings: &Bindings) -> Option<ClauseList> {
+	let procedure = self.data.get_mut(predicate)?;
+	Some(match goal {
+	    Term::Str(_, args) => procedure.matching(args, bindings),
+	    _ => procedure.clauses.clone(),
+	})
+    }
+
+    /// The arguments of a predicate that have an index, counting from 1
+    pub fn indexed_arguments(&self, predicate: &Predicate) -> Vec<usize> {
+	match self.data.get(predicate) {
+	    Some(procedure) => procedure.indexes.iter().enumerate()
+		.filter(|(_, index)| index.is_some())
+		.map(|(argument, _)| argument + 1)
+		.collect(),
+	    None => Vec::new(),
 	}
     }
 
-    pub fn get_clauses(&self, predicate: &Predicate) -> Option<&Vec<Clause>> {
-	self.data.get(predicate)
+    pub fn predicates(&self) -> impl Iterator<Item = &Predicate> {
+	self.data.keys()
+    }
+
+    /// The predicate a goal calls from a module: the module's own, one it
+    /// imports, or else one in `user`
+    pub fn resolve(&self, module: &Atom, name: &Atom, arity: usize) -> Option<Predicate> {
+	let predicate = Predicate::new(name.clone(), arity).in_module(module.clone());
+	if self.data.contains_key(&predicate) {
+	    return Some(predicate);
+	}
+	if let Some(source) = self.modules.get(module).and_then(|module| module.imports.get(&(name.clone(), arity))) {
+	    return Some(predicate.in_module(source.clone()));
+	}
+	let predicate = predicate.in_module("user".into());
+	self.data.contains_key(&predicate).then_some(predicate)
+    }
+
+    /// Creates a module, or replaces its exports if it exists
+    pub fn define_module(&mut self, name: &Atom, exports: Vec<(Atom, usize)>) {
+	self.modules.entry(name.clone()).or_default().exports = exports;
+    }
+
+    pub fn module(&self, name: &Atom) -> Option<&Module> {
+	self.modules.get(name)
+    }
+
+    /// Makes the exported predicates of `source`, or those of them in
+    /// `only`, visible in `target`. If `target` already has one of them,
+    /// defined there or imported from elsewhere, nothing is imported and
+    /// that predicate is returned.
+    pub fn import(&mut self, target: &Atom, source: &Atom, only: Option<&[(Atom, usize)]>) -> Result<(), Predicate> {
+	let exports = self.modules.get(source).map(|module| module.exports.clone()).unwrap_or_default();
+	let imported: Vec<(Atom, usize)> = match only {
+	    Some(only) => exports.into_iter().filter(|export| only.contains(export)).collect(),
+	    None => exports,
+	};
+	let imports = self.modules.get(target).map(|module| &module.imports);
+	for (name, arity) in &imported {
+	    let conflict = match imports.and_then(|imports| imports.get(&(name.clone(), *arity))) {
+		Some(other) => other != source,
+		None => self.data.contains_key(&Predicate::new(name.clone(), *arity).in_module(target.clone())),
+	    };
+	    if conflict {
+		return Err(Predicate::new(name.clone(), *arity).in_module(source.clone()));
+	    }
+	}
+	let imports = &mut self.modules.entry(target.clone()).or_default().imports;
+	for key in imported {
+	    imports.insert(key, source.clone());
+	}
+	Ok(())
     }
 
     pub fn clear_all(&mut self) {
@@ -73,28 +438,129 @@ impl Database {
 #[test]
 fn add_rule() {
     let clause = Clause {
-	head: Term::Str("mortal".into(), vec![Term::Var("X".into())]),
-	body: vec![Term::Str("human".into(), vec![Term::Var("X".into())])],
+	head: Term::Str("mortal".into(), vec![Term::Var(0)].into()),
+	body: vec![Term::Str("human".into(), vec![Term::Var(0)].into())],
     };
 
     let mut db = Database::new();
     db.add_clause(clause);
-    let clauses = db.get_clauses(&Predicate { name: "mortal".into(), arity: 1}).unwrap();
+    let clauses = db.get_clauses(&Predicate::new("mortal".into(), 1)).unwrap();
     assert_eq!(clauses.len(), 1);
 }
 
 #[test]
 fn add_fact() {
     let clause = Clause {
-	head: Term::Str("human".into(), vec![Term::Atom("socrates".into())]),
+	head: Term::Str("human".into(), vec![Term::Atom("socrates".into())].into()),
 	body: vec![Term::Atom("true".into())],
     };
 
     let mut db = Database::new();
     db.add_clause(clause);
-    let clauses = db.get_clauses(&Predicate { name: "human".into(), arity: 1}).unwrap();
+    let clauses = db.get_clauses(&Predicate::new("human".into(), 1)).unwrap();
     assert_eq!(clauses.len(), 1);
     assert_eq!(clauses[0].body, vec![Term::Atom("true".into())]);
-    let clauses = db.get_clauses(&Predicate { name: "human".into(), arity: 0});
+    let clauses = db.get_clauses(&Predicate::new("human".into(), 0));
     assert!(clauses.is_none());
 }
+
+/// The positions of the clauses that can match a goal
+#[cfg(test)]
+fn matching_positions(db: &mut Database, predicate: &Predicate, goal: &Term, bindings: &Bindings) -> Vec<usize> {
+    let matching = db.matching_clauses(predicate, goal, bindings).unwrap();
+    let clauses = &db.data[predicate].clauses;
+    matching.iter().map(|clause| clauses.iter().position(|other| Rc::ptr_eq(clause, other)).unwrap()).collect()
+}
+
+#[test]
+fn first_argument_index() {
+    let fact = |arg: Term| Clause { head: Term::Str("p".into(), vec![arg].into()), body: vec![] };
+    let goal = |arg: Term| Term::Str("p".into(), vec![arg].into());
+    let mut db = Database::new();
+    db.add_clause(fact(Term::Atom("a".into())));
+    db.add_clause(fact(Term::Var(0)));
+    db.add_clause(fact(Term::Int(1.into())));
+    db.add_clause(fact(Term::Str("f".into(), vec![Term::Var(0)].into())));
+    db.add_clause(fact(Term::Atom("a".into())));
+    let predicate = Predicate::new("p".into(), 1);
+    let mut bindings = Bindings::new();
+    let mut matching = |term: Term, bindings: &Bindings| matching_positions(&mut db, &predicate, &goal(term), bindings);
+    assert_eq!(matching(Term::Atom("a".into()), &bindings), vec![0, 1, 4]);
+    assert_eq!(matching(Term::Atom("b".into()), &bindings), vec![1]);
+    assert_eq!(matching(Term::Int(1.into()), &bindings), vec![1, 2]);
+    assert_eq!(matching(Term::Float(1.0), &bindings), vec![1]);
+    assert_eq!(matching(Term::Str("f".into(), vec![Term::Atom("x".into())].into()), &bindings), vec![1, 3]);
+    assert_eq!(matching(Term::Str("g".into(), vec![Term::Atom("x".into())].into()), &bindings), vec![1]);
+    assert_eq!(matching(Term::Var(0), &bindings), vec![0, 1, 2, 3, 4]);
+    bindings.bind(0, Term::Int(1.into()));
+    assert_eq!(matching(Term::Var(0), &bindings), vec![1, 2]);
+}
+
+#[test]
+fn index_arguments_on_demand() {
+    let fact = |x: &str, y: &str| Clause { head: Term::Str("q".into(), vec![Term::Atom(x.into()), Term::Atom(y.into())].into()), body: vec![] };
+    let goal = |x: Term, y: Term| Term::Str("q".into(), vec![x, y].into());
+    let mut db = Database::new();
+    db.add_clause(fact("a", "x"));
+    db.add_clause(fact("b", "y"));
+    db.add_clause(fact("c", "x"));
+    let predicate = Predicate::new("q".into(), 2);
+    let bindings = Bindings::new();
+    assert!(db.indexed_arguments(&predicate).is_empty());
+    let x = Term::Atom("x".into());
+    assert_eq!(matching_positions(&mut db, &predicate, &goal(Term::Var(0), x.clone()), &bindings), vec![0, 2]);
+    assert_eq!(db.indexed_arguments(&predicate), vec![2]);
+    // Indexes are kept up to date
+    db.add_clause(fact("d", "x"));
+    assert_eq!(matching_positions(&mut db, &predicate, &goal(Term::Var(0), x.clone()), &bindings), vec![0, 2, 3]);
+    // The most selective index is used
+    assert_eq!(matching_positions(&mut db, &predicate, &goal(Term::Atom("c".into()), x), &bindings), vec![2]);
+    assert_eq!(db.indexed_arguments(&predicate), vec![1, 2]);
+}
+
+#[test]
+fn logical_update_view() {
+    let fact = |x: i64| Clause { head: Term::Str("r".into(), vec![Term::Int(x.into())].into()), body: vec![] };
+    let predicate = Predicate::new("r".into(), 1);
+    let mut db = Database::new();
+    for x in 0..4 {
+	db.assert_clause(&"user".into(), fact(x), false);
+    }
+    assert!(!db.is_static(&predicate));
+    let goal = Term::Str("r".into(), vec![Term::Var(0)].into());
+    let running = db.matching_clauses(&predicate, &goal, &Bindings::new()).unwrap();
+    let generation = db.generation();
+    db.erase(&predicate, &running[1]);
+    db.assert_clause(&"user".into(), fact(-1), true);
+    // The running call still sees the clauses there were when it started
+    assert_eq!(running.len(), 4);
+    assert!(running.iter().all(|clause| clause.visible(generation)));
+    assert!(!running[1].visible(db.generation()));
+    assert_eq!(db.get_clauses(&predicate).unwrap(), vec![&fact(-1), &fact(0), &fact(2), &fact(3)]);
+    // Erased clauses are taken out of the lists once they are many
+    db.erase(&predicate, &running[0]);
+    db.erase(&predicate, &running[2]);
+    assert_eq!(db.data[&predicate].clauses.len(), 2);
+    assert_eq!(db.get_clauses(&predicate).unwrap(), vec![&fact(-1), &fact(3)]);
+}
+
+#[test]
+fn assert_first() {
+    let fact = |x: i64| Clause { head: Term::Str("s".into(), vec![Term::Int(x.into())].into()), body: vec![] };
+    let predicate = Predicate::new("s".into(), 1);
+    let mut db = Database::new();
+    db.assert_clause(&"user".into(), fact(0), false);
+    // Index the argument, so its lists are kept up to date too
+    let goal = |arg: Term| Term::Str("s".into(), vec![arg].into());
+    assert_eq!(matching_positions(&mut db, &predicate, &goal(Term::Int(0.into())), &Bindings::new()), vec![0]);
+    for x in 1..100_000 {
+	db.assert_clause(&"user".into(), fact(x), x % 2 == 0);
+    }
+    let clauses = db.get_clauses(&predicate).unwrap();
+    assert_eq!(clauses.len(), 100_000);
+    assert_eq!(clauses[0], &fact(99_998));
+    assert_eq!(clauses[49_999], &fact(0));
+    assert_eq!(clauses[50_000], &fact(1));
+    assert_eq!(clauses[99_999], &fact(99_999));
+    assert_eq!(matching_positions(&mut db, &predicate, &goal(Term::Int(2.into())), &Bindings::new()), vec![49_998]);
+}
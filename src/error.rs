@@ -0,0 +1,137 @@
+use std::fmt;
+
+use crate::term::Term;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrologError {
+    SyntaxError(SyntaxError),
+    /// An error term raised while proving a goal, like `error(type_error(evaluable, foo/0), _)`
+    Exception(Term),
+}
+
+impl fmt::Display for PrologError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    PrologError::SyntaxError(error) => write!(f, "{}", error),
+	    PrologError::Exception(term) => write!(f, "Uncaught exception: {}", term),
+	}
+    }
+}
+
+impl std::error::Error for PrologError {}
+
+/// A text that can't be read as a term. It keeps the position of the error
+/// and the line where it is, to show it to the user:
+///
+/// ```text
+/// family.pl:3:10: syntax error: operator expected, found atom b
+/// parent(a b).
+///          ^
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    /// What was expected, and what was found instead
+    pub message: String,
+    pub file: Option<String>,
+    /// Line and column of the error, counting from 1
+    pub line: usize,
+    pub column: usize,
+    /// The line of the source text with the error
+    pub excerpt: String,
+}
+
+impl SyntaxError {
+    /// An error at the given byte offset of the source text
+    pub fn new(source: &str, offset: usize, message: impl Into<String>) -> Self {
+	let before = &source[..offset];
+	let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+	SyntaxError {
+	    message: message.into(),
+	    file: None,
+	    line: before.matches('\n').count() + 1,
+	    column: before[line_start..].chars().count() + 1,
+	    excerpt: source[line_start..].lines().next().unwrap_or("").to_string(),
+	}
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	if let Some(file) = &self.file {
+	    write!(f, "{}:", file)?;
+	}
+	writeln!(f, "{}:{}: syntax error: {}", self.line, self.column, self.message)?;
+	writeln!(f, "{}", self.excerpt)?;
+	// Keep the tabs of the excerpt so the caret lines up
+	let indent: String = self.excerpt.chars().take(self.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+	write!(f, "{}^", indent)
+    }
+}
+
+fn error(formal: Term) -> PrologError {
+    PrologError::Exception(Term::Str("error".into(), vec![formal, Term::Var(0)].into()))
+}
+
+pub fn instantiation_error() -> PrologError {
+    error(Term::Atom("instantiation_error".into()))
+}
+
+pub fn type_error(kind: &str, culprit: Term) -> PrologError {
+    error(Term::Str("type_error".into(), vec![Term::Atom(kind.into()), culprit].into()))
+}
+
+pub fn evaluation_error(kind: &str) -> PrologError {
+    error(Term::Str("evaluation_error".into(), vec![Term::Atom(kind.into())].into()))
+}
+
+pub fn domain_error(domain: &str, culprit: Term) -> PrologError {
+    error(Term::Str("domain_error".into(), vec![Term::Atom(domain.into()), culprit].into()))
+}
+
+pub fn permission_error(action: &str, kind: &str, culprit: Term) -> PrologError {
+    error(Term::Str("permission_error".into(), vec![Term::Atom(action.into()), Term::Atom(kind.into()), culprit].into()))
+}
+
+/// The error for importing a predicate into a module that already has one
+/// with the same name and arity
+pub fn import_error(module: Term, culprit: Term) -> PrologError {
+    let action = Term::Str("import_into".into(), vec![module].into());
+    error(Term::Str("permission_error".into(), vec![action, Term::Atom("procedure".into()), culprit].into()))
+}
+
+pub fn existence_error(kind: &str, culprit: Term) -> PrologError {
+    error(Term::Str("existence_error".into(), vec![Term::Atom(kind.into()), culprit].into()))
+}
+
+/// The error for binding a variable to a term that contains it, when the
+/// `occurs_check` flag is `error`
+pub fn occurs_check_error(var: Term, term: Term) -> PrologError {
+    error(Term::Str("occurs_check".into(), vec![var, term].into()))
+}
+
+pub fn representation_error(limit: &str) -> PrologError {
+    error(Term::Str("representation_error".into(), vec![Term::Atom(limit.into())].into()))
+}
+
+/// The error for a text that builtins like `term_to_atom/2` can't read
+pub fn syntax_error(message: &str) -> PrologError {
+    error(Term::Str("syntax_error".into(), vec![Term::Atom(message.into())].into()))
+}
+
+pub fn resource_error(resource: &str) -> PrologError {
+    error(Term::Str("resource_error".into(), vec![Term::Atom(resource.into())].into()))
+}
+
+/// The `Name/Arity` indicator of a predicate or evaluable functor
+pub fn indicator(name: &str, arity: usize) -> Term {
+    Term::Str("/".into(), vec![Term::Atom(name.into()), Term::Int((arity as i64).into())].into())
+}
+
+#[test]
+fn show_syntax_error() {
+    let mut error = SyntaxError::new("a.\n\tparent(a b).\n", 13, "operator expected, found atom b");
+    assert_eq!((error.line, error.column), (2, 11));
+    assert_eq!(error.to_string(), "2:11: syntax error: operator expected, found atom b\n\tparent(a b).\n\t         ^");
+    error.file = Some("family.pl".into());
+    assert!(error.to_string().starts_with("family.pl:2:11: syntax error"));
+}
@@ -0,0 +1,114 @@
+use crate::error::{PrologError, instantiation_error, type_error, domain_error, permission_error};
+use crate::integer::Integer;
+use crate::term::Term;
+
+/// What to do when calling a predicate that has no clauses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unknown {
+    /// Raise `existence_error(procedure, Name/Arity)`
+    Error,
+    /// Send a warning to the diagnostics and fail
+    Warning,
+    Fail,
+}
+
+impl Unknown {
+    fn name(&self) -> &'static str {
+	match self {
+	    Unknown::Error => "error",
+	    Unknown::Warning => "warning",
+	    Unknown::Fail => "fail",
+	}
+    }
+}
+
+/// Whether unification checks that a variable isn't bound to a term that
+/// contains it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OccursCheck {
+    False,
+    /// Such unifications fail
+    True,
+    /// Such unifications raise `occurs_check(Var, Term)`
+    Error,
+}
+
+impl OccursCheck {
+    fn name(&self) -> &'static str {
+	match self {
+	    OccursCheck::False => "false",
+	    OccursCheck::True => "true",
+	    OccursCheck::Error => "error",
+	}
+    }
+}
+
+/// The Prolog flags of a machine, read with `current_prolog_flag/2` and
+/// changed with `set_prolog_flag/2`
+#[derive(Debug, Clone)]
+pub struct Flags {
+    pub unknown: Unknown,
+    pub occurs_check: OccursCheck,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+	Flags {
+	    unknown: Unknown::Error,
+	    occurs_check: OccursCheck::False,
+	}
+    }
+}
+
+impl Flags {
+    /// Every flag with its current value
+    pub fn values(&self) -> Vec<(&'static str, Term)> {
+	let atom = |name: &str| Term::Atom(name.into());
+	let mut values = vec![
+	    ("bounded", atom(if cfg!(feature = "bigint") { "false" } else { "true" })),
+	    ("integer_rounding_function", atom("toward_zero")),
+	    ("max_arity", atom("unbounded")),
+	    ("occurs_check", atom(self.occurs_check.name())),
+	    ("unknown", atom(self.unknown.name())),
+	];
+	if !cfg!(feature = "bigint") {
+	    values.push(("max_integer", Term::Int(Integer::from(i64::MAX))));
+	    values.push(("min_integer", Term::Int(Integer::from(i64::MIN))));
+	}
+	values
+    }
+
+    pub fn set(&mut self, flag: &Term, value: &Term) -> Result<(), PrologError> {
+	let name = match flag {
+	    Term::Var(_) => return Err(instantiation_error()),
+	    Term::Atom(name) => name,
+	    flag => return Err(type_error("atom", flag.clone())),
+	};
+	if let Term::Var(_) = value {
+	    return Err(instantiation_error());
+	}
+	let flag_value = || domain_error("flag_value", Term::Str("+".into(), vec![flag.clone(), value.clone()].into()));
+	match &*name.name() {
+	    "unknown" => {
+		self.unknown = match value {
+		    Term::Atom(value) if value == "error" => Unknown::Error,
+		    Term::Atom(value) if value == "warning" => Unknown::Warning,
+		    Term::Atom(value) if value == "fail" => Unknown::Fail,
+		    _ => return Err(flag_value()),
+		};
+		Ok(())
+	    }
+	    "occurs_check" => {
+		self.occurs_check = match value {
+		    Term::Atom(value) if value == "false" => OccursCheck::False,
+		    Term::Atom(value) if value == "true" => OccursCheck::True,
+		    Term::Atom(value) if value == "error" => OccursCheck::Error,
+		    _ => return Err(flag_value()),
+		};
+		Ok(())
+	    }
+	    _ if self.values().iter().any(|(known, _)| name == known) => Err(permission_error("modify", "flag", flag.clone())),
+	    _ => Err(domain_error("prolog_flag", flag.clone())),
+	}
+    }
+}
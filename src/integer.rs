@@ -0,0 +1,327 @@
+// Without big integers `Integer` has a single variant, so the fast paths
+// below always match
+#![cfg_attr(not(feature = "bigint"), allow(irrefutable_let_patterns, unreachable_patterns))]
+
+use std::cmp::Ordering;
+use std::fmt;
+
+#[cfg(feature = "bigint")]
+use num_bigint::BigInt;
+#[cfg(feature = "bigint")]
+use num_integer::Integer as _;
+#[cfg(feature = "bigint")]
+use num_traits::{FromPrimitive, Signed, ToPrimitive};
+
+/// Intermediate type used when a result may not fit in an `i64`
+#[cfg(feature = "bigint")]
+type Wide = BigInt;
+#[cfg(not(feature = "bigint"))]
+type Wide = i128;
+
+/// A Prolog integer. Values that fit in a machine word are stored unboxed and
+/// results that overflow are promoted to big integers, so arithmetic never
+/// wraps. Without the `bigint` feature the operations that would overflow
+/// return `None` instead.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Integer {
+    Small(i64),
+    #[cfg(feature = "bigint")]
+    Big(BigInt),
+}
+
+impl Integer {
+    fn from_wide(x: Wide) -> Option<Integer> {
+	#[cfg(feature = "bigint")]
+	return match x.to_i64() {
+	    Some(x) => Some(Integer::Small(x)),
+	    None => Some(Integer::Big(x)),
+	};
+	#[cfg(not(feature = "bigint"))]
+	i64::try_from(x).ok().map(Integer::Small)
+    }
+
+    fn to_wide(&self) -> Wide {
+	match self {
+	    Integer::Small(x) => Wide::from(*x),
+	    #[cfg(feature = "bigint")]
+	    Integer::Big(x) => x.clone(),
+	}
+    }
+
+    /// Parses the digits of an unsigned integer in the given radix
+    pub fn parse(digits: &str, radix: u32) -> Option<Integer> {
+	#[cfg(feature = "bigint")]
+	let wide = BigInt::parse_bytes(digits.as_bytes(), radix);
+	#[cfg(not(feature = "bigint"))]
+	let wide = i128::from_str_radix(digits, radix).ok();
+	wide.and_then(Integer::from_wide)
+    }
+
+    /// Converts an integral float, failing for infinities and NaN
+    pub fn from_f64(x: f64) -> Option<Integer> {
+	if !x.is_finite() {
+	    return None;
+	}
+	if x >= i64::MIN as f64 && x < i64::MAX as f64 {
+	    return Some(Integer::Small(x as i64));
+	}
+	#[cfg(feature = "bigint")]
+	return BigInt::from_f64(x).and_then(Integer::from_wide);
+	#[cfg(not(feature = "bigint"))]
+	None
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+	match self {
+	    Integer::Small(x) => Some(*x),
+	    #[cfg(feature = "bigint")]
+	    Integer::Big(_) => None,
+	}
+    }
+
+    pub fn to_f64(&self) -> f64 {
+	match self {
+	    Integer::Small(x) => *x as f64,
+	    #[cfg(feature = "bigint")]
+	    Integer::Big(x) => x.to_f64().unwrap_or(f64::NAN),
+	}
+    }
+
+    pub fn signum(&self) -> i64 {
+	match self {
+	    Integer::Small(x) => x.signum(),
+	    #[cfg(feature = "bigint")]
+	    Integer::Big(x) => if x.is_negative() { -1 } else { 1 },
+	}
+    }
+
+    pub fn is_zero(&self) -> bool {
+	self.signum() == 0
+    }
+
+    pub fn checked_add(&self, other: &Integer) -> Option<Integer> {
+	if let (Integer::Small(x), Integer::Small(y)) = (self, other) {
+	    if let Some(z) = x.checked_add(*y) {
+		return Some(Integer::Small(z));
+	    }
+	}
+	Integer::from_wide(self.to_wide() + other.to_wide())
+    }
+
+    pub fn checked_sub(&self, other: &Integer) -> Option<Integer> {
+	if let (Integer::Small(x), Integer::Small(y)) = (self, other) {
+	    if let Some(z) = x.checked_sub(*y) {
+		return Some(Integer::Small(z));
+	    }
+	}
+	Integer::from_wide(self.to_wide() - other.to_wide())
+    }
+
+    pub fn checked_mul(&self, other: &Integer) -> Option<Integer> {
+	if let (Integer::Small(x), Integer::Small(y)) = (self, other) {
+	    if let Some(z) = x.checked_mul(*y) {
+		return Some(Integer::Small(z));
+	    }
+	}
+	Integer::from_wide(self.to_wide() * other.to_wide())
+    }
+
+    pub fn checked_neg(&self) -> Option<Integer> {
+	Integer::Small(0).checked_sub(self)
+    }
+
+    pub fn checked_abs(&self) -> Option<Integer> {
+	if self.signum() < 0 { self.checked_neg() } else { Some(self.clone()) }
+    }
+
+    /// Division rounding toward zero. The divisor must not be zero.
+    pub fn div_trunc(&self, other: &Integer) -> Option<Integer> {
+	if let (Integer::Small(x), Integer::Small(y)) = (self, other) {
+	    if let Some(z) = x.checked_div(*y) {
+		return Some(Integer::Small(z));
+	    }
+	}
+	Integer::from_wide(self.to_wide() / other.to_wide())
+    }
+
+    /// Remainder with the sign of the dividend. The divisor must not be zero.
+    pub fn rem_trunc(&self, other: &Integer) -> Integer {
+	match (self, other) {
+	    (Integer::Small(x), Integer::Small(y)) => Integer::Small(x.wrapping_rem(*y)),
+	    _ => Integer::from_wide(self.to_wide() % other.to_wide()).expect("remainder is smaller than divisor"),
+	}
+    }
+
+    /// Division rounding toward negative infinity. The divisor must not be zero.
+    pub fn div_floor(&self, other: &Integer) -> Option<Integer> {
+	let quotient = self.div_trunc(other)?;
+	if !self.rem_trunc(other).is_zero() && (self.signum() < 0) != (other.signum() < 0) {
+	    quotient.checked_sub(&Integer::Small(1))
+	} else {
+	    Some(quotient)
+	}
+    }
+
+    /// Remainder with the sign of the divisor. The divisor must not be zero.
+    pub fn mod_floor(&self, other: &Integer) -> Integer {
+	let rem = self.rem_trunc(other);
+	if !rem.is_zero() && (rem.signum() < 0) != (other.signum() < 0) {
+	    rem.checked_add(other).expect("modulo is smaller than divisor")
+	} else {
+	    rem
+	}
+    }
+
+    pub fn checked_pow(&self, exponent: u32) -> Option<Integer> {
+	if let Integer::Small(x) = self {
+	    if let Some(z) = x.checked_pow(exponent) {
+		return Some(Integer::Small(z));
+	    }
+	}
+	#[cfg(feature = "bigint")]
+	return Integer::from_wide(num_traits::pow::Pow::pow(self.to_wide(), exponent));
+	#[cfg(not(feature = "bigint"))]
+	self.to_wide().checked_pow(exponent).and_then(Integer::from_wide)
+    }
+
+    /// Arithmetic shift to the left, or to the right for negative shifts
+    pub fn checked_shl(&self, shift: i64) -> Option<Integer> {
+	if shift < 0 {
+	    return Some(self.shr(shift.checked_neg().unwrap_or(i64::MAX)));
+	}
+	if self.is_zero() {
+	    return Some(Integer::Small(0));
+	}
+	if let Integer::Small(x) = self {
+	    if shift < 63 && (x << shift) >> shift == *x {
+		return Some(Integer::Small(x << shift));
+	    }
+	}
+	let shift = u32::try_from(shift).ok()?;
+	#[cfg(feature = "bigint")]
+	return Integer::from_wide(self.to_wide() << shift);
+	#[cfg(not(feature = "bigint"))]
+	{
+	    let x = self.to_wide();
+	    if shift < 127 && (x << shift) >> shift == x { Integer::from_wide(x << shift) } else { None }
+	}
+    }
+
+    /// Arithmetic shift to the right, rounding toward negative infinity
+    pub fn shr(&self, shift: i64) -> Integer {
+	if shift < 0 {
+	    return self.checked_shl(shift.checked_neg().unwrap_or(i64::MAX)).unwrap_or(Integer::Small(0));
+	}
+	match self {
+	    Integer::Small(x) => Integer::Small(x >> shift.min(63)),
+	    #[cfg(feature = "bigint")]
+	    Integer::Big(x) => {
+		let shift = usize::try_from(shift).unwrap_or(usize::MAX);
+		Integer::from_wide(x >> shift).expect("shifting right never overflows")
+	    }
+	}
+    }
+
+    pub fn bitand(&self, other: &Integer) -> Integer {
+	Integer::from_wide(self.to_wide() & other.to_wide()).expect("bitwise and never overflows")
+    }
+
+    pub fn bitor(&self, other: &Integer) -> Integer {
+	Integer::from_wide(self.to_wide() | other.to_wide()).expect("bitwise or never overflows")
+    }
+
+    pub fn bitxor(&self, other: &Integer) -> Integer {
+	Integer::from_wide(self.to_wide() ^ other.to_wide()).expect("bitwise xor never overflows")
+    }
+
+    pub fn not(&self) -> Integer {
+	Integer::from_wide(!self.to_wide()).expect("bitwise negation never overflows")
+    }
+
+    /// Greatest common divisor, always positive
+    pub fn gcd(&self, other: &Integer) -> Option<Integer> {
+	if let (Integer::Small(x), Integer::Small(y)) = (self, other) {
+	    let (mut x, mut y) = (x.unsigned_abs(), y.unsigned_abs());
+	    while y != 0 {
+		(x, y) = (y, x % y);
+	    }
+	    return Integer::from_wide(Wide::from(x));
+	}
+	#[cfg(feature = "bigint")]
+	return Integer::from_wide(self.to_wide().gcd(&other.to_wide()));
+	#[cfg(not(feature = "bigint"))]
+	None
+    }
+
+    /// Index of the most significant bit set, for positive integers
+    pub fn msb(&self) -> Option<u64> {
+	if self.signum() <= 0 {
+	    return None;
+	}
+	match self {
+	    Integer::Small(x) => Some(63 - x.leading_zeros() as u64),
+	    #[cfg(feature = "bigint")]
+	    Integer::Big(x) => Some(x.bits() - 1),
+	}
+    }
+}
+
+impl From<i64> for Integer {
+    fn from(x: i64) -> Self {
+	Integer::Small(x)
+    }
+}
+
+impl PartialOrd for Integer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+	Some(self.cmp(other))
+    }
+}
+
+impl Ord for Integer {
+    fn cmp(&self, other: &Self) -> Ordering {
+	match (self, other) {
+	    (Integer::Small(x), Integer::Small(y)) => x.cmp(y),
+	    _ => self.to_wide().cmp(&other.to_wide()),
+	}
+    }
+}
+
+impl fmt::Display for Integer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    Integer::Small(x) => write!(f, "{}", x),
+	    #[cfg(feature = "bigint")]
+	    Integer::Big(x) => write!(f, "{}", x),
+	}
+    }
+}
+
+#[test]
+fn promotes_on_overflow() {
+    let max = Integer::from(i64::MAX);
+    let sum = max.checked_add(&Integer::from(1));
+    if cfg!(feature = "bigint") {
+	assert_eq!(sum.unwrap().to_string(), "9223372036854775808");
+	let back = Integer::parse("9223372036854775808", 10).unwrap().checked_sub(&Integer::from(1));
+	assert_eq!(back, Some(max));
+    } else {
+	assert_eq!(sum, None);
+    }
+}
+
+#[cfg(feature = "bigint")]
+#[test]
+fn big_operations() {
+    let big = Integer::parse("100000000000000000000", 10).unwrap();
+    let small = Integer::from(-7);
+    assert_eq!(big.div_floor(&small).unwrap().to_string(), "-14285714285714285715");
+    assert_eq!(big.mod_floor(&small).to_string(), "-5");
+    assert_eq!(big.rem_trunc(&small).to_string(), "2");
+    assert_eq!(big.checked_neg().unwrap().shr(3).to_string(), "-12500000000000000000");
+    assert_eq!(Integer::from(1).checked_shl(100).unwrap().msb(), Some(100));
+    assert_eq!(big.gcd(&Integer::from(30)), Some(Integer::from(10)));
+    assert_eq!(Integer::from(2).checked_pow(64).unwrap().to_string(), "18446744073709551616");
+    assert!(big > Integer::from(i64::MAX));
+    assert!(big.checked_neg().unwrap() < Integer::from(i64::MIN));
+}
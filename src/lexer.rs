@@ -0,0 +1,302 @@
+use crate::integer::Integer;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TokenKind {
+    Name(String),
+    QuotedName(String),
+    Var(String),
+    Int(Integer),
+    Float(f64),
+    /// A double quoted text, read as a list of codes
+    Codes(String),
+    Punct(char),
+    End,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    /// Whether there was whitespace before the token. `f(` is a compound term,
+    /// `f (` is an atom followed by a parenthesized term.
+    pub layout_before: bool,
+    /// Byte offset of the token in the input
+    pub start: usize,
+}
+
+/// A lexical error and the byte offset where it was found
+pub(crate) type LexError = (usize, String);
+
+fn is_symbol_char(c: char) -> bool {
+    "#$&*+-./:<=>?@^~\\".contains(c)
+}
+
+fn is_alphanumeric(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+pub(crate) struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+	Lexer { input, pos: 0 }
+    }
+
+    fn peek_char(&self) -> Option<char> {
+	self.input[self.pos..].chars().next()
+    }
+
+    fn peek_char_at(&self, n: usize) -> Option<char> {
+	self.input[self.pos..].chars().nth(n)
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+	let c = self.peek_char()?;
+	self.pos += c.len_utf8();
+	Some(c)
+    }
+
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+	let start = self.pos;
+	while let Some(c) = self.peek_char() {
+	    if !pred(c) {
+		break;
+	    }
+	    self.pos += c.len_utf8();
+	}
+	&self.input[start..self.pos]
+    }
+
+    pub fn input(&self) -> &'a str {
+	self.input
+    }
+
+    /// Skips a character after an error, so reading can go on
+    pub fn skip_char(&mut self) {
+	self.next_char();
+    }
+
+    /// Skips whitespace and comments, returning whether there was any
+    fn skip_layout(&mut self) -> Result<bool, LexError> {
+	let start = self.pos;
+	loop {
+	    self.take_while(char::is_whitespace);
+	    let rest = &self.input[self.pos..];
+	    if rest.starts_with('%') {
+		self.take_while(|c| c != '\n');
+	    } else if let Some(comment) = rest.strip_prefix("/*") {
+		match comment.find("*/") {
+		    Some(end) => self.pos += end + 4,
+		    None => return Err((self.pos, "unterminated block comment".into())),
+		}
+	    } else {
+		return Ok(self.pos > start);
+	    }
+	}
+    }
+
+    /// Returns the next token, or `None` at the end of the input
+    pub fn next_token(&mut self) -> Result<Option<Token>, LexError> {
+	let layout_before = self.skip_layout()?;
+	let start = self.pos;
+	let c = match self.peek_char() {
+	    Some(c) => c,
+	    None => return Ok(None),
+	};
+	let kind = self.token_kind(c).map_err(|message| (start, message))?;
+	Ok(Some(Token { kind, layout_before, start }))
+    }
+
+    fn token_kind(&mut self, c: char) -> Result<TokenKind, String> {
+	let kind = if c.is_ascii_digit() {
+	    self.number()?
+	} else if c == '_' || c.is_uppercase() {
+	    TokenKind::Var(self.take_while(is_alphanumeric).to_string())
+	} else if c.is_alphabetic() {
+	    TokenKind::Name(self.take_while(is_alphanumeric).to_string())
+	} else if c == '\'' {
+	    self.next_char();
+	    TokenKind::QuotedName(self.quoted('\'')?)
+	} else if c == '"' || c == '`' {
+	    self.next_char();
+	    TokenKind::Codes(self.quoted(c)?)
+	} else if c == '.' && self.peek_char_at(1).map(|c| c.is_whitespace() || c == '%').unwrap_or(true) {
+	    self.next_char();
+	    TokenKind::End
+	} else if is_symbol_char(c) {
+	    TokenKind::Name(self.take_while(is_symbol_char).to_string())
+	} else if c == '!' || c == ';' {
+	    self.next_char();
+	    TokenKind::Name(c.to_string())
+	} else if "()[]{},|".contains(c) {
+	    self.next_char();
+	    TokenKind::Punct(c)
+	} else {
+	    return Err(format!("unexpected character {}", c));
+	};
+	Ok(kind)
+    }
+
+    fn number(&mut self) -> Result<TokenKind, String> {
+	if self.input[self.pos..].starts_with("0'") {
+	    self.pos += 2;
+	    return self.char_code();
+	}
+	for (prefix, radix) in [("0x", 16), ("0o", 8), ("0b", 2)] {
+	    if self.input[self.pos..].starts_with(prefix) && self.peek_char_at(2).map(|c| c.is_digit(radix)).unwrap_or(false) {
+		self.pos += 2;
+		let digits = self.take_while(|c| c.is_digit(radix));
+		return Integer::parse(digits, radix).map(TokenKind::Int).ok_or_else(|| "integer too big".to_string());
+	    }
+	}
+	let start = self.pos;
+	self.take_while(|c| c.is_ascii_digit());
+	let mut is_float = false;
+	if self.peek_char() == Some('.') && self.peek_char_at(1).map(|c| c.is_ascii_digit()).unwrap_or(false) {
+	    is_float = true;
+	    self.next_char();
+	    self.take_while(|c| c.is_ascii_digit());
+	}
+	if let Some('e' | 'E') = self.peek_char() {
+	    let exponent_digit = match self.peek_char_at(1) {
+		Some('+' | '-') => self.peek_char_at(2),
+		c => c,
+	    };
+	    if exponent_digit.map(|c| c.is_ascii_digit()).unwrap_or(false) {
+		is_float = true;
+		self.next_char();
+		if let Some('+' | '-') = self.peek_char() {
+		    self.next_char();
+		}
+		self.take_while(|c| c.is_ascii_digit());
+	    }
+	}
+	let text = &self.input[start..self.pos];
+	if is_float {
+	    text.parse().map(TokenKind::Float).map_err(|_| format!("invalid float {}", text))
+	} else {
+	    Integer::parse(text, 10).map(TokenKind::Int).ok_or_else(|| "integer too big".to_string())
+	}
+    }
+
+    /// The code of a character: `0'a`, `0'\n`, `0'''`
+    fn char_code(&mut self) -> Result<TokenKind, String> {
+	let c = match self.next_char() {
+	    Some('\\') => self.escape()?.ok_or("invalid character code")?,
+	    Some('\'') => {
+		if self.peek_char() == Some('\'') {
+		    self.next_char();
+		}
+		'\''
+	    }
+	    Some(c) => c,
+	    None => return Err("unexpected end of input".into()),
+	};
+	Ok(TokenKind::Int(Integer::from(c as i64)))
+    }
+
+    /// Reads the rest of a quoted text after the opening quote. A quoted
+    /// text can't span lines unless they end with `\`, so when the closing
+    /// quote is missing reading goes on right after the opening one, to
+    /// find the end of the clause there.
+    fn quoted(&mut self, quote: char) -> Result<String, String> {
+	let start = self.pos;
+	let mut text = String::new();
+	loop {
+	    match self.next_char() {
+		None => return Err("unterminated quoted".into()),
+		Some('\n') => {
+		    self.pos = start;
+		    return Err("unterminated quoted".into());
+		}
+		Some(c) if c == quote => {
+		    if self.peek_char() == Some(quote) {
+			self.next_char();
+			text.push(quote);
+		    } else {
+			return Ok(text);
+		    }
+		}
+		Some('\\') => {
+		    if let Some(c) = self.escape()? {
+			text.push(c);
+		    }
+		}
+		Some(c) => text.push(c),
+	    }
+	}
+    }
+
+    /// Reads an escape sequence after the backslash. A backslash followed by a
+    /// newline continues the quoted text in the next line.
+    fn escape(&mut self) -> Result<Option<char>, String> {
+	let c = match self.next_char() {
+	    Some('n') => '\n',
+	    Some('t') => '\t',
+	    Some('r') => '\r',
+	    Some('a') => '\x07',
+	    Some('b') => '\x08',
+	    Some('f') => '\x0c',
+	    Some('v') => '\x0b',
+	    Some('e') => '\x1b',
+	    Some('s') => ' ',
+	    Some('0'..='7') => {
+		self.pos -= 1;
+		let digits = self.take_while(|c| c.is_digit(8));
+		let code = u32::from_str_radix(digits, 8).map_err(|_| "invalid escape")?;
+		if self.next_char() != Some('\\') {
+		    return Err("invalid escape".into());
+		}
+		char::from_u32(code).ok_or("invalid escape")?
+	    }
+	    Some('x') => {
+		let digits = self.take_while(|c| c.is_ascii_hexdigit());
+		let code = u32::from_str_radix(digits, 16).map_err(|_| "invalid escape")?;
+		if self.next_char() != Some('\\') {
+		    return Err("invalid escape".into());
+		}
+		char::from_u32(code).ok_or("invalid escape")?
+	    }
+	    Some('\n') => return Ok(None),
+	    Some(c @ ('\\' | '\'' | '"' | '`')) => c,
+	    _ => return Err("invalid escape".into()),
+	};
+	Ok(Some(c))
+    }
+}
+
+#[cfg(test)]
+fn tokens(input: &str) -> Result<Vec<TokenKind>, LexError> {
+    let mut lexer = Lexer::new(input);
+    let mut tokens = Vec::new();
+    while let Some(token) = lexer.next_token()? {
+	tokens.push(token.kind);
+    }
+    Ok(tokens)
+}
+
+#[test]
+fn skip_comments() {
+    let name = |name: &str| TokenKind::Name(name.into());
+    assert_eq!(tokens("a % comment\n  :- /* block\n comment */ b.% end"), Ok(vec![name("a"), name(":-"), name("b"), TokenKind::End]));
+    assert_eq!(tokens("'% not a comment' \"/* nor this */\""), Ok(vec![TokenKind::QuotedName("% not a comment".into()), TokenKind::Codes("/* nor this */".into())]));
+    assert_eq!(tokens("X = 1 /* unterminated"), Err((6, "unterminated block comment".into())));
+}
+
+#[test]
+fn quoted_newline() {
+    assert_eq!(tokens("'a\\\nb'"), Ok(vec![TokenKind::QuotedName("ab".into())]));
+    assert_eq!(tokens("'a\nb'"), Err((0, "unterminated quoted".into())));
+    let mut lexer = Lexer::new("\"open).\nb.");
+    assert!(lexer.next_token().is_err());
+    assert_eq!(lexer.next_token().unwrap().unwrap().kind, TokenKind::Name("open".into()));
+}
+
+#[test]
+fn layout_before() {
+    let mut lexer = Lexer::new("f(a) f (a)");
+    let layout: Vec<bool> = std::iter::from_fn(|| lexer.next_token().unwrap()).map(|token| token.layout_before).collect();
+    assert_eq!(layout, vec![false, false, false, false, true, true, false, false]);
+}
@@ -1,5 +1,14 @@
 pub mod database;
 pub mod unify;
 pub mod prover;
+pub mod atom;
 pub mod term;
 pub mod parser;
+mod lexer;
+pub mod error;
+pub mod flags;
+pub mod machine;
+pub mod arith;
+pub mod integer;
+pub mod ops;
+mod builtins;
@@ -1,4 +1,111 @@
-member(X, [X|Xs]).
-member(X, [Y|Xs]) :- member(X, Xs).
+% Library predicates available in every machine. A program that defines a
+% predicate with the same name and arity replaces the library version.
 
-list([a,b,c]).
+append([], Ys, Ys).
+append([X|Xs], Ys, [X|Zs]) :-
+    append(Xs, Ys, Zs).
+
+member(X, [X|_]).
+member(X, [_|Xs]) :-
+    member(X, Xs).
+
+memberchk(X, Xs) :-
+    member(X, Xs),
+    !.
+
+reverse(Xs, Ys) :-
+    reverse(Xs, [], Ys).
+
+reverse([], Ys, Ys).
+reverse([X|Xs], Acc, Ys) :-
+    reverse(Xs, [X|Acc], Ys).
+
+nth0(N, Xs, X) :-
+    nth(Xs, 0, N, X).
+
+nth1(N, Xs, X) :-
+    nth(Xs, 1, N, X).
+
+nth([X|_], N, N, X).
+nth([_|Xs], I, N, X) :-
+    J is I + 1,
+    nth(Xs, J, N, X).
+
+last([X], X) :-
+    !.
+last([_|Xs], X) :-
+    last(Xs, X).
+
+% Closures given from another module run in that module
+:- meta_predicate
+    maplist(1, ?),
+    maplist(2, ?, ?),
+    maplist(3, ?, ?, ?),
+    maplist(4, ?, ?, ?, ?),
+    foldl(3, ?, ?, ?),
+    foldl(4, ?, ?, ?, ?),
+    include(1, ?, ?),
+    exclude(1, ?, ?),
+    partition(1, ?, ?, ?).
+
+maplist(_, []).
+maplist(G, [X|Xs]) :-
+    call(G, X),
+    maplist(G, Xs).
+
+maplist(_, [], []).
+maplist(G, [X|Xs], [Y|Ys]) :-
+    call(G, X, Y),
+    maplist(G, Xs, Ys).
+
+maplist(_, [], [], []).
+maplist(G, [X|Xs], [Y|Ys], [Z|Zs]) :-
+    call(G, X, Y, Z),
+    maplist(G, Xs, Ys, Zs).
+
+maplist(_, [], [], [], []).
+maplist(G, [X|Xs], [Y|Ys], [Z|Zs], [W|Ws]) :-
+    call(G, X, Y, Z, W),
+    maplist(G, Xs, Ys, Zs, Ws).
+
+foldl(G, Xs, V0, V) :-
+    foldl_(Xs, G, V0, V).
+
+foldl_([], _, V, V).
+foldl_([X|Xs], G, V0, V) :-
+    call(G, X, V0, V1),
+    foldl_(Xs, G, V1, V).
+
+foldl(G, Xs, Ys, V0, V) :-
+    foldl_(Xs, Ys, G, V0, V).
+
+foldl_([], [], _, V, V).
+foldl_([X|Xs], [Y|Ys], G, V0, V) :-
+    call(G, X, Y, V0, V1),
+    foldl_(Xs, Ys, G, V1, V).
+
+include(_, [], []).
+include(P, [X|Xs], Included) :-
+    (   call(P, X)
+    ->  Included = [X|Ys]
+    ;   Included = Ys
+    ),
+    include(P, Xs, Ys).
+
+exclude(_, [], []).
+exclude(P, [X|Xs], Excluded) :-
+    (   call(P, X)
+    ->  Excluded = Ys
+    ;   Excluded = [X|Ys]
+    ),
+    exclude(P, Xs, Ys).
+
+partition(_, [], [], []).
+partition(P, [X|Xs], Included, Excluded) :-
+    (   call(P, X)
+    ->  Included = [X|Is],
+        Excluded = Es
+    ;   Included = Is,
+        Excluded = [X|Es]
+    ),
+    partition(P, Xs, Is, Es).
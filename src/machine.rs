@@ -0,0 +1,704 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::atom::Atom;
+use crate::database::{Database, Predicate};
+use crate::error::{PrologError, SyntaxError, existence_error, import_error, instantiation_error, type_error};
+use crate::flags::Flags;
+use crate::ops::Operators;
+use crate::parser::{self, Parser};
+use crate::prover::Solutions;
+use crate::term::Term;
+
+/// Predicates written in Prolog that every machine starts with
+const LIBRARY: &str = include_str!("lists.pl");
+
+/// Something the user should be told that the machine can't return: a
+/// warning, or an error found when nothing was there to take it
+#[derive(Debug, PartialEq)]
+pub enum Diagnostic {
+    Warning(String),
+    Error(PrologError),
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    Diagnostic::Warning(message) => write!(f, "Warning: {}", message),
+	    Diagnostic::Error(error) => write!(f, "{}", error),
+	}
+    }
+}
+
+pub struct Machine {
+    database: Database,
+    operators: Operators,
+    flags: Flags,
+    /// Library predicates not redefined by the user yet
+    library: HashSet<Predicate>,
+    /// The module consulted clauses go to and queries run in
+    context: Atom,
+    /// The files loaded, with the module each one defines
+    loaded: HashMap<PathBuf, Option<Atom>>,
+    /// The texts being loaded, the innermost last
+    loading: Vec<Load>,
+    /// The file each predicate is loaded from
+    sources: HashMap<Predicate, PathBuf>,
+    /// Where diagnostics are sent
+    diagnostics: Box<dyn FnMut(Diagnostic)>,
+    /// Where the text that predicates like `listing/1` print is sent
+    output: Box<dyn FnMut(&str)>,
+}
+
+/// The state of a text being loaded
+struct Load {
+    /// The file the terms are read from, to find relative paths
+    file: Option<PathBuf>,
+    /// The file consulted, which may include others
+    source: Option<PathBuf>,
+    /// The predicate of the last clause
+    last: Option<Predicate>,
+    /// The predicates with clauses in the text
+    seen: HashSet<Predicate>,
+    initialization: Vec<Term>,
+    errors: Vec<PrologError>,
+}
+
+impl Load {
+    fn new(file: Option<&Path>) -> Self {
+	let file = file.map(Path::to_path_buf);
+	Load { file: file.clone(), source: file, last: None, seen: HashSet::new(), initialization: Vec::new(), errors: Vec::new() }
+    }
+}
+
+/// The file of a `:- include(File)` directive
+fn included_file(term: &Term) -> Option<&Term> {
+    match term {
+	Term::Str(name, args) if name == ":-" && args.len() == 1 => match &args[0] {
+	    Term::Str(name, args) if name == "include" && args.len() == 1 => Some(&args[0]),
+	    _ => None,
+	},
+	_ => None,
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Self {
+	Self::from_database(Database::new())
+    }
+
+    pub fn from_database(database: Database) -> Self {
+	let defined: HashSet<Predicate> = database.predicates().cloned().collect();
+	let mut machine = Machine {
+	    database,
+	    operators: Operators::default(),
+	    flags: Flags::default(),
+	    library: HashSet::new(),
+	    context: "user".into(),
+	    loaded: HashMap::new(),
+	    loading: Vec::new(),
+	    sources: HashMap::new(),
+	    diagnostics: Box::new(|_| ()),
+	    output: Box::new(|_| ()),
+	};
+	let mut parser = Parser::new(LIBRARY);
+	while let Some(term) = parser.read_term(&machine.operators).expect("the library is valid Prolog") {
+	    match term {
+		Term::Str(name, args) if name == ":-" && args.len() == 1 => {
+		    machine.run_directive(args[0].clone()).expect("the library directives succeed");
+		}
+		term => {
+		    let clause = parser::term_to_clause(term).expect("the library is valid Prolog");
+		    if let Some(predicate) = Predicate::from_clause(&clause).filter(|predicate| !defined.contains(predicate)) {
+			machine.library.insert(predicate);
+			machine.database.add_clause(clause);
+		    }
+		}
+	    }
+	}
+	machine
+    }
+
+    pub fn database(&self) -> &Database {
+	&self.database
+    }
+
+    pub fn database_mut(&mut self) -> &mut Database {
+	&mut self.database
+    }
+
+    /// Whether a predicate comes from the library and hasn't been redefined
+    pub fn is_library(&self, predicate: &Predicate) -> bool {
+	self.library.contains(predicate)
+    }
+
+    /// The module consulted clauses go to and queries run in
+    pub fn context_module(&self) -> &Atom {
+	&self.context
+    }
+
+    pub(crate) fn set_context_module(&mut self, module: Atom) {
+	self.context = module;
+    }
+
+    /// The operators used to read queries and consulted texts
+    pub fn operators(&self) -> &Operators {
+	&self.operators
+    }
+
+    pub fn operators_mut(&mut self) -> &mut Operators {
+	&mut self.operators
+    }
+
+    pub fn flags(&self) -> &Flags {
+	&self.flags
+    }
+
+    pub fn flags_mut(&mut self) -> &mut Flags {
+	&mut self.flags
+    }
+
+    /// Sets where the warnings and the errors that can't be returned go.
+    /// They are dropped until this is called.
+    pub fn set_diagnostics(&mut self, sink: impl FnMut(Diagnostic) + 'static) {
+	self.diagnostics = Box::new(sink);
+    }
+
+    /// Sets where the text printed by `portray_clause/1` and `listing/1`
+    /// goes. It is dropped until this is called.
+    pub fn set_output(&mut self, sink: impl FnMut(&str) + 'static) {
+	self.output = Box::new(sink);
+    }
+
+    pub(crate) fn print(&mut self, text: &str) {
+	(self.output)(text);
+    }
+
+    pub(crate) fn warn(&mut self, message: String) {
+	(self.diagnostics)(Diagnostic::Warning(message));
+    }
+
+    /// Adds every clause of a Prolog source text to the database and runs
+    /// its `:- Goal` directives, in order, so `:- op(...)` affects the
+    /// clauses that follow it. A clause with errors is skipped and the rest
+    /// are still loaded; all the errors found are returned.
+    pub fn consult(&mut self, source: &str) -> Result<(), Vec<PrologError>> {
+	let (module, mut errors) = self.load_text(source, None);
+	if let Some(module) = module {
+	    let target = self.context.clone();
+	    if let Err(error) = self.import(&target, &module, None) {
+		errors.push(error);
+	    }
+	}
+	if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Consults a Prolog file, adding `.pl` to its name if needed. The
+    /// clauses loaded from it before are replaced. Syntax errors report the
+    /// file name.
+    pub fn consult_file(&mut self, path: &str) -> Result<(), Vec<PrologError>> {
+	match self.load_file(&Term::Atom(path.into()), true, None) {
+	    Ok(errors) if errors.is_empty() => Ok(()),
+	    Ok(errors) => Err(errors),
+	    Err(error) => Err(vec![error]),
+	}
+    }
+
+    /// Loads a file and imports the module it defines, if any, into the
+    /// context module: all its exports, or only those in `only`. A file
+    /// loaded already is only loaded again if `reload` is set. Returns the
+    /// errors found while loading, or the error that stopped it.
+    pub(crate) fn load_file(&mut self, file: &Term, reload: bool, only: Option<&[(Atom, usize)]>) -> Result<Vec<PrologError>, PrologError> {
+	let path = match self.resolve_file(file)? {
+	    Some(path) => path,
+	    None => return Ok(Vec::new()),
+	};
+	let key = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+	let (module, errors) = match self.loaded.get(&key) {
+	    Some(module) if !reload => (module.clone(), Vec::new()),
+	    _ => {
+		let source = fs::read_to_string(&path).map_err(|_| existence_error("source_sink", file.clone()))?;
+		self.forget_file(&key);
+		self.loaded.insert(key.clone(), None);
+		let (module, errors) = self.load_text(&source, Some(&path));
+		self.loaded.insert(key, module.clone());
+		(module, errors)
+	    }
+	};
+	if let Some(module) = module {
+	    let target = self.context.clone();
+	    if let Err(error) = self.import(&target, &module, only) {
+		self.report_errors(errors);
+		return Err(error);
+	    }
+	}
+	Ok(errors)
+    }
+
+    /// The path of a file to load: relative paths are taken from the
+    /// directory of the file being loaded, and `.pl` is added if the file
+    /// doesn't exist without it. `library(_)` files are built in, so there
+    /// is nothing to load.
+    fn resolve_file(&self, file: &Term) -> Result<Option<PathBuf>, PrologError> {
+	let name = match file {
+	    Term::Str(name, args) if *name == "library" && args.len() == 1 => return Ok(None),
+	    Term::Atom(name) => name.name(),
+	    Term::Var(_) => return Err(instantiation_error()),
+	    file => return Err(type_error("atom", file.clone())),
+	};
+	let mut path = PathBuf::from(&*name);
+	if path.is_relative() {
+	    let directory = self.loading.iter().rev().find_map(|load| load.file.as_ref()).and_then(|file| file.parent());
+	    if let Some(directory) = directory {
+		path = directory.join(path);
+	    }
+	}
+	if !path.exists() && path.extension().is_none() {
+	    path.set_extension("pl");
+	}
+	if path.is_file() {
+	    Ok(Some(path))
+	} else {
+	    Err(existence_error("source_sink", file.clone()))
+	}
+    }
+
+    /// Removes the predicates loaded from a file, before loading it again
+    fn forget_file(&mut self, file: &Path) {
+	let predicates: Vec<Predicate> = self.sources.iter()
+	    .filter(|(predicate, source)| source.as_path() == file && !self.database.is_multifile(predicate))
+	    .map(|(predicate, _)| predicate.clone())
+	    .collect();
+	for predicate in predicates {
+	    self.database.clear_predicate(&predicate);
+	    self.sources.remove(&predicate);
+	}
+    }
+
+    /// Loads a source text, returning the module it defines and the errors
+    /// found. Its `initialization/1` goals run once it is loaded, and then
+    /// the context module is restored.
+    fn load_text(&mut self, source: &str, file: Option<&Path>) -> (Option<Atom>, Vec<PrologError>) {
+	let context = self.context.clone();
+	self.loading.push(Load::new(file));
+	self.load_terms(source, file);
+	let goals = std::mem::take(&mut self.loading.last_mut().expect("a text is loading").initialization);
+	for goal in goals {
+	    if let Err(error) = self.run_directive(goal) {
+		self.report_errors(vec![error]);
+	    }
+	}
+	let load = self.loading.pop().expect("a text is loading");
+	let module = std::mem::replace(&mut self.context, context.clone());
+	(Some(module).filter(|module| *module != context), load.errors)
+    }
+
+    /// Adds the clauses and runs the directives of a text, which is the
+    /// file being loaded or one it includes
+    fn load_terms(&mut self, source: &str, file: Option<&Path>) {
+	let mut parser = Parser::new(source);
+	loop {
+	    let result = match parser.read_term(&self.operators) {
+		Ok(Some(term)) => match included_file(&term) {
+		    Some(included) => self.include(included),
+		    None => self.add_term(term, parser.line()),
+		},
+		Ok(None) => break,
+		Err(PrologError::SyntaxError(mut error)) => {
+		    error.file = file.map(|file| file.display().to_string());
+		    Err(PrologError::SyntaxError(error))
+		}
+		Err(error) => Err(error),
+	    };
+	    if let Err(error) = result {
+		self.report_errors(vec![error]);
+	    }
+	}
+    }
+
+    /// Loads the terms of a file as if they were in the file being loaded,
+    /// for `:- include(File)`
+    fn include(&mut self, file: &Term) -> Result<(), PrologError> {
+	let path = self.resolve_file(file)?.ok_or_else(|| type_error("atom", file.clone()))?;
+	let source = fs::read_to_string(&path).map_err(|_| existence_error("source_sink", file.clone()))?;
+	let load = self.loading.last_mut().expect("a text is loading");
+	let outer = load.file.replace(path.clone());
+	self.load_terms(&source, Some(&path));
+	self.loading.last_mut().expect("a text is loading").file = outer;
+	Ok(())
+    }
+
+    /// Records errors found while loading, to return them once the
+    /// outermost text is loaded. Errors found elsewhere go to the
+    /// diagnostics.
+    pub(crate) fn report_errors(&mut self, errors: Vec<PrologError>) {
+	match self.loading.last_mut() {
+	    Some(load) => load.errors.extend(errors),
+	    None => errors.into_iter().for_each(|error| (self.diagnostics)(Diagnostic::Error(error))),
+	}
+    }
+
+    /// Keeps an `initialization/1` goal to run when the text being loaded
+    /// is loaded. Returns false if no text is loading.
+    pub(crate) fn add_initialization(&mut self, goal: Term) -> bool {
+	match self.loading.last_mut() {
+	    Some(load) => {
+		load.initialization.push(goal);
+		true
+	    }
+	    None => false,
+	}
+    }
+
+    fn import(&mut self, target: &Atom, source: &Atom, only: Option<&[(Atom, usize)]>) -> Result<(), PrologError> {
+	if *target == "user" {
+	    // Imported predicates replace the library ones, like user clauses
+	    let exports = self.database.module(source).map(|module| module.exports.clone()).unwrap_or_default();
+	    for (name, arity) in exports {
+		let predicate = Predicate::new(name.clone(), arity);
+		if only.is_none_or(|only| only.contains(&(name, arity))) && self.library.remove(&predicate) {
+		    self.database.clear_predicate(&predicate);
+		}
+	    }
+	}
+	self.database.import(target, source, only)
+	    .map_err(|predicate| import_error(Term::Atom(target.clone()), predicate.indicator()))
+    }
+
+    /// Adds a clause read at a line of the file being loaded, or runs a
+    /// directive
+    fn add_term(&mut self, term: Term, line: usize) -> Result<(), PrologError> {
+	match term {
+	    Term::Str(name, args) if name == ":-" && args.len() == 1 => self.run_directive(args[0].clone()),
+	    term => {
+		let clause = parser::term_to_clause(term)?;
+		if let Some(predicate) = Predicate::from_clause(&clause) {
+		    let predicate = predicate.in_module(self.context.clone());
+		    for warning in self.check_clause_order(&predicate, line) {
+			self.warn(warning);
+		    }
+		    // The first user clause replaces the library definition
+		    if self.library.remove(&predicate) {
+			self.database.clear_predicate(&predicate);
+		    }
+		}
+		self.database.add_clause_to(&self.context, clause);
+		Ok(())
+	    }
+	}
+    }
+
+    /// Checks that the clauses of a predicate are together in the file
+    /// being loaded, unless it is `discontiguous`, and that they come from
+    /// one file, unless it is `multifile`. Returns the warnings to give for
+    /// a clause at `line`, with the file and line first.
+    fn check_clause_order(&mut self, predicate: &Predicate, line: usize) -> Vec<String> {
+	let Some(load) = self.loading.last_mut() else {
+	    return Vec::new();
+	};
+	let mut warnings = Vec::new();
+	if load.last.as_ref() != Some(predicate) {
+	    if !load.seen.insert(predicate.clone()) && !self.database.is_discontiguous(predicate) {
+		warnings.push(format!("clauses of {} are not together in the source file", predicate.indicator()));
+	    }
+	    load.last = Some(predicate.clone());
+	}
+	if let Some(source) = &load.source {
+	    match self.sources.get(predicate) {
+		Some(other) if other != source && !self.database.is_multifile(predicate) => {
+		    warnings.push(format!("{} is defined in {} and {}, declare it multifile", predicate.indicator(), other.display(), source.display()));
+		}
+		Some(_) => (),
+		None => {
+		    self.sources.insert(predicate.clone(), source.clone());
+		}
+	    }
+	}
+	let location = match &load.file {
+	    Some(file) => format!("{}:{}", file.display(), line),
+	    None => line.to_string(),
+	};
+	warnings.into_iter().map(|warning| format!("{}: {}", location, warning)).collect()
+    }
+
+    fn run_directive(&mut self, goal: Term) -> Result<(), PrologError> {
+	match self.query_goals(parser::conjunction_to_goals(goal.clone()), &[]).next() {
+	    Some(Ok(_)) => Ok(()),
+	    Some(Err(error)) => Err(error),
+	    None => {
+		self.warn(format!("directive failed: {}", goal));
+		Ok(())
+	    }
+	}
+    }
+
+    /// Runs a query like `member(X, [a,b]).`, computing its answers lazily
+    pub fn query(&mut self, query: &str) -> Solutions<'_> {
+	let query = query.trim();
+	let query = if query.ends_with('.') {
+	    query.to_string()
+	} else {
+	    format!("{}.", query)
+	};
+	let mut reader = Parser::new(&query);
+	match reader.read_term(&self.operators) {
+	    Ok(Some(term)) => {
+		let variable_names = reader.variable_names().to_vec();
+		self.query_goals(parser::conjunction_to_goals(term), &variable_names)
+	    }
+	    Ok(None) => {
+		let error = SyntaxError::new(&query, 0, "term expected, found end of input");
+		Solutions::error(self, PrologError::SyntaxError(error))
+	    }
+	    Err(error) => Solutions::error(self, error),
+	}
+    }
+
+    /// Runs the goals of a query, reporting the values of the variables in
+    /// `variable_names`
+    pub fn query_goals(&mut self, goals: Vec<Term>, variable_names: &[(String, usize)]) -> Solutions<'_> {
+	Solutions::new(self, goals, variable_names)
+    }
+}
+
+#[test]
+fn query_lazily() {
+    let mut machine = Machine::new();
+    machine.consult("human(socrates).\nhuman(plato).\nmortal(X) :- human(X).\n").unwrap();
+
+    let mut solutions = machine.query("mortal(X).");
+    let answer = solutions.next().unwrap().unwrap();
+    assert_eq!(answer.get("X"), Some(&Term::Atom("socrates".into())));
+    let answer = solutions.next().unwrap().unwrap();
+    assert_eq!(answer.to_string(), "X = plato");
+    assert!(solutions.next().is_none());
+    assert!(solutions.next().is_none());
+}
+
+#[test]
+fn know_the_last_answer() {
+    let mut machine = Machine::new();
+    machine.consult(":- dynamic(c/1).\nc(0).\np(1).\np(2).\n").unwrap();
+    for query in ["findall(X, p(X), L).", "retract(c(X)), assertz(c(1)), c(Y).", "catch(p(2), _, true).", "p(1) *-> true ; true."] {
+	let mut solutions = machine.query(query);
+	assert!(solutions.has_more());
+	assert!(solutions.next().unwrap().is_ok());
+	assert!(!solutions.has_more(), "{}", query);
+	assert!(solutions.next().is_none());
+    }
+    let mut solutions = machine.query("p(X).");
+    assert!(solutions.next().unwrap().is_ok());
+    assert!(solutions.has_more());
+    let mut solutions = machine.query("foo(");
+    assert!(solutions.has_more());
+    assert!(solutions.next().unwrap().is_err());
+    assert!(!solutions.has_more());
+}
+
+#[test]
+fn query_syntax_error() {
+    let mut machine = Machine::new();
+    let mut solutions = machine.query("mortal(X");
+    assert!(matches!(solutions.next(), Some(Err(PrologError::SyntaxError(_)))));
+    assert!(solutions.next().is_none());
+}
+
+#[test]
+fn user_defined_operators() {
+    let mut machine = Machine::new();
+    machine.consult(":- op(700, xfx, ===>).\n:- op(200, xf, [factorial]).\nrule(a ===> b).\nfact(5 factorial).\n").unwrap();
+    let answers: Vec<String> = machine.query("rule(X ===> Y), fact(N factorial).").map(|answer| answer.unwrap().to_string()).collect();
+    assert_eq!(answers, vec!["X = a, Y = b, N = 5"]);
+
+    let answers: Vec<String> = machine.query("current_op(P, T, ===>).").map(|answer| answer.unwrap().to_string()).collect();
+    assert_eq!(answers, vec!["P = 700, T = xfx"]);
+    let answers: Vec<String> = machine.query("current_op(P, T, -).").map(|answer| answer.unwrap().to_string()).collect();
+    assert_eq!(answers, vec!["P = 200, T = fy", "P = 500, T = yfx"]);
+
+    assert_eq!(machine.query("op(0, xfx, ===>), current_op(_, _, ===>).").next(), None);
+    assert!(matches!(machine.query("X = (a ===> b).").next(), Some(Err(PrologError::SyntaxError(_)))));
+}
+
+#[test]
+fn operator_errors() {
+    use crate::error::{domain_error, instantiation_error, permission_error, type_error};
+    let mut machine = Machine::new();
+    let mut first_error = |query: &str| machine.query(query).next().unwrap().unwrap_err();
+    assert_eq!(first_error("op(1201, xfx, foo)."), domain_error("operator_priority", Term::Int(1201.into())));
+    assert_eq!(first_error("op(700, yfy, foo)."), domain_error("operator_specifier", Term::Atom("yfy".into())));
+    assert_eq!(first_error("op(700, xfx, ',')."), permission_error("modify", "operator", Term::Atom(",".into())));
+    assert_eq!(first_error("op(700, xfx, [foo, 1])."), type_error("atom", Term::Int(1.into())));
+    assert_eq!(first_error("op(_, xfx, foo)."), instantiation_error());
+    assert_eq!(first_error("current_op(1201, _, _)."), domain_error("operator_priority", Term::Int(1201.into())));
+}
+
+#[test]
+fn consult_skips_bad_clauses() {
+    let mut machine = Machine::new();
+    let errors = machine.consult("human(socrates).\nhuman(plato]).\nhuman(aristotle).\n3.\n").unwrap_err();
+    assert_eq!(errors.len(), 2);
+    match &errors[0] {
+	PrologError::SyntaxError(error) => assert_eq!((error.line, error.column), (2, 12)),
+	error => panic!("unexpected {:?}", error),
+    }
+    assert_eq!(errors[1], type_error("callable", Term::Int(3.into())));
+    let answers: Vec<String> = machine.query("human(X).").map(|answer| answer.unwrap().to_string()).collect();
+    assert_eq!(answers, vec!["X = socrates", "X = aristotle"]);
+
+    // An unterminated quote ends at the end of its line
+    let errors = machine.consult("human('zeno).\nhuman(\"thales).\nhuman(epicurus).\nhuman(diogenes).\n").unwrap_err();
+    assert_eq!(errors.len(), 2);
+    match &errors[1] {
+	PrologError::SyntaxError(error) => assert_eq!((error.line, error.column, &*error.message), (2, 7, "unterminated quoted")),
+	error => panic!("unexpected {:?}", error),
+    }
+    let answers: Vec<String> = machine.query("human(X).").map(|answer| answer.unwrap().to_string()).collect();
+    assert_eq!(answers, vec!["X = socrates", "X = aristotle", "X = epicurus", "X = diogenes"]);
+
+    let errors = machine.consult_file("does/not/exist.pl").unwrap_err();
+    assert_eq!(errors, vec![crate::error::existence_error("source_sink", Term::Atom("does/not/exist.pl".into()))]);
+}
+
+#[test]
+fn library_predicates() {
+    let mut machine = Machine::new();
+    machine.consult("double(X, Y) :- Y is X * 2.\nadd(X, Y, Z) :- Z is X + Y.\nsmall(X) :- X < 3.\n").unwrap();
+    let mut answers = |query: &str| -> Vec<String> { machine.query(query).map(|answer| answer.unwrap().to_string()).collect() };
+
+    assert_eq!(answers("maplist(double, [1,2,3], Ys)."), vec!["Ys = [2,4,6]"]);
+    assert_eq!(answers("maplist(add, [1,2], [10,20], Zs)."), vec!["Zs = [11,22]"]);
+    assert_eq!(answers("foldl(add, [1,2,3], 0, Sum)."), vec!["Sum = 6"]);
+    assert_eq!(answers("include(small, [1,5,2,4], Xs), exclude(small, [1,5,2,4], Ys)."), vec!["Xs = [1,2], Ys = [5,4]"]);
+    assert_eq!(answers("partition(small, [1,5,2,4], Is, Es)."), vec!["Is = [1,2], Es = [5,4]"]);
+    assert_eq!(answers("append(Xs, [c], [a,b,c]), reverse(Xs, Ys)."), vec!["Xs = [a,b], Ys = [b,a]"]);
+    assert_eq!(answers("nth0(1, [a,b,c], X), nth1(1, [a,b,c], Y), last([a,b,c], Z)."), vec!["X = b, Y = a, Z = c"]);
+    assert_eq!(answers("memberchk(X, [a,b])."), vec!["X = a"]);
+}
+
+#[test]
+fn user_definitions_replace_library() {
+    let mut machine = Machine::new();
+    machine.consult("member(only, _).\n").unwrap();
+    let answers: Vec<String> = machine.query("member(X, [a,b]).").map(|answer| answer.unwrap().to_string()).collect();
+    assert_eq!(answers, vec!["X = only"]);
+}
+
+#[test]
+fn modules() {
+    use crate::error::{existence_error, import_error, indicator};
+    let directory = std::env::temp_dir().join(format!("modules_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let write = |name: &str, source: &str| {
+	let path = directory.join(name);
+	fs::write(&path, source).unwrap();
+	path.to_str().unwrap().trim_end_matches(".pl").to_string()
+    };
+    let a = write("a.pl", ":- module(a, [double/2, doubles/2]).\ndouble(X, Y) :- helper(X, Y).\nhelper(X, Y) :- Y is X * 2.\ndoubles(Xs, Ys) :- maplist(helper, Xs, Ys).\n");
+    let b = write("b.pl", ":- module(b, [twice/2, double/2]).\ntwice(X, Y) :- helper(X, Y).\nhelper(X, [X,X]).\ndouble(X, X).\n");
+    let mut machine = Machine::new();
+    machine.consult(&format!(":- use_module('{}').\n:- use_module('{}', [twice/2]).\n", a, b)).unwrap();
+    let mut answers = |query: &str| -> Vec<String> { machine.query(query).map(|answer| answer.unwrap().to_string()).collect() };
+
+    assert_eq!(answers("double(3, X), twice(3, Y)."), vec!["X = 6, Y = [3,3]"]);
+    assert_eq!(answers("a:helper(1, X), b:helper(1, Y)."), vec!["X = 2, Y = [1,1]"]);
+    assert_eq!(answers("doubles([1,2], Ys)."), vec!["Ys = [2,4]"]);
+    assert_eq!(answers("b:double(3, X)."), vec!["X = 3"]);
+    assert_eq!(answers("current_predicate(a:P)."), vec!["P = double/2", "P = doubles/2", "P = helper/2"]);
+    assert_eq!(answers("current_predicate(P)."), Vec::<String>::new());
+
+    let error = existence_error("procedure", Term::Str(":".into(), vec![Term::Atom("a".into()), indicator("nothing", 0)].into()));
+    assert_eq!(machine.query("a:nothing.").next(), Some(Err(error)));
+    let error = existence_error("procedure", indicator("helper", 2));
+    assert_eq!(machine.query("helper(1, _).").next(), Some(Err(error)));
+    let error = import_error(Term::Atom("user".into()), Term::Str(":".into(), vec![Term::Atom("b".into()), indicator("double", 2)].into()));
+    assert_eq!(machine.query(&format!("use_module('{}').", b)).next(), Some(Err(error)));
+
+    let errors = machine.consult(":- module(c, [double/2]).\ndouble(_, none).\n").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(machine.context_module(), &Atom::from("user"));
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn load_files() {
+    let directory = std::env::temp_dir().join(format!("load_files_{}", std::process::id()));
+    fs::create_dir_all(directory.join("parts")).unwrap();
+    let write = |name: &str, source: &str| fs::write(directory.join(name), source).unwrap();
+    write("main.pl", ":- initialization(assertz(started)).\n:- ensure_loaded(lib).\n:- include('parts/facts').\n:- discontiguous(color/1).\ncolor(red).\nsize(big).\ncolor(blue).\n:- multifile(shared/1).\nshared(main).\n");
+    write("lib.pl", "helper(1).\nshared(lib).\n");
+    write("parts/facts.pl", "fact(a).\n:- include(more).\n");
+    write("parts/more.pl", "fact(b).\n");
+    let main = directory.join("main").to_str().unwrap().to_string();
+    let lib = directory.join("lib").to_str().unwrap().to_string();
+
+    let mut machine = Machine::new();
+    machine.consult_file(&main).unwrap();
+    let mut answers = |query: &str| -> Vec<String> { machine.query(query).map(|answer| answer.unwrap().to_string()).collect() };
+    assert_eq!(answers("started, helper(X)."), vec!["X = 1"]);
+    assert_eq!(answers("fact(X)."), vec!["X = a", "X = b"]);
+    assert_eq!(answers("color(X)."), vec!["X = red", "X = blue"]);
+    assert_eq!(answers("shared(X)."), vec!["X = lib", "X = main"]);
+    assert_eq!(answers("predicate_property(color(_), discontiguous)."), vec!["true"]);
+    // Consulting a file again replaces its clauses
+    assert_eq!(answers(&format!("['{}'], consult('{}'), ensure_loaded('{}'), helper(X).", lib, lib, lib)), vec!["X = 1"]);
+    assert_eq!(answers("initialization(X = 1)."), vec!["X = 1"]);
+    let error = existence_error("source_sink", Term::Atom("missing".into()));
+    assert_eq!(machine.query("consult(missing).").next(), Some(Err(error)));
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn clause_order_warnings() {
+    let mut machine = Machine::new();
+    let (p, q) = (Predicate::new("p".into(), 0), Predicate::new("q".into(), 1));
+    machine.loading.push(Load::new(Some(Path::new("a.pl"))));
+    assert!(machine.check_clause_order(&p, 1).is_empty());
+    assert!(machine.check_clause_order(&p, 2).is_empty());
+    assert!(machine.check_clause_order(&q, 3).is_empty());
+    assert_eq!(machine.check_clause_order(&p, 4), vec!["a.pl:4: clauses of p/0 are not together in the source file"]);
+    machine.database.set_discontiguous(&q);
+    assert!(machine.check_clause_order(&q, 5).is_empty());
+    machine.loading.push(Load::new(Some(Path::new("b.pl"))));
+    assert_eq!(machine.check_clause_order(&p, 1), vec!["b.pl:1: p/0 is defined in a.pl and b.pl, declare it multifile"]);
+    assert!(machine.check_clause_order(&Predicate::new("r".into(), 0), 2).is_empty());
+    // Both warnings are given when both apply
+    assert_eq!(machine.check_clause_order(&p, 3), vec![
+	"b.pl:3: clauses of p/0 are not together in the source file",
+	"b.pl:3: p/0 is defined in a.pl and b.pl, declare it multifile",
+    ]);
+    machine.database.set_multifile(&p);
+    assert!(machine.check_clause_order(&p, 4).is_empty());
+}
+
+#[test]
+fn send_diagnostics() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    let mut machine = Machine::new();
+    let diagnostics = Rc::new(RefCell::new(Vec::new()));
+    let sink = diagnostics.clone();
+    machine.set_diagnostics(move |diagnostic| sink.borrow_mut().push(diagnostic.to_string()));
+    machine.consult("p(1).\nq.\np(2).\n:- fail.\n").unwrap();
+    assert_eq!(*diagnostics.borrow(), vec![
+	"Warning: 3: clauses of p/1 are not together in the source file",
+	"Warning: directive failed: fail",
+    ]);
+    diagnostics.borrow_mut().clear();
+    // Errors of a file consulted from a query can't be returned by it
+    let directory = std::env::temp_dir().join(format!("esgueva_diagnostics_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let file = directory.join("bad.pl");
+    fs::write(&file, "r(1).\nr(2.\n").unwrap();
+    assert!(machine.query(&format!("consult('{}').", file.display())).next().unwrap().is_ok());
+    assert_eq!(diagnostics.borrow().len(), 1);
+    assert!(diagnostics.borrow()[0].contains("bad.pl"));
+    fs::remove_dir_all(&directory).unwrap();
+}
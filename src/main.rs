@@ -1,65 +1,78 @@
 use std::env;
-use std::fs;
 use std::io;
 use std::io::Write;
-use std::collections::HashMap;
 
-use esgueva::database::Database;
-use esgueva::parser;
-use esgueva::prover;
-use esgueva::term::Term;
+use esgueva::machine::Machine;
 
 fn main() {
     println!("Esgueva Prolog 0.1.0 - Adrián Arroyo Calle 2022");
     let args: Vec<String> = env::args().collect();
 
-    match args.len() {
-	2 => {
-	    if args[1] == "-h" {
-		print_help();
-	    } else {
-		repl(file_to_database(&args[1]))
-	    }
-	}
-	1 => repl(Database::new()),
-	_ => print_help()
+    if args.iter().skip(1).any(|arg| arg == "-h") {
+	print_help();
+    } else {
+	repl(files_to_machine(&args[1..]))
     }
-
 }
 
 fn print_help() {
-    println!("Usage: esgueva [PROLOG FILE]\tStart Esgueva top-level optionally loading a file");
-    println!("       esgueva -h\t\tShow help");
+    println!("Usage: esgueva [PROLOG FILE]...\tStart Esgueva top-level optionally loading files");
+    println!("       esgueva -h\t\t\tShow help");
 }
 
-fn file_to_database(file: &str) -> Database {
-    let mut db = Database::new();
-    let contents = fs::read_to_string(file).expect("File must exist");
-
-    if let Ok((_, clauses)) = parser::file(&contents) {
-	for clause in clauses {
-	    db.add_clause(clause);
+fn files_to_machine(files: &[String]) -> Machine {
+    let mut machine = Machine::new();
+    machine.set_diagnostics(|diagnostic| eprintln!("{}", diagnostic));
+    machine.set_output(|text| print!("{}", text));
+    for file in files {
+	if let Err(errors) = machine.consult_file(file) {
+	    for error in errors {
+		eprintln!("{}", error);
+	    }
 	}
-    } else {
-	eprintln!("Error loading file: {}", file);
     }
-    
-    db
+
+    machine
 }
 
-fn repl(database: Database) {
+fn repl(mut machine: Machine) {
     loop {
 	print!("?- ");
 	io::stdout().flush().unwrap();
 	let mut input = String::new();
-	io::stdin().read_line(&mut input).unwrap();
-	if let Ok((_, mut goals)) = parser::clause_body(&input) {
-	    let vars_in_goals = prover::find_variables_in_goals(&goals);
-	    goals.push(Term::Atom("__backtracking?".into()));
-	    prover::prove_all(goals.into(), Some(HashMap::new()), &database, &vars_in_goals);
-	    println!("false.");
-	} else {
-	    eprintln!("Can't parse query!");
+	if io::stdin().read_line(&mut input).unwrap() == 0 {
+	    println!();
+	    return;
+	}
+	if input.trim().is_empty() {
+	    continue;
+	}
+	let mut solutions = machine.query(&input);
+	loop {
+	    match solutions.next() {
+		Some(Ok(answer)) => {
+		    print!("{}", answer);
+		    io::stdout().flush().unwrap();
+		    if !solutions.has_more() || !ask_more() {
+			println!(".");
+			break;
+		    }
+		}
+		Some(Err(error)) => {
+		    eprintln!("{}", error);
+		    break;
+		}
+		None => {
+		    println!("false.");
+		    break;
+		}
+	    }
 	}
     }
 }
+
+fn ask_more() -> bool {
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap();
+    input.trim_start().starts_with(';')
+}
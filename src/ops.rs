@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpType {
+    Xfx,
+    Xfy,
+    Yfx,
+    Fy,
+    Fx,
+    Xf,
+    Yf,
+}
+
+impl OpType {
+    pub fn from_name(name: &str) -> Option<OpType> {
+	match name {
+	    "xfx" => Some(OpType::Xfx),
+	    "xfy" => Some(OpType::Xfy),
+	    "yfx" => Some(OpType::Yfx),
+	    "fy" => Some(OpType::Fy),
+	    "fx" => Some(OpType::Fx),
+	    "xf" => Some(OpType::Xf),
+	    "yf" => Some(OpType::Yf),
+	    _ => None,
+	}
+    }
+
+    pub fn name(&self) -> &'static str {
+	match self {
+	    OpType::Xfx => "xfx",
+	    OpType::Xfy => "xfy",
+	    OpType::Yfx => "yfx",
+	    OpType::Fy => "fy",
+	    OpType::Fx => "fx",
+	    OpType::Xf => "xf",
+	    OpType::Yf => "yf",
+	}
+    }
+}
+
+/// An operator definition: its priority and the maximum priorities its
+/// arguments can have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Op {
+    pub priority: u32,
+    pub op_type: OpType,
+}
+
+impl Op {
+    /// Maximum priority of the left argument, for infix and postfix operators
+    pub fn left_max(&self) -> u32 {
+	match self.op_type {
+	    OpType::Yfx | OpType::Yf => self.priority,
+	    _ => self.priority - 1,
+	}
+    }
+
+    /// Maximum priority of the right argument, for infix and prefix operators
+    pub fn right_max(&self) -> u32 {
+	match self.op_type {
+	    OpType::Xfy | OpType::Fy => self.priority,
+	    _ => self.priority - 1,
+	}
+    }
+}
+
+/// The operator table used to read and write terms. It can be changed with
+/// `op/3`, so every `Machine` has its own copy.
+#[derive(Debug, Clone)]
+pub struct Operators {
+    prefix: HashMap<String, Op>,
+    infix: HashMap<String, Op>,
+    postfix: HashMap<String, Op>,
+}
+
+const DEFAULT_OPS: &[(u32, OpType, &[&str])] = &[
+    (1200, OpType::Xfx, &[":-", "-->"]),
+    (1200, OpType::Fx, &[":-", "?-"]),
+    (1150, OpType::Fx, &["dynamic", "discontiguous", "initialization", "meta_predicate", "module_transparent", "multifile", "public"]),
+    (1100, OpType::Xfy, &[";", "|"]),
+    (1050, OpType::Xfy, &["->", "*->"]),
+    (1000, OpType::Xfy, &[","]),
+    (900, OpType::Fy, &["\\+"]),
+    (700, OpType::Xfx, &["=", "\\=", "==", "\\==", "@<", "@>", "@=<", "@>=", "=..", "is", "=:=", "=\\=", "<", ">", "=<", ">="]),
+    (500, OpType::Yfx, &["+", "-", "/\\", "\\/", "xor"]),
+    (400, OpType::Yfx, &["*", "/", "//", "<<", ">>", "mod", "rem", "div"]),
+    (200, OpType::Xfx, &["**"]),
+    (200, OpType::Xfy, &["^", ":"]),
+    (200, OpType::Fy, &["-", "+", "\\"]),
+];
+
+impl Default for Operators {
+    fn default() -> Self {
+	let mut ops = Operators {
+	    prefix: HashMap::new(),
+	    infix: HashMap::new(),
+	    postfix: HashMap::new(),
+	};
+	for (priority, op_type, names) in DEFAULT_OPS {
+	    for name in names.iter() {
+		ops.add(*priority, *op_type, name);
+	    }
+	}
+	ops
+    }
+}
+
+impl Operators {
+    /// The operators every Prolog text starts with
+    pub fn default_table() -> &'static Operators {
+	static DEFAULT: OnceLock<Operators> = OnceLock::new();
+	DEFAULT.get_or_init(Operators::default)
+    }
+
+    /// Defines an operator, or removes it when the priority is 0
+    pub fn add(&mut self, priority: u32, op_type: OpType, name: &str) {
+	let table = match op_type {
+	    OpType::Fx | OpType::Fy => &mut self.prefix,
+	    OpType::Xfx | OpType::Xfy | OpType::Yfx => &mut self.infix,
+	    OpType::Xf | OpType::Yf => &mut self.postfix,
+	};
+	if priority == 0 {
+	    table.remove(name);
+	} else {
+	    table.insert(name.to_string(), Op { priority, op_type });
+	}
+    }
+
+    pub fn prefix(&self, name: &str) -> Option<Op> {
+	self.prefix.get(name).copied()
+    }
+
+    pub fn infix(&self, name: &str) -> Option<Op> {
+	self.infix.get(name).copied()
+    }
+
+    pub fn postfix(&self, name: &str) -> Option<Op> {
+	self.postfix.get(name).copied()
+    }
+
+    pub fn is_op(&self, name: &str) -> bool {
+	self.prefix.contains_key(name) || self.infix.contains_key(name) || self.postfix.contains_key(name)
+    }
+
+    /// Every operator definition as `(priority, type, name)`
+    pub fn iter(&self) -> impl Iterator<Item = (u32, OpType, &str)> {
+	self.prefix.iter()
+	    .chain(self.infix.iter())
+	    .chain(self.postfix.iter())
+	    .map(|(name, op)| (op.priority, op.op_type, name.as_str()))
+    }
+}
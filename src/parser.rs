@@ -1,163 +1,397 @@
-use std::collections::VecDeque;
-use nom::{
-    IResult,
-    Err,
-    error::Error,
-    error::ErrorKind,
-    branch::alt,
-    bytes::complete::tag,
-    bytes::complete::is_not,
-    character::complete::anychar,
-    character::complete::char,
-    character::complete::alphanumeric0,
-    character::complete::multispace1,
-    multi::many0,
-    multi::many1,
-    multi::separated_list0,
-    multi::separated_list1,
-    sequence::delimited,
-};
-
 use crate::term::Term;
+use crate::integer::Integer;
 use crate::database::Clause;
-
-pub fn file(input: &str) -> IResult<&str, Vec<Clause>> {
-    let (input, clauses) = separated_list0(multispace1, clause)(input)?;
-
-    Ok((input, clauses))
+use crate::error::{PrologError, SyntaxError, type_error, instantiation_error};
+use crate::ops::Operators;
+use crate::lexer::{Lexer, Token, TokenKind};
+#[cfg(test)]
+use crate::ops::OpType;
+
+/// A syntax error and the byte offset where it was found
+type ParseError = (usize, String);
+
+/// Reads Prolog terms written in standard syntax with the operators of an
+/// operator table. The table is passed on every read, because directives
+/// like `:- op(700, xfx, ===).` change it while a text is being read.
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<Option<Token>>,
+    /// Whether the last token read was the end of a clause
+    after_end: bool,
+    /// The named variables of the term being read and their numbers
+    variable_names: Vec<(String, usize)>,
+    variable_count: usize,
+    /// The byte offset where the last term read starts
+    term_start: usize,
 }
 
-fn clause(input: &str) -> IResult<&str, Clause> {
-    let (input, clause) = alt((clause_fact, clause_rule))(input)?;
-
-    Ok((input, clause))
-}
-
-fn clause_fact(input: &str) -> IResult<&str, Clause> {
-    let (input, head) = alt((term_str, term_atom))(input)?;
-    let (input, _) = char('.')(input)?;
+impl<'a> Parser<'a> {
+    pub fn new(input: &'a str) -> Self {
+	Parser {
+	    lexer: Lexer::new(input),
+	    peeked: None,
+	    after_end: false,
+	    variable_names: Vec::new(),
+	    variable_count: 0,
+	    term_start: 0,
+	}
+    }
 
-    Ok((input, Clause { head, body: vec![] }))
-}
+    /// The names of the variables of the last term read, with the numbers
+    /// they got. The variables of each term are numbered from 0 in the order
+    /// they appear.
+    pub fn variable_names(&self) -> &[(String, usize)] {
+	&self.variable_names
+    }
 
-fn clause_rule(input: &str) -> IResult<&str, Clause> {
-    let (input, head) = alt((term_str, term_atom))(input)?;
-    let (input, _) = many1(char(' '))(input)?;
-    let (input, _) = tag(":-")(input)?;
-    let (input, _) = many1(char(' '))(input)?;
-    let (input, body) = clause_body(input)?;
+    /// The line where the last term read starts, counting from 1
+    pub fn line(&self) -> usize {
+	self.lexer.input()[..self.term_start].matches('\n').count() + 1
+    }
 
-    Ok((input, Clause { head, body }))
-}
+    /// Reads the next term, which must end with a `.`. Returns `None` when
+    /// there are no more terms in the input. After a syntax error the rest
+    /// of the clause is skipped, so the next call reads the following one.
+    pub fn read_term(&mut self, ops: &Operators) -> Result<Option<Term>, PrologError> {
+	self.read_term_(ops).map_err(|(offset, message)| {
+	    self.skip_clause();
+	    PrologError::SyntaxError(SyntaxError::new(self.lexer.input(), offset, message))
+	})
+    }
 
-pub fn clause_body(input: &str) -> IResult<&str, Vec<Term>> {
-    let (input, goals) = separated_list1(spaced_comma, alt((term_str, term_atom)))(input)?;
-    let (input, _) = char('.')(input)?;
+    fn read_term_(&mut self, ops: &Operators) -> Result<Option<Term>, ParseError> {
+	self.variable_names.clear();
+	self.variable_count = 0;
+	self.after_end = false;
+	match self.peek()? {
+	    Some(token) => self.term_start = token.start,
+	    None => return Ok(None),
+	}
+	let (term, _) = self.parse(ops, 1200)?;
+	match self.next()? {
+	    Some(Token { kind: TokenKind::End, .. }) => Ok(Some(term)),
+	    Some(token) => Err((token.start, format!("operator expected, found {}", describe(&token.kind)))),
+	    None => Err(self.end_of_input("end of clause expected")),
+	}
+    }
 
-    Ok((input, goals))
-}
+    /// Skips tokens up to the end of the current clause
+    fn skip_clause(&mut self) {
+	if self.after_end {
+	    return;
+	}
+	if let Some(Some(Token { kind: TokenKind::End, .. }) | None) = self.peeked.take() {
+	    return;
+	}
+	loop {
+	    match self.lexer.next_token() {
+		Ok(Some(Token { kind: TokenKind::End, .. }) | None) => return,
+		Ok(Some(_)) => (),
+		Err(_) => self.lexer.skip_char(),
+	    }
+	}
+    }
 
-fn spaced_comma(input: &str) -> IResult<&str, ()> {
-    let (input, _) = many0(char(' '))(input)?;
-    let (input, _) = char(',')(input)?;
-    let (input, _) = many0(char(' '))(input)?;
+    fn end_of_input(&self, expected: &str) -> ParseError {
+	(self.lexer.input().len(), format!("{}, found end of input", expected))
+    }
 
-    Ok((input, ()))
-}
+    fn peek(&mut self) -> Result<Option<&Token>, ParseError> {
+	if self.peeked.is_none() {
+	    self.peeked = Some(self.lexer.next_token()?);
+	}
+	Ok(self.peeked.as_ref().unwrap().as_ref())
+    }
 
-fn term_str(input: &str) -> IResult<&str, Term> {
-    alt((term_str_default, term_str_quoted, term_str_list, term_str_head_tail))(input)
-}
+    fn next(&mut self) -> Result<Option<Token>, ParseError> {
+	let token = match self.peeked.take() {
+	    Some(token) => token,
+	    None => self.lexer.next_token()?,
+	};
+	self.after_end = matches!(token, Some(Token { kind: TokenKind::End, .. }));
+	Ok(token)
+    }
 
-fn term_str_default(input: &str) -> IResult<&str, Term> {
-    let (input, first) = anychar(input)?;
-    if !first.is_ascii_lowercase() {
-	return Err(Err::Error(Error::new(input, ErrorKind::Char)));
+    fn peek_kind(&mut self) -> Result<Option<TokenKind>, ParseError> {
+	Ok(self.peek()?.map(|token| token.kind.clone()))
     }
-    let (input, atom) = alphanumeric0(input)?;
-    
-    let (input, _) = char('(')(input)?;
 
-    let (input, args) = separated_list1(spaced_comma, alt((term_str, term_var, term_atom)))(input)?;
-    
-    let (input, _) = char(')')(input)?;
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+	match self.next()? {
+	    Some(Token { kind: TokenKind::Punct(p), .. }) if p == c => Ok(()),
+	    Some(token) => Err((token.start, format!("{} expected, found {}", c, describe(&token.kind)))),
+	    None => Err(self.end_of_input(&format!("{} expected", c))),
+	}
+    }
 
-    Ok((input, Term::Str(format!("{}{}", first, atom), args)))
-}
+    /// Parses a term of at most the given priority, returning the term and its priority
+    fn parse(&mut self, ops: &Operators, max: u32) -> Result<(Term, u32), ParseError> {
+	let (mut left, mut left_priority) = self.parse_primary(ops, max)?;
+	loop {
+	    let name = match self.peek_kind()? {
+		Some(TokenKind::Name(name)) => name,
+		Some(TokenKind::Punct(',')) => ",".to_string(),
+		Some(TokenKind::Punct('|')) => "|".to_string(),
+		_ => break,
+	    };
+	    if let Some(op) = ops.infix(&name) {
+		if op.priority <= max && left_priority <= op.left_max() {
+		    self.next()?;
+		    let (right, _) = self.parse(ops, op.right_max())?;
+		    let name = if name == "|" { ";".to_string() } else { name };
+		    left = Term::Str(name.into(), vec![left, right].into());
+		    left_priority = op.priority;
+		    continue;
+		}
+	    }
+	    if let Some(op) = ops.postfix(&name) {
+		if op.priority <= max && left_priority <= op.left_max() {
+		    self.next()?;
+		    left = Term::Str(name.into(), vec![left].into());
+		    left_priority = op.priority;
+		    continue;
+		}
+	    }
+	    break;
+	}
+	Ok((left, left_priority))
+    }
 
-fn term_str_quoted(input: &str) -> IResult<&str, Term> {
-    let (input, atom) = delimited(char('\''), is_not("'"), char('\''))(input)?;
-    let (input, _) = char('(')(input)?;
+    fn parse_primary(&mut self, ops: &Operators, max: u32) -> Result<(Term, u32), ParseError> {
+	let token = match self.next()? {
+	    Some(token) => token,
+	    None => return Err(self.end_of_input("term expected")),
+	};
+	let start = token.start;
+	match token.kind {
+	    TokenKind::Int(x) => Ok((Term::Int(x), 0)),
+	    TokenKind::Float(x) => Ok((Term::Float(x), 0)),
+	    TokenKind::Var(name) => Ok((self.variable(name), 0)),
+	    TokenKind::Codes(text) => {
+		let codes = text.chars().map(|c| Term::Int(Integer::from(c as i64))).collect();
+		Ok((build_list(codes, Term::Atom("[]".into())), 0))
+	    }
+	    TokenKind::Punct('(') => {
+		let (term, _) = self.parse(ops, 1200)?;
+		self.expect(')')?;
+		Ok((term, 0))
+	    }
+	    TokenKind::Punct('[') => {
+		if let Some(TokenKind::Punct(']')) = self.peek_kind()? {
+		    self.next()?;
+		    return self.parse_name("[]".into(), ops, max);
+		}
+		let list = self.parse_list(ops)?;
+		Ok((list, 0))
+	    }
+	    TokenKind::Punct('{') => {
+		if let Some(TokenKind::Punct('}')) = self.peek_kind()? {
+		    self.next()?;
+		    return self.parse_name("{}".into(), ops, max);
+		}
+		let (term, _) = self.parse(ops, 1200)?;
+		self.expect('}')?;
+		Ok((Term::Str("{}".into(), vec![term].into()), 0))
+	    }
+	    TokenKind::Name(name) => {
+		if name == "-" || name == "+" {
+		    if let Some(Token { kind: TokenKind::Int(_) | TokenKind::Float(_), layout_before: false, .. }) = self.peek()? {
+			return self.parse_signed_number(&name);
+		    }
+		}
+		self.parse_prefix(name, ops, max)
+	    }
+	    TokenKind::QuotedName(name) => self.parse_name(name, ops, max),
+	    kind => Err((start, format!("term expected, found {}", describe(&kind)))),
+	}
+    }
 
-    let (input, args) = separated_list1(spaced_comma, alt((term_str, term_var, term_atom)))(input)?;
-    
-    let (input, _) = char(')')(input)?;
+    fn parse_signed_number(&mut self, sign: &str) -> Result<(Term, u32), ParseError> {
+	let token = self.next()?.expect("a number was peeked");
+	let number = match token.kind {
+	    TokenKind::Int(x) if sign == "-" => Term::Int(x.checked_neg().ok_or((token.start, "integer too big".to_string()))?),
+	    TokenKind::Float(x) if sign == "-" => Term::Float(-x),
+	    TokenKind::Int(x) => Term::Int(x),
+	    TokenKind::Float(x) => Term::Float(x),
+	    _ => unreachable!("a number was peeked"),
+	};
+	Ok((number, 0))
+    }
 
-    Ok((input, Term::Str(atom.to_string(), args)))
-}
+    /// A name that may be a prefix operator applied to the following term
+    fn parse_prefix(&mut self, name: String, ops: &Operators, max: u32) -> Result<(Term, u32), ParseError> {
+	let op = match ops.prefix(&name) {
+	    Some(op) => op,
+	    None => return self.parse_name(name, ops, max),
+	};
+	let next = self.peek()?.cloned();
+	let is_operand = match next {
+	    None => true,
+	    Some(Token { kind: TokenKind::Punct('('), layout_before: false, .. }) => return self.parse_name(name, ops, max),
+	    Some(Token { kind: TokenKind::End | TokenKind::Punct(')' | ']' | '}' | ',' | '|'), .. }) => true,
+	    Some(Token { kind: TokenKind::Name(ref next), .. }) => {
+		(ops.infix(next).is_some() || ops.postfix(next).is_some()) && ops.prefix(next).is_none()
+	    }
+	    _ => false,
+	};
+	if is_operand {
+	    return Ok((Term::Atom(name.into()), 0));
+	}
+	let (priority, arg_max) = if op.priority > max { (999, 999) } else { (op.priority, op.right_max()) };
+	let (arg, _) = self.parse(ops, arg_max)?;
+	Ok((Term::Str(name.into(), vec![arg].into()), priority))
+    }
 
-fn term_str_list(input: &str) -> IResult<&str, Term> {
-    let (input, _) = char('[')(input)?;
-    let (input, elements) = separated_list1(spaced_comma, alt((term_str, term_var, term_atom)))(input)?;
-    let (input, _) = char(']')(input)?;
+    /// An atom, or a compound term if it is followed by an open parenthesis
+    fn parse_name(&mut self, name: String, ops: &Operators, _max: u32) -> Result<(Term, u32), ParseError> {
+	if let Some(Token { kind: TokenKind::Punct('('), layout_before: false, .. }) = self.peek()? {
+	    self.next()?;
+	    let mut args = vec![self.parse(ops, 999)?.0];
+	    while let Some(TokenKind::Punct(',')) = self.peek_kind()? {
+		self.next()?;
+		args.push(self.parse(ops, 999)?.0);
+	    }
+	    self.expect(')')?;
+	    return Ok((Term::Str(name.into(), args.into()), 0));
+	}
+	Ok((Term::Atom(name.into()), 0))
+    }
 
-    let list = build_list(elements.into());
+    /// The elements of a list after the opening bracket
+    fn parse_list(&mut self, ops: &Operators) -> Result<Term, ParseError> {
+	let mut elements = vec![self.parse(ops, 999)?.0];
+	loop {
+	    let token = match self.next()? {
+		Some(token) => token,
+		None => return Err(self.end_of_input(", | or ] expected")),
+	    };
+	    match token.kind {
+		TokenKind::Punct(',') => elements.push(self.parse(ops, 999)?.0),
+		TokenKind::Punct('|') => {
+		    let (tail, _) = self.parse(ops, 999)?;
+		    self.expect(']')?;
+		    return Ok(build_list(elements, tail));
+		}
+		TokenKind::Punct(']') => return Ok(build_list(elements, Term::Atom("[]".into()))),
+		kind => return Err((token.start, format!(", | or ] expected, found {}", describe(&kind)))),
+	    }
+	}
+    }
 
-    Ok((input, list))
+    /// Every `_` is a different variable
+    fn variable(&mut self, name: String) -> Term {
+	if let Some((_, id)) = self.variable_names.iter().find(|(var, _)| *var == name) {
+	    return Term::Var(*id);
+	}
+	let id = self.variable_count;
+	self.variable_count += 1;
+	if name != "_" {
+	    self.variable_names.push((name, id));
+	}
+	Term::Var(id)
+    }
 }
 
-fn build_list(mut elements: VecDeque<Term>) -> Term {
-    if let Some(element) = elements.pop_front() {
-	Term::Str(".".into(), vec![element, build_list(elements)])
-    } else {
-	Term::Atom("[]".into())
+fn describe(kind: &TokenKind) -> String {
+    match kind {
+	TokenKind::Name(name) | TokenKind::QuotedName(name) => format!("atom {}", name),
+	TokenKind::Var(name) => format!("variable {}", name),
+	TokenKind::Int(x) => format!("number {}", x),
+	TokenKind::Float(x) => format!("number {}", x),
+	TokenKind::Codes(text) => format!("string \"{}\"", text),
+	TokenKind::Punct(c) => c.to_string(),
+	TokenKind::End => "end of clause".into(),
     }
 }
 
-fn term_str_head_tail(input: &str) -> IResult<&str, Term> {
-    let (input, _) = char('[')(input)?;
-    let (input, head) = alt((term_str, term_var, term_atom))(input)?;
-    let (input, _) = char('|')(input)?;
-    let (input, tail) = alt((term_str, term_var, term_atom))(input)?;
-    let (input, _) = char(']')(input)?;
-
-    Ok((input, Term::Str(".".into(), vec![head, tail])))
+fn build_list(elements: Vec<Term>, tail: Term) -> Term {
+    elements.into_iter().rev().fold(tail, |list, element| Term::Str(".".into(), vec![element, list].into()))
 }
 
-fn term_var(input: &str) -> IResult<&str, Term> {
-    let (input, first) = anychar(input)?;
-    if !first.is_ascii_uppercase() {
-	return Err(Err::Error(Error::new(input, ErrorKind::Char)));
+/// Splits a conjunction `(A, B, C)` into its goals
+pub fn conjunction_to_goals(term: Term) -> Vec<Term> {
+    let mut goals = Vec::new();
+    let mut term = term;
+    loop {
+	match term {
+	    Term::Str(name, args) if name == "," && args.len() == 2 => {
+		let mut args = args.into_vec();
+		let right = args.pop().unwrap();
+		let left = args.pop().unwrap();
+		goals.extend(conjunction_to_goals(left));
+		term = right;
+	    }
+	    term => {
+		goals.push(term);
+		return goals;
+	    }
+	}
     }
-    let (input, var) = alphanumeric0(input)?;
-
-    Ok((input, Term::Var(format!("{}{}", first, var))))
 }
 
-fn term_atom(input: &str) -> IResult<&str, Term> {
-    alt((term_atom_default, term_atom_quoted, term_atom_nil))(input)
+/// Joins goals into a conjunction, `true` if there are none
+pub fn goals_to_conjunction(goals: Vec<Term>) -> Term {
+    goals.into_iter().rev().reduce(|right, left| Term::Str(",".into(), vec![left, right].into()))
+	.unwrap_or_else(|| Term::Atom("true".into()))
 }
 
-fn term_atom_default(input: &str) -> IResult<&str, Term> {
-    let (input, first) = anychar(input)?;
-    if !first.is_ascii_lowercase() {
-	return Err(Err::Error(Error::new(input, ErrorKind::Char)));
+/// Converts a term read from a Prolog text into a clause: `Head :- Body` or
+/// a fact. Variables in the body, also inside `;`, `->` and `*->`, become
+/// calls to them, so a cut they are bound to doesn't cut the clause.
+pub fn term_to_clause(term: Term) -> Result<Clause, PrologError> {
+    let (head, body) = match &term {
+	Term::Str(name, args) if name == ":-" && args.len() == 2 => (args[0].clone(), conjunction_to_goals(args[1].clone())),
+	head => (head.clone(), vec![]),
+    };
+    match head {
+	Term::Atom(_) | Term::Str(_, _) => (),
+	Term::Var(_) => return Err(instantiation_error()),
+	head => return Err(type_error("callable", head)),
     }
-    let (input, atom) = alphanumeric0(input)?;
-
-    Ok((input, Term::Atom(format!("{}{}", first, atom))))
+    let body = body.into_iter()
+	.map(|goal| body_goal(goal, &term))
+	.collect::<Result<_, _>>()?;
+    Ok(Clause { head, body })
 }
 
-fn term_atom_quoted(input: &str) -> IResult<&str, Term> {
-    let (input, atom) = delimited(char('\''), is_not("'"), char('\''))(input)?;
+fn body_goal(goal: Term, clause: &Term) -> Result<Term, PrologError> {
+    match goal {
+	Term::Var(_) => Ok(Term::Str("call".into(), vec![goal].into())),
+	Term::Int(_) | Term::Float(_) => Err(type_error("callable", clause.clone())),
+	Term::Str(name, args) if args.len() == 2 && matches!(&*name.name(), "," | ";" | "->" | "*->") => {
+	    let args = args.iter().map(|arg| body_goal(arg.clone(), clause)).collect::<Result<Vec<_>, _>>()?;
+	    Ok(Term::Str(name, args.into()))
+	}
+	goal => Ok(goal),
+    }
+}
 
-    Ok((input, Term::Atom(atom.to_string())))
+/// Reads every clause of a Prolog text with the default operators
+pub fn file(input: &str) -> Result<Vec<Clause>, PrologError> {
+    let mut parser = Parser::new(input);
+    let mut clauses = Vec::new();
+    while let Some(term) = parser.read_term(Operators::default_table())? {
+	clauses.push(term_to_clause(term)?);
+    }
+    Ok(clauses)
 }
 
-fn term_atom_nil(input: &str) -> IResult<&str, Term> {
-    let (input, _) = tag("[]")(input)?;
+/// Reads a single clause with the default operators
+pub fn clause(input: &str) -> Result<Clause, PrologError> {
+    let mut parser = Parser::new(input);
+    match parser.read_term(Operators::default_table())? {
+	Some(term) => term_to_clause(term),
+	None => Err(PrologError::SyntaxError(SyntaxError::new(input, input.len(), "term expected, found end of input"))),
+    }
+}
 
-    Ok((input, Term::Atom("[]".into())))
+/// Reads the goals of a query like `member(X, [1,2]), X > 1.`
+pub fn clause_body(input: &str) -> Result<Vec<Term>, PrologError> {
+    let mut parser = Parser::new(input);
+    match parser.read_term(Operators::default_table())? {
+	Some(term) => Ok(conjunction_to_goals(term)),
+	None => Err(PrologError::SyntaxError(SyntaxError::new(input, input.len(), "term expected, found end of input"))),
+    }
 }
 
 #[test]
@@ -165,10 +399,10 @@ fn parse1() {
     let input = "f(X,b,g(T)), g(X, a, Z).";
     let result = clause_body(input);
     let expected = vec![
-	Term::Str("f".into(), vec![Term::Var("X".into()), Term::Atom("b".into()), Term::Str("g".into(), vec![Term::Var("T".into())])]),
-	Term::Str("g".into(), vec![Term::Var("X".into()), Term::Atom("a".into()), Term::Var("Z".into())]),
+	Term::Str("f".into(), vec![Term::Var(0), Term::Atom("b".into()), Term::Str("g".into(), vec![Term::Var(1)].into())].into()),
+	Term::Str("g".into(), vec![Term::Var(0), Term::Atom("a".into()), Term::Var(2)].into()),
     ];
-    assert_eq!(result, Ok(("", expected)));
+    assert_eq!(result, Ok(expected));
 }
 
 #[test]
@@ -176,10 +410,10 @@ fn parse_fact() {
     let input = "f(adrian, valladolid).";
     let result = clause(input);
     let expected = Clause {
-	head: Term::Str("f".into(), vec![Term::Atom("adrian".into()), Term::Atom("valladolid".into())]),
+	head: Term::Str("f".into(), vec![Term::Atom("adrian".into()), Term::Atom("valladolid".into())].into()),
 	body: vec![],
     };
-    assert_eq!(result, Ok(("", expected)));
+    assert_eq!(result, Ok(expected));
 }
 
 #[test]
@@ -187,10 +421,10 @@ fn parse_fact_2() {
     let input = "list('.'(a,nil)).";
     let result = clause(input);
     let expected = Clause {
-	head: Term::Str("list".into(), vec![Term::Str(".".into(), vec![Term::Atom("a".into()), Term::Atom("nil".into())])]),
+	head: Term::Str("list".into(), vec![Term::Str(".".into(), vec![Term::Atom("a".into()), Term::Atom("nil".into())].into())].into()),
 	body: vec![],
     };
-    assert_eq!(result, Ok(("", expected)));
+    assert_eq!(result, Ok(expected));
 }
 
 #[test]
@@ -206,13 +440,13 @@ fn parse_fact_3() {
 			Term::Str(".".into(), vec![
 			    Term::Atom("c".into()),
 			    Term::Atom("[]".into())
-			])
-		    ]),
+			].into())
+		    ].into()),
 		    Term::Atom("[]".into())
-		])])]),
+		].into())].into())].into()),
 	body: vec![],
     };
-    assert_eq!(result, Ok(("", expected)));
+    assert_eq!(result, Ok(expected));
 }
 
 #[test]
@@ -220,10 +454,10 @@ fn parse_fact_4() {
     let input = "list([X|Xs]).";
     let result = clause(input);
     let expected = Clause {
-	head: Term::Str("list".into(), vec![Term::Str(".".into(), vec![Term::Var("X".into()), Term::Var("Xs".into())])]),
+	head: Term::Str("list".into(), vec![Term::Str(".".into(), vec![Term::Var(0), Term::Var(1)].into())].into()),
 	body: vec![],
     };
-    assert_eq!(result, Ok(("", expected)));
+    assert_eq!(result, Ok(expected));
 }
 
 #[test]
@@ -231,13 +465,13 @@ fn parse_rule() {
     let input = "likes(X, sandy) :- likes(X, cats), likes(X, kim).";
     let result = clause(input);
     let expected = Clause {
-	head: Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("sandy".into())]),
+	head: Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("sandy".into())].into()),
 	body: vec![
-	    Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("cats".into())]),
-	    Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("kim".into())]),
+	    Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("cats".into())].into()),
+	    Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("kim".into())].into()),
 	],
     };
-    assert_eq!(result, Ok(("", expected)));
+    assert_eq!(result, Ok(expected));
 }
 
 #[test]
@@ -246,38 +480,186 @@ fn parse_file() {
     let result = file(input);
     let expected = vec![
 	Clause {
-	    head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Atom("robin".into())]),
+	    head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Atom("robin".into())].into()),
 	    body: vec![],
 	},
 	Clause {
-	    head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("lee".into())]),
+	    head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("lee".into())].into()),
 	    body: vec![],
 	},
 	Clause {
-	    head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("kim".into())]),
+	    head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("kim".into())].into()),
 	    body: vec![],
 	},
 	Clause {
-	    head: Term::Str("likes".into(), vec![Term::Atom("robin".into()), Term::Atom("cats".into())]),
+	    head: Term::Str("likes".into(), vec![Term::Atom("robin".into()), Term::Atom("cats".into())].into()),
 	    body: vec![],
 	},
 	Clause {
-	    head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Var("X".into())]),
+	    head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Var(0)].into()),
 	    body: vec![
-		Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("cats".into())])
+		Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("cats".into())].into())
 	    ],
 	},
 	Clause {
-	    head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Var("X".into())]),
+	    head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Var(0)].into()),
 	    body: vec![
-		Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("lee".into())]),
-		Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("kim".into())])		 
+		Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("lee".into())].into()),
+		Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("kim".into())].into())		 
 	    ],
 	},
 	Clause {
-	    head: Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Var("X".into())]),
+	    head: Term::Str("likes".into(), vec![Term::Var(0), Term::Var(0)].into()),
 	    body: vec![],
 	}
     ];
-    assert_eq!(result, Ok(("\n", expected)));
+    assert_eq!(result, Ok(expected));
+}
+
+#[test]
+fn parse_cut() {
+    let input = "first(X) :- member(X, Xs), !.";
+    let result = clause(input);
+    let expected = Clause {
+	head: Term::Str("first".into(), vec![Term::Var(0)].into()),
+	body: vec![
+	    Term::Str("member".into(), vec![Term::Var(0), Term::Var(1)].into()),
+	    Term::Atom("!".into()),
+	],
+    };
+    assert_eq!(result, Ok(expected));
+}
+
+#[test]
+fn parse_numbers() {
+    let input = r"n(42, -7, 2.75, -2.5e3, 1.0E-2, 0xff, 0o17, 0b101, 0'a, 0'\n, 0''', '-'(1)).";
+    let result = clause(input);
+    let expected = Clause {
+	head: Term::Str("n".into(), vec![
+	    Term::Int(42.into()),
+	    Term::Int((-7).into()),
+	    Term::Float(2.75),
+	    Term::Float(-2500.0),
+	    Term::Float(0.01),
+	    Term::Int(255.into()),
+	    Term::Int(15.into()),
+	    Term::Int(5.into()),
+	    Term::Int(97.into()),
+	    Term::Int(10.into()),
+	    Term::Int(39.into()),
+	    Term::Str("-".into(), vec![Term::Int(1.into())].into()),
+	].into()),
+	body: vec![],
+    };
+    assert_eq!(result, Ok(expected));
+}
+
+#[test]
+fn parse_symbol_atoms() {
+    let input = "test :- is(X, +(1, *(2, 3))), =:=(X, 7), is_list([]).";
+    let result = clause(input);
+    let expected = Clause {
+	head: Term::Atom("test".into()),
+	body: vec![
+	    Term::Str("is".into(), vec![
+		Term::Var(0),
+		Term::Str("+".into(), vec![Term::Int(1.into()), Term::Str("*".into(), vec![Term::Int(2.into()), Term::Int(3.into())].into())].into()),
+	    ].into()),
+	    Term::Str("=:=".into(), vec![Term::Var(0), Term::Int(7.into())].into()),
+	    Term::Str("is_list".into(), vec![Term::Atom("[]".into())].into()),
+	],
+    };
+    assert_eq!(result, Ok(expected));
+}
+
+#[cfg(feature = "bigint")]
+#[test]
+fn parse_big_integers() {
+    let input = "n(123456789012345678901234567890, -9223372036854775808, 0xffffffffffffffffff).";
+    let clause = clause(input).unwrap();
+    assert_eq!(clause.head.to_string(), "n(123456789012345678901234567890,-9223372036854775808,4722366482869645213695)");
+}
+
+#[test]
+fn parse_operators() {
+    let parse = |input: &str| clause_body(input).unwrap().iter().map(|goal| format!("{:?}", goal)).collect::<Vec<_>>().join(" ");
+    let same = |x: &str, y: &str| assert_eq!(clause_body(x), clause_body(y), "{} should read as {}", x, y);
+
+    same("X = 1 + 2 * 3 - 4.", "=(X, -(+(1, *(2, 3)), 4)).");
+    same("X is 2 ^ 3 ^ 2 - 1 - 1.", "is(X, -(-(^(2, ^(3, 2)), 1), 1)).");
+    same("X = a:b:c.", "=(X, :(a, :(b, c))).");
+    same("a :- b, c ; d -> e.", ":-(a, ;(','(b, c), ->(d, e))).");
+    same("\\+ \\+ a.", "\\+(\\+(a)).");
+    same("X = - 1.", "=(X, -(1)).");
+    same("X = -1.", "=(X, -1).");
+    same("X = - (1).", "=(X, -(1)).");
+    same("X = -a.", "=(X, -(a)).");
+    same("X = 1 - -1.", "=(X, -(1, -1)).");
+    same("X = (a, b).", "=(X, ','(a, b)).");
+    same("X = [-].", "=(X, [-]).");
+    same("X = (a | b).", "=(X, ;(a, b)).");
+    same("X = f((a :- b), (c, d)).", "=(X, f(:-(a, b), ','(c, d))).");
+    same("X = {a, b}.", "=(X, {}(','(a, b))).");
+    same("X = \"ab\".", "=(X, [97, 98]).");
+    same("X = 'hello world'.", "=(X, 'hello world').");
+    same("X = 'it''s\\n'.", "=(X, 'it\\'s\\n').");
+    same("X == - .", "==(X, -).");
+    assert_eq!(parse("f(_, _)."), "Str(\"f\", [Var(0), Var(1)])");
+    assert!(clause_body("X = 1 = 2.").is_err());
+    assert!(clause_body("X is 2 ** 3 ** 2.").is_err());
+    assert!(clause_body("X = f (a).").is_err());
+    assert!(clause_body("- - - .").is_ok());
+}
+
+#[test]
+fn parse_with_custom_operators() {
+    let mut ops = Operators::default();
+    ops.add(700, OpType::Xfx, "===>");
+    ops.add(200, OpType::Xf, "factorial");
+    ops.add(0, OpType::Yfx, "+");
+    let mut parser = Parser::new("a ===> b factorial. +(1, 2). 1 + 2.");
+    let term = parser.read_term(&ops).unwrap().unwrap();
+    assert_eq!(term, Term::Str("===>".into(), vec![Term::Atom("a".into()), Term::Str("factorial".into(), vec![Term::Atom("b".into())].into())].into()));
+    let term = parser.read_term(&ops).unwrap().unwrap();
+    assert_eq!(term, Term::Str("+".into(), vec![Term::Int(1.into()), Term::Int(2.into())].into()));
+    assert!(parser.read_term(&ops).is_err());
+}
+
+#[test]
+fn parse_formatted_file() {
+    let input = r#"% Lists
+append([], Ys, Ys).   % base case
+append([X|Xs], Ys,
+       [X|Zs]) :-
+    /* the recursive
+       case */
+    append(Xs, Ys, Zs).
+"#;
+    let result = file(input).unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[1], clause("append([X|Xs], Ys, [X|Zs]) :- append(Xs, Ys, Zs).").unwrap());
+}
+
+#[test]
+fn syntax_error_position() {
+    let error = match clause("foo(a,\n    b c).") {
+	Err(PrologError::SyntaxError(error)) => error,
+	result => panic!("unexpected {:?}", result),
+    };
+    assert_eq!(error.message, ") expected, found atom c");
+    assert_eq!((error.line, error.column), (2, 7));
+    assert_eq!(error.excerpt, "    b c).");
+}
+
+#[test]
+fn recover_after_syntax_error() {
+    let mut parser = Parser::new("a. b(. c :- d e. f('x.y'). g(\"unterminated.\n");
+    let ops = Operators::default_table();
+    assert_eq!(parser.read_term(ops), Ok(Some(Term::Atom("a".into()))));
+    assert!(parser.read_term(ops).is_err());
+    assert!(parser.read_term(ops).is_err());
+    let term = parser.read_term(ops).unwrap().unwrap();
+    assert_eq!(term, Term::Str("f".into(), vec![Term::Atom("x.y".into())].into()));
+    assert!(parser.read_term(ops).is_err());
+    assert_eq!(parser.read_term(ops), Ok(None));
 }
@@ -1,205 +1,1086 @@
-use uuid::Uuid;
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::collections::VecDeque;
-
-use crate::term::Term;
-use crate::unify::{Bindings, unify};
-use crate::database::{Database, Predicate, Clause};
-
-fn prove(goal: Term, bindings: Bindings, database: &Database, other_goals: VecDeque<Term>, vars_in_goals: &HashSet<String>) -> Bindings {
-    if let Some(predicate) = Predicate::from_term(&goal) {
-	if &predicate.name == "__backtracking?" {
-	    let mut line = Vec::new();
-	    for var in vars_in_goals {
-		line.push(format!("{} = {}", var, subst_bindings(bindings.clone(), Term::Var(var.clone()))));
-	    }
-	    if line.len() == 0 {
-		println!("true");
-	    } else {
-                println!("{}", line.join(","));
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::iter::Peekable;
+use std::rc::Rc;
+
+use crate::atom::{Atom, Known};
+use crate::term::{Term, write_term_with_names};
+use crate::unify::{Bindings, Mark, unify, unify_or_cycle, walk};
+use crate::database::{Predicate, Clause, ClauseList};
+#[cfg(test)]
+use crate::database::Database;
+use crate::error::{PrologError, instantiation_error, type_error, existence_error, occurs_check_error, syntax_error};
+#[cfg(test)]
+use crate::error::indicator;
+use crate::flags::{Unknown, OccursCheck};
+use crate::builtins::find_builtin;
+use crate::integer::Integer;
+use crate::machine::Machine;
+use crate::ops::Operators;
+use crate::parser::Parser;
+
+/// A solution of a query: the value of every variable that appears in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    bindings: Vec<(String, Term)>,
+    /// The names of the query variables, to write the ones that appear in
+    /// the values
+    variable_names: Vec<(String, usize)>,
+}
+
+impl Answer {
+    pub fn get(&self, var: &str) -> Option<&Term> {
+	self.bindings.iter().find(|(name, _)| name == var).map(|(_, value)| value)
+    }
+
+    pub fn bindings(&self) -> &[(String, Term)] {
+	&self.bindings
+    }
+}
+
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	if self.bindings.is_empty() {
+	    write!(f, "true")
+	} else {
+	    let line: Vec<String> = self.bindings.iter()
+		.map(|(var, value)| format!("{} = {}", var, write_term_with_names(value, Operators::default_table(), 699, &self.variable_names)))
+		.collect();
+	    write!(f, "{}", line.join(", "))
+	}
+    }
+}
+
+/// A goal waiting to be proved. `cut_barrier` is the height of the
+/// choicepoint stack when the clause (or `call/1`) that introduced the goal
+/// was entered, so `!` knows how many choicepoints it has to discard.
+/// `module` is where the goal runs: the module of the clause it comes from.
+#[derive(Clone)]
+struct Goal {
+    term: Term,
+    cut_barrier: usize,
+    module: Atom,
+}
+
+/// What is left to do: prove a goal, or act on a marker the prover left
+/// among the goals. Markers aren't terms, so Prolog code can't make them.
+#[derive(Clone)]
+enum Pending {
+    Goal(Goal),
+    /// The condition of the `*->` whose else branch is the choicepoint at
+    /// this index succeeded
+    SoftCut(usize),
+    /// The goal of the `catch/3` at this choicepoint index exited. The
+    /// catch is active while this marker is among the pending goals.
+    ExitCatch(usize),
+}
+
+/// The goals still to prove, as a linked list. Choicepoints share it
+/// instead of copying it, and the body of a clause replaces the call on top
+/// of the goals that follow it, so a last call doesn't make it grow.
+#[derive(Clone, Default)]
+struct Goals(Option<Rc<GoalNode>>);
+
+struct GoalNode {
+    pending: Pending,
+    next: Goals,
+}
+
+impl Goals {
+    fn push(&mut self, goal: Goal) {
+	self.push_pending(Pending::Goal(goal));
+    }
+
+    fn push_pending(&mut self, pending: Pending) {
+	let next = Goals(self.0.take());
+	self.0 = Some(Rc::new(GoalNode { pending, next }));
+    }
+
+    fn pop(&mut self) -> Option<Pending> {
+	let node = self.0.take()?;
+	match Rc::try_unwrap(node) {
+	    Ok(mut node) => {
+		self.0 = node.next.0.take();
+		Some(node.pending)
 	    }
-	    if ask_confirm() {
-		None
-	    } else {
-		bindings
+	    Err(node) => {
+		self.0 = node.next.0.clone();
+		Some(node.pending.clone())
 	    }
-	} else {
-	    if let Some(clauses) = database.get_clauses(&predicate) {
-		for clause in clauses {
-		    let renamed_clause = rename_variables(&clause);
-		    let bindings = unify(goal.clone(), renamed_clause.head, bindings.clone(), false);
-		    if bindings.is_none() {
-			// do nothing
-		    } else {
-			let mut goals = VecDeque::from(renamed_clause.body.clone());
-			goals.append(&mut other_goals.clone());
-			let new_bindings = prove_all(goals, bindings, database, vars_in_goals);
-			if new_bindings.is_some() {
-			    return new_bindings;
-			}
-		    }
-		}
-		None
-	    } else {
-		None
+	}
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Pending> {
+	std::iter::successors(self.0.as_deref(), |node| node.next.0.as_deref()).map(|node| &node.pending)
+    }
+
+    /// The terms of the goals, up to the first node in `seen`, where the
+    /// goals shared with a list already visited begin
+    fn unseen_terms<'g>(&'g self, seen: &mut HashSet<*const GoalNode>, terms: &mut Vec<&'g Term>) {
+	let mut next = self.0.as_ref();
+	while let Some(node) = next {
+	    if !seen.insert(Rc::as_ptr(node)) {
+		break;
 	    }
+	    if let Pending::Goal(goal) = &node.pending {
+		terms.push(&goal.term);
+	    }
+	    next = node.next.0.as_ref();
 	}
-    } else {
-	None
     }
 }
 
-pub fn prove_all(mut goals: VecDeque<Term>, bindings: Bindings, database: &Database, vars_in_goals: &HashSet<String>) -> Bindings {
-    if let Some(goal) = goals.pop_front() {
-	prove(goal, bindings, database, goals, vars_in_goals)
-    } else {
-	bindings
+/// Drops the nodes that are not shared one by one, instead of recursing
+/// down the whole list
+impl Drop for Goals {
+    fn drop(&mut self) {
+	let mut next = self.0.take();
+	while let Some(node) = next {
+	    next = match Rc::try_unwrap(node) {
+		Ok(mut node) => node.next.0.take(),
+		Err(_) => None,
+	    };
+	}
     }
 }
 
-fn batch_prove(goal: Term, bindings: Bindings, database: &Database) -> Option<Vec<Bindings>> {
-    if let Some(predicate) = Predicate::from_term(&goal) {
-	if let Some(clauses) = database.get_clauses(&predicate) {
-	    let mut solutions = Vec::new();
-	    for clause in clauses {
-		let renamed_clause = rename_variables(&clause);
-		let bindings = unify(goal.clone(), renamed_clause.head, bindings.clone(), false);
-		if bindings.is_none() {
-		    // do nothing
-		} else if renamed_clause.body.len() == 0 {
-		    solutions.push(bindings);
-		} else {
-		    if let Some(mut all_solutions) = batch_prove_all(renamed_clause.body, vec![bindings], database) {
-			solutions.append(&mut all_solutions);
+/// What to try when backtracking into a choicepoint
+enum Alternative {
+    /// The clauses of a predicate that can match the goal, from `next` on,
+    /// as they were at `generation`
+    Clauses { goal: Goal, clauses: ClauseList, generation: u64, next: usize },
+    /// The clauses that `retract/1` can still remove, from `next` on
+    Retract { clause: Term, predicate: Predicate, clauses: ClauseList, next: usize },
+    /// The answers of a nondeterministic builtin: unify `target` with each candidate
+    Unify { target: Term, candidates: Vec<Term>, next: usize },
+    /// Unify `target` with the integers from `next` up to `high`, or
+    /// without end if there is no `high`
+    Range { target: Term, next: Integer, high: Option<Integer> },
+    /// Unify `target` with each candidate as it is generated. They have no
+    /// variables, so the garbage collector doesn't need to see them.
+    Generate { target: Term, candidates: Peekable<Box<dyn Iterator<Item = Term>>> },
+    /// Go on with the saved goals: the else branch of a disjunction, or
+    /// what follows `\+/1` when its goal fails
+    Resume,
+    /// A choicepoint that was disabled by a soft cut
+    Fail,
+    /// A `catch/3` call. Backtracking into it just fails, it is only used
+    /// when an exception is raised.
+    Catch { catcher: Term, recovery: Term, module: Atom },
+}
+
+impl Alternative {
+    /// The terms the alternative will use
+    fn terms(&self) -> Vec<&Term> {
+	match self {
+	    Alternative::Clauses { goal, .. } => vec![&goal.term],
+	    Alternative::Retract { clause, .. } => vec![clause],
+	    Alternative::Unify { target, candidates, .. } => std::iter::once(target).chain(candidates).collect(),
+	    Alternative::Range { target, .. } | Alternative::Generate { target, .. } => vec![target],
+	    Alternative::Catch { catcher, recovery, .. } => vec![catcher, recovery],
+	    Alternative::Resume | Alternative::Fail => Vec::new(),
+	}
+    }
+}
+
+struct ChoicePoint {
+    goals: Goals,
+    /// The mark to undo the bindings made after the choicepoint to
+    trail: Mark,
+    alternative: Alternative,
+}
+
+/// Iterator over the answers of a query. Every call to `next` resumes the
+/// search from the last choicepoint, so answers are only computed on demand.
+///
+/// The search is a loop over an explicit goal list and choicepoint stack,
+/// so deep recursion in Prolog doesn't use Rust stack.
+pub struct Solutions<'a> {
+    machine: &'a mut Machine,
+    variable_names: Vec<(String, usize)>,
+    goals: Goals,
+    bindings: Bindings,
+    choicepoints: Vec<ChoicePoint>,
+    error: Option<PrologError>,
+    started: bool,
+    /// The module of the goal a builtin is running for
+    context: Atom,
+    /// The `find_all` calls running. Garbage is only collected outside
+    /// them, because the builtins that call them hold terms.
+    nested: usize,
+}
+
+impl<'a> Solutions<'a> {
+    /// Starts solving the goals of a query. `variable_names` are the names
+    /// of its variables, as given by the parser.
+    pub fn new(machine: &'a mut Machine, goals: Vec<Term>, variable_names: &[(String, usize)]) -> Self {
+	let context = machine.context_module().clone();
+	let mut solutions = Solutions {
+	    machine,
+	    variable_names: variable_names.to_vec(),
+	    goals: Goals::default(),
+	    bindings: Bindings::new(),
+	    choicepoints: Vec::new(),
+	    error: None,
+	    started: false,
+	    context,
+	    nested: 0,
+	};
+	// The fresh variables come after the ones of the query
+	let count = find_variables_in_goals(&goals).into_iter().max().map_or(0, |var| var + 1);
+	solutions.bindings.fresh_variables(count);
+	for term in goals.into_iter().rev() {
+	    solutions.goals.push(Goal { term, cut_barrier: 0, module: solutions.context.clone() });
+	}
+	solutions
+    }
+
+    pub fn error(machine: &'a mut Machine, error: PrologError) -> Self {
+	let mut solutions = Self::new(machine, Vec::new(), &[]);
+	solutions.error = Some(error);
+	solutions
+    }
+
+    /// Whether there may be more answers: before the first one, or while
+    /// a choicepoint is left to resume. Once it is false, `next` returns
+    /// `None`, so a top level can end the last answer without asking.
+    pub fn has_more(&self) -> bool {
+	!self.started || self.error.is_some()
+	    || self.choicepoints.iter().any(|choicepoint| !matches!(choicepoint.alternative, Alternative::Fail | Alternative::Catch { .. }))
+    }
+
+    pub(crate) fn machine(&mut self) -> &mut Machine {
+	self.machine
+    }
+
+    /// Splits a term like `m:Goal` into its module and the term qualified.
+    /// Unqualified terms belong to the module of the running builtin.
+    pub(crate) fn strip_module(&self, term: Term) -> (Atom, Term) {
+	self.strip_module_in(self.context.clone(), term)
+    }
+
+    /// Like `strip_module`, with `module` for unqualified terms
+    pub(crate) fn strip_module_in(&self, mut module: Atom, term: Term) -> (Atom, Term) {
+	let mut term = self.deref(term);
+	while let Term::Str(name, args) = &term {
+	    match (name.known(), &args[..]) {
+		(Some(Known::Colon), [Term::Atom(qualifier), inner]) => {
+		    module = qualifier.clone();
+		    term = self.deref(inner.clone());
+		}
+		_ => break,
+	    }
+	}
+	(module, term)
+    }
+
+    fn solve(&mut self) -> Result<bool, PrologError> {
+	loop {
+	    if self.nested == 0 && self.bindings.needs_collection() {
+		self.collect_garbage();
+	    }
+	    let Some(pending) = self.goals.pop() else {
+		break;
+	    };
+	    let result = match pending {
+		Pending::Goal(goal) => self.step(goal),
+		Pending::SoftCut(index) => {
+		    // Its else branch is not tried anymore, but the condition
+		    // can still give more answers
+		    self.choicepoints[index].alternative = Alternative::Fail;
+		    Ok(true)
+		}
+		Pending::ExitCatch(index) => {
+		    // A goal that exits without choicepoints can't raise
+		    // anything more, so its catch is not needed anymore
+		    if index + 1 == self.choicepoints.len() {
+			self.cut(index);
 		    }
+		    Ok(true)
 		}
+	    };
+	    match result {
+		Ok(true) => (),
+		Ok(false) => {
+		    if !self.backtrack() {
+			return Ok(false);
+		    }
+		}
+		Err(PrologError::Exception(ball)) => {
+		    if !self.handle_exception(&ball) {
+			return Err(PrologError::Exception(ball));
+		    }
+		}
+		Err(error) => return Err(error),
 	    }
-	    if solutions.len() == 0 {
-		None
-	    } else {
-		Some(solutions)
+	}
+	Ok(true)
+    }
+
+    fn step(&mut self, goal: Goal) -> Result<bool, PrologError> {
+	let Goal { term, cut_barrier, module } = goal;
+	let term = self.deref(term);
+	let (name, args): (&Atom, &[Term]) = match &term {
+	    Term::Atom(name) => (name, &[]),
+	    Term::Str(name, args) => (name, args),
+	    Term::Var(_) => return Err(instantiation_error()),
+	    term => return Err(type_error("callable", term.clone())),
+	};
+	match (name.known(), args) {
+	    (Some(Known::Cut), []) => {
+		self.cut(cut_barrier);
+		Ok(true)
 	    }
-	} else {
-	    None
+	    (Some(Known::Comma), [left, right]) => {
+		self.goals.push(Goal { term: right.clone(), cut_barrier, module: module.clone() });
+		self.goals.push(Goal { term: left.clone(), cut_barrier, module: module.clone() });
+		Ok(true)
+	    }
+	    (Some(Known::Semicolon), [left, right]) => {
+		match left {
+		    Term::Str(name, cond) if *name == Known::IfThen && cond.len() == 2 => {
+			let barrier = self.choicepoints.len();
+			self.push_alternative(Goal { term: right.clone(), cut_barrier, module: module.clone() });
+			self.if_then(&cond[0], &cond[1], &module, cut_barrier, barrier);
+		    }
+		    Term::Str(name, cond) if *name == Known::SoftIfThen && cond.len() == 2 => {
+			let else_choicepoint = self.choicepoints.len();
+			self.push_alternative(Goal { term: right.clone(), cut_barrier, module: module.clone() });
+			self.goals.push(Goal { term: cond[1].clone(), cut_barrier, module: module.clone() });
+			self.goals.push_pending(Pending::SoftCut(else_choicepoint));
+			self.goals.push(Goal { term: cond[0].clone(), cut_barrier: self.choicepoints.len(), module: module.clone() });
+		    }
+		    _ => {
+			self.push_alternative(Goal { term: right.clone(), cut_barrier, module: module.clone() });
+			self.goals.push(Goal { term: left.clone(), cut_barrier, module: module.clone() });
+		    }
+		}
+		Ok(true)
+	    }
+	    (Some(Known::IfThen), [cond, then]) => {
+		let barrier = self.choicepoints.len();
+		self.if_then(cond, then, &module, cut_barrier, barrier);
+		Ok(true)
+	    }
+	    (Some(Known::SoftIfThen), [cond, then]) => {
+		self.goals.push(Goal { term: then.clone(), cut_barrier, module: module.clone() });
+		self.goals.push(Goal { term: cond.clone(), cut_barrier: self.choicepoints.len(), module: module.clone() });
+		Ok(true)
+	    }
+	    (Some(Known::Catch), [goal, catcher, recovery]) => {
+		let index = self.choicepoints.len();
+		self.choicepoints.push(ChoicePoint {
+		    goals: self.goals.clone(),
+		    trail: self.bindings.mark(),
+		    alternative: Alternative::Catch { catcher: catcher.clone(), recovery: recovery.clone(), module: module.clone() },
+		});
+		self.goals.push_pending(Pending::ExitCatch(index));
+		self.goals.push(Goal { term: goal.clone(), cut_barrier: index + 1, module: module.clone() });
+		Ok(true)
+	    }
+	    (Some(Known::Throw), [ball]) => {
+		match self.substitute(ball) {
+		    Term::Var(_) => Err(instantiation_error()),
+		    ball => Err(PrologError::Exception(ball)),
+		}
+	    }
+	    (Some(Known::Call), [closure, extra @ ..]) => {
+		let goal = self.add_args(closure.clone(), extra)?;
+		let cut_barrier = self.choicepoints.len();
+		self.goals.push(Goal { term: goal, cut_barrier, module: module.clone() });
+		Ok(true)
+	    }
+	    (Some(Known::NotProvable), [goal]) => {
+		// If the goal succeeds, the cut removes the choicepoint that
+		// would resume here and we fail. Otherwise we backtrack into it
+		// and go on with the rest of the goals.
+		let cut_barrier = self.choicepoints.len();
+		self.choicepoints.push(ChoicePoint {
+		    goals: self.goals.clone(),
+		    trail: self.bindings.mark(),
+		    alternative: Alternative::Resume,
+		});
+		self.goals.push(Goal { term: Term::Atom(Known::Fail.into()), cut_barrier, module: module.clone() });
+		self.goals.push(Goal { term: Term::Atom(Known::Cut.into()), cut_barrier, module: module.clone() });
+		self.goals.push(Goal { term: Term::Str(Known::Call.into(), vec![goal.clone()].into()), cut_barrier, module: module.clone() });
+		Ok(true)
+	    }
+	    (Some(Known::Colon), [qualifier, goal]) => {
+		let module = match self.deref(qualifier.clone()) {
+		    Term::Atom(module) => module,
+		    Term::Var(_) => return Err(instantiation_error()),
+		    qualifier => return Err(type_error("module", qualifier)),
+		};
+		self.goals.push(Goal { term: goal.clone(), cut_barrier, module });
+		Ok(true)
+	    }
+	    (_, args) => match find_builtin(name, args.len()) {
+		Some(builtin) => {
+		    self.context = module;
+		    builtin(self, args)
+		}
+		None => self.resolve_predicate(Goal { term: term.clone(), cut_barrier, module }),
+	    },
+	}
+    }
+    /// Removes the choicepoints from `height` on, keeping the bindings made
+    /// since them
+    fn cut(&mut self, height: usize) {
+	if let Some(choicepoint) = self.choicepoints.get(height) {
+	    self.bindings.release(choicepoint.trail);
+	    self.choicepoints.truncate(height);
 	}
-    } else {
-	None
     }
-}
 
-fn batch_prove_all(mut goals: Vec<Term>, bindings: Vec<Bindings>, database: &Database) -> Option<Vec<Bindings>> {
-    if let Some(goal) = goals.pop() {
-	let mut solutions = Vec::new();
-	for binding in bindings {
-	    if let Some(mut goal_solutions) = batch_prove(goal.clone(), binding, database) {
-		solutions.append(&mut goal_solutions);
+    /// Drops the bindings of the variables that can't be reached anymore:
+    /// from the query, the pending goals or the choicepoints
+    fn collect_garbage(&mut self) {
+	let mut seen_goals = HashSet::new();
+	let mut pending = Vec::new();
+	self.goals.unseen_terms(&mut seen_goals, &mut pending);
+	for choicepoint in &self.choicepoints {
+	    choicepoint.goals.unseen_terms(&mut seen_goals, &mut pending);
+	    pending.extend(choicepoint.alternative.terms());
+	}
+	pending.extend(self.bindings.overwritten_values());
+	let bindings = &self.bindings;
+	let mut live = HashSet::new();
+	let mut seen_args = HashSet::new();
+	let mut variables: Vec<usize> = self.variable_names.iter().map(|(_, var)| *var).collect();
+	loop {
+	    for var in variables.drain(..) {
+		if live.insert(var) {
+		    pending.extend(bindings.get(var));
+		}
+	    }
+	    match pending.pop() {
+		Some(Term::Var(var)) => variables.push(*var),
+		Some(Term::Str(_, args)) if seen_args.insert(args.as_ptr()) => pending.extend(args.iter()),
+		Some(_) => (),
+		None => break,
 	    }
 	}
-	if solutions.len() == 0 {
-	    None
-	} else {
-	    batch_prove_all(goals, solutions, database)
+	self.bindings.retain(&live);
+    }
+
+    /// Unwinds to the innermost active `catch/3` whose catcher unifies with
+    /// the ball and continues with its recovery goal. Returns false if
+    /// nothing catches it.
+    fn handle_exception(&mut self, ball: &Term) -> bool {
+	// The ball is copied, so its variables are not affected by the
+	// bindings undone while unwinding
+	let ball = rename_term(ball, &mut HashMap::new(), &mut self.bindings);
+	while let Some(index) = self.active_catch() {
+	    self.choicepoints.truncate(index + 1);
+	    let choicepoint = self.choicepoints.pop().expect("active catch");
+	    self.goals = choicepoint.goals;
+	    self.bindings.undo(choicepoint.trail);
+	    if let Alternative::Catch { catcher, recovery, module } = choicepoint.alternative {
+		if self.unify(catcher, ball.clone()) {
+		    self.goals.push(Goal { term: Term::Str("call".into(), vec![recovery].into()), cut_barrier: index, module });
+		    return true;
+		}
+	    }
 	}
-    } else {
-	Some(bindings)
+	false
     }
-}
 
-fn rename_variables(clause: &Clause) -> Clause {
-    let mut bindings = HashMap::new();
-    Clause {
-	head: rename_term(&clause.head, &mut bindings),
-	body: clause.body.iter().map(|term| rename_term(term, &mut bindings)).collect(),
+    /// The choicepoint of the innermost active `catch/3`: the one whose
+    /// marker comes first among the pending goals
+    fn active_catch(&self) -> Option<usize> {
+	self.goals.iter().find_map(|pending| match pending {
+	    Pending::ExitCatch(index) => Some(*index),
+	    _ => None,
+	})
     }
-}
 
-fn rename_term(term: &Term, bindings: &mut HashMap<String, String>) -> Term {
-    match term {
-	Term::Atom(f) => Term::Atom(f.clone()),
-	Term::Var(var) => {
-	    if let Some(subst) = bindings.get(var) {
-		Term::Var(subst.clone())
-	    } else {
-		let id = Uuid::now_v1(&[1, 2, 3, 4, 5, 6]).to_string();
-		bindings.insert(var.clone(), id.clone());
-		Term::Var(id)
+    /// Adds extra arguments to a closure, as `call/N` does: `call(plus(1), 2, X)`
+    /// calls `plus(1, 2, X)`, and `call(m:p, X)` calls `m:p(X)`
+    fn add_args(&self, closure: Term, extra: &[Term]) -> Result<Term, PrologError> {
+	match self.deref(closure) {
+	    Term::Var(_) => Err(instantiation_error()),
+	    closure if extra.is_empty() => Ok(closure),
+	    Term::Str(name, args) if name == Known::Colon && args.len() == 2 => {
+		let goal = self.add_args(args[1].clone(), extra)?;
+		Ok(Term::Str(name, vec![args[0].clone(), goal].into()))
+	    }
+	    Term::Atom(name) => Ok(Term::Str(name, extra.to_vec().into())),
+	    Term::Str(name, args) => {
+		let mut args = args.into_vec();
+		args.extend_from_slice(extra);
+		Ok(Term::Str(name, args.into()))
 	    }
-	},
-	Term::Str(f, args) => {
-	    Term::Str(f.clone(), args.iter().map(|arg| rename_term(arg, bindings)).collect())
+	    closure => Err(type_error("callable", closure)),
 	}
     }
-}
 
-fn top_level_prove(goals: Vec<Term>, database: &Database) -> String {
-    let vars_in_goals = find_variables_in_goals(&goals);
-    let solutions = batch_prove_all(goals, vec![Some(HashMap::new())], database);
+    /// Leaves a choicepoint that goes on with `goal` instead of the current
+    /// one, for the else branch of a disjunction
+    fn push_alternative(&mut self, goal: Goal) {
+	let mut goals = self.goals.clone();
+	goals.push(goal);
+	self.choicepoints.push(ChoicePoint {
+	    goals,
+	    trail: self.bindings.mark(),
+	    alternative: Alternative::Resume,
+	});
+    }
 
-    if let Some(solutions) = solutions {
-	let mut output = Vec::new();
-	for solution in solutions {
-	    let mut line = Vec::new();
-	    for var in &vars_in_goals {
-		line.push(format!("{} = {}", var, subst_bindings(solution.clone(), Term::Var(var.clone()))));
+    /// Pushes the goals of `Cond -> Then`. The condition is opaque to cut,
+    /// and once it succeeds a cut to `barrier` discards its choicepoints and
+    /// the else branch.
+    fn if_then(&mut self, cond: &Term, then: &Term, module: &Atom, cut_barrier: usize, barrier: usize) {
+	self.goals.push(Goal { term: then.clone(), cut_barrier, module: module.clone() });
+	self.goals.push(Goal { term: Term::Atom("!".into()), cut_barrier: barrier, module: module.clone() });
+	self.goals.push(Goal { term: cond.clone(), cut_barrier: self.choicepoints.len(), module: module.clone() });
+    }
+
+    /// Pushes a goal to prove before the current ones. Builtins use it to
+    /// go on with other goals, which are opaque to cut.
+    pub(crate) fn push_goal(&mut self, term: Term) {
+	let cut_barrier = self.choicepoints.len();
+	self.goals.push(Goal { term, cut_barrier, module: self.context.clone() });
+    }
+
+    fn resolve_predicate(&mut self, goal: Goal) -> Result<bool, PrologError> {
+	let (name, arity) = match &goal.term {
+	    Term::Atom(name) => (name, 0),
+	    Term::Str(name, args) => (name, args.len()),
+	    _ => unreachable!("goal is callable"),
+	};
+	let predicate = match self.machine.database().resolve(&goal.module, name, arity) {
+	    Some(predicate) => predicate,
+	    None => {
+		let predicate = Predicate::new(name.clone(), arity).in_module(goal.module);
+		return match self.machine.flags().unknown {
+		    Unknown::Error => Err(existence_error("procedure", predicate.indicator())),
+		    Unknown::Warning => {
+			self.machine.warn(format!("unknown procedure {}", predicate.indicator()));
+			Ok(false)
+		    }
+		    Unknown::Fail => Ok(false),
+		};
 	    }
-	    output.push(line.join(","));
+	};
+	let term = self.qualify_meta_arguments(&predicate, goal.term, &goal.module);
+	let clauses = self.machine.database_mut().matching_clauses(&predicate, &term, &self.bindings).expect("the predicate exists");
+	let generation = self.machine.database().generation();
+	let goal = Goal { term, cut_barrier: goal.cut_barrier, module: predicate.module };
+	Ok(self.resolve(goal, clauses, generation, 0))
+    }
+
+    /// Qualifies the goals and closures passed to a meta-predicate of
+    /// another module with the module of the caller, so they are called
+    /// there
+    fn qualify_meta_arguments(&self, predicate: &Predicate, term: Term, caller: &Atom) -> Term {
+	let meta_arguments = self.machine.database().meta_arguments(predicate);
+	if predicate.module == *caller || meta_arguments.is_empty() {
+	    return term;
 	}
-	output.join(";\n")
-    } else {
-	format!("false.")
+	let args = match &term {
+	    Term::Str(_, args) => args,
+	    _ => return term,
+	};
+	let args: Vec<Term> = args.iter().zip(meta_arguments)
+	    .map(|(arg, &meta)| match self.deref(arg.clone()) {
+		Term::Str(name, args) if meta && name == Known::Colon && args.len() == 2 => Term::Str(name, args),
+		arg if meta => Term::Str(Known::Colon.into(), vec![Term::Atom(caller.clone()), arg].into()),
+		_ => arg.clone(),
+	    })
+	    .collect();
+	Term::Str(predicate.name.clone(), args.into())
     }
-}
 
-fn top_level_prove_backtracking(mut goals: Vec<Term>, database: &Database) {
-    let vars_in_goals = find_variables_in_goals(&goals);
-    goals.push(Term::Atom("__backtracking?".into()));
-    let solution = prove_all(goals.into(), Some(HashMap::new()), database, &vars_in_goals);
+    fn backtrack(&mut self) -> bool {
+	while let Some(choicepoint) = self.choicepoints.pop() {
+	    self.goals = choicepoint.goals;
+	    self.bindings.undo(choicepoint.trail);
+	    let resumed = match choicepoint.alternative {
+		Alternative::Clauses { goal, clauses, generation, next } => self.resolve(goal, clauses, generation, next),
+		Alternative::Retract { clause, predicate, clauses, next } => self.retract_candidates(clause, predicate, clauses, next),
+		Alternative::Unify { target, candidates, next } => self.unify_candidates(target, candidates, next),
+		Alternative::Range { target, next, high } => self.unify_range(target, next, high),
+		Alternative::Generate { target, candidates } => self.unify_generated(target, candidates),
+		Alternative::Resume => true,
+		Alternative::Fail | Alternative::Catch { .. } => false,
+	    };
+	    if resumed {
+		return true;
+	    }
+	}
+	false
+    }
 
-    if solution.is_some() {
-	println!("true.")
-    } else {
-	println!("false.")
+    /// Tries the clauses in `candidates` from `start` on, leaving a
+    /// choicepoint only if there are more to try after the one that matches
+    fn resolve(&mut self, goal: Goal, candidates: ClauseList, generation: u64, start: usize) -> bool {
+	let trail = self.bindings.mark();
+	let occurs_check = self.machine.flags().occurs_check;
+	for (i, clause) in candidates.iter().enumerate().skip(start) {
+	    if !clause.visible(generation) {
+		continue;
+	    }
+	    let renamed_clause = rename_clause(&clause.clause, &mut self.bindings);
+	    let unified = match self.unify_with(&goal.term, &renamed_clause.head, occurs_check) {
+		Ok(unified) => unified,
+		Err(PrologError::Exception(ball)) => {
+		    self.bindings.release(trail);
+		    let cut_barrier = self.choicepoints.len();
+		    let term = Term::Str("throw".into(), vec![ball].into());
+		    self.goals.push(Goal { term, cut_barrier, module: goal.module });
+		    return true;
+		}
+		Err(PrologError::SyntaxError(_)) => unreachable!("unification raises no syntax errors"),
+	    };
+	    if unified {
+		let cut_barrier = self.choicepoints.len();
+		let module = goal.module.clone();
+		if i + 1 < candidates.len() {
+		    self.choicepoints.push(ChoicePoint {
+			goals: self.goals.clone(),
+			trail,
+			alternative: Alternative::Clauses { goal, clauses: candidates, generation, next: i + 1 },
+		    });
+		} else {
+		    self.bindings.release(trail);
+		}
+		for term in renamed_clause.body.into_iter().rev() {
+		    self.goals.push(Goal { term, cut_barrier, module: module.clone() });
+		}
+		return true;
+	    }
+	}
+	self.bindings.release(trail);
+	false
     }
-}
 
-fn ask_confirm() -> bool {
-    loop {
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        match input.chars().nth(0).unwrap() {
-            ';' => return true,
-            _ => return false,
-        }
+    /// Erases the first clause of a predicate that unifies with `Head :-
+    /// Body`, leaving a choicepoint to erase the next ones on backtracking
+    pub(crate) fn retract(&mut self, head: Term, body: Term, predicate: Predicate) -> bool {
+	let candidates = self.machine.database_mut().matching_clauses(&predicate, &head, &self.bindings);
+	let clause = Term::Str(":-".into(), vec![head, body].into());
+	match candidates {
+	    Some(clauses) => self.retract_candidates(clause, predicate, clauses, 0),
+	    None => false,
+	}
+    }
+
+    /// The clauses of a predicate that can match `head`, renamed, as
+    /// `Head :- Body` terms
+    pub(crate) fn clauses(&mut self, predicate: &Predicate, head: &Term) -> Vec<Term> {
+	let generation = self.machine.database().generation();
+	let candidates = match self.machine.database_mut().matching_clauses(predicate, head, &self.bindings) {
+	    Some(candidates) => candidates,
+	    None => return Vec::new(),
+	};
+	candidates.iter()
+	    .filter(|clause| clause.visible(generation))
+	    .map(|clause| rename_clause(&clause.clause, &mut self.bindings).to_term())
+	    .collect()
+    }
+
+    fn retract_candidates(&mut self, clause: Term, predicate: Predicate, candidates: ClauseList, start: usize) -> bool {
+	let trail = self.bindings.mark();
+	for (i, stored) in candidates.iter().enumerate().skip(start) {
+	    if stored.is_erased() {
+		continue;
+	    }
+	    let renamed_clause = rename_clause(&stored.clause, &mut self.bindings);
+	    if unify(&clause, &renamed_clause.to_term(), &mut self.bindings, false) {
+		let stored = stored.clone();
+		self.machine.database_mut().erase(&predicate, &stored);
+		if i + 1 < candidates.len() {
+		    self.choicepoints.push(ChoicePoint {
+			goals: self.goals.clone(),
+			trail,
+			alternative: Alternative::Retract { clause, predicate, clauses: candidates, next: i + 1 },
+		    });
+		} else {
+		    self.bindings.release(trail);
+		}
+		return true;
+	    }
+	}
+	self.bindings.release(trail);
+	false
+    }
+
+    /// Collects a copy of `template` for each solution of `goal`, as
+    /// `findall/3` does. The goal runs on its own, leaving the pending
+    /// goals, choicepoints and bindings as they were.
+    pub(crate) fn find_all(&mut self, template: &Term, goal: Term) -> Result<Vec<Term>, PrologError> {
+	let context = self.context.clone();
+	let goals = std::mem::take(&mut self.goals);
+	let choicepoints = std::mem::take(&mut self.choicepoints);
+	let trail = self.bindings.mark();
+	self.nested += 1;
+	self.goals.push(Goal { term: goal, cut_barrier: 0, module: context.clone() });
+	let mut results = Vec::new();
+	let mut found = self.solve();
+	while let Ok(true) = found {
+	    results.push(self.substitute(template));
+	    found = if self.backtrack() { self.solve() } else { Ok(false) };
+	}
+	self.nested -= 1;
+	self.goals = goals;
+	self.choicepoints = choicepoints;
+	self.bindings.undo(trail);
+	self.context = context;
+	found?;
+	Ok(results.iter().map(|result| rename_term(result, &mut HashMap::new(), &mut self.bindings)).collect())
     }
-}
 
-fn subst_bindings(bindings: Bindings, term: Term) -> Term {
-    let bindings = bindings.expect("Only can be called when bindings are OK");
-    match term {
-	Term::Atom(x) => Term::Atom(x.clone()),
-	Term::Var(ref x) => {
-	    if let Some(value) = bindings.get(x) {
-		subst_bindings(Some(bindings.clone()), value.clone())
+    /// Unifies `target` with the first candidate that matches, leaving a
+    /// choicepoint to try the rest on backtracking. This is how
+    /// nondeterministic builtins return several answers.
+    pub(crate) fn unify_alternatives(&mut self, target: Term, candidates: Vec<Term>) -> bool {
+	self.unify_candidates(target, candidates, 0)
+    }
+
+    fn unify_candidates(&mut self, target: Term, candidates: Vec<Term>, start: usize) -> bool {
+	let trail = self.bindings.mark();
+	for i in start..candidates.len() {
+	    if unify(&target, &candidates[i], &mut self.bindings, false) {
+		if i + 1 < candidates.len() {
+		    self.choicepoints.push(ChoicePoint {
+			goals: self.goals.clone(),
+			trail,
+			alternative: Alternative::Unify { target, candidates, next: i + 1 },
+		    });
+		} else {
+		    self.bindings.release(trail);
+		}
+		return true;
+	    }
+	}
+	self.bindings.release(trail);
+	false
+    }
+
+    /// Unifies `target` with `next`, leaving a choicepoint for the following
+    /// integers up to `high`. Unlike `unify_alternatives`, the candidates are
+    /// generated as they are needed, so the range can be huge or endless.
+    pub(crate) fn unify_range(&mut self, target: Term, next: Integer, high: Option<Integer>) -> bool {
+	if high.as_ref().is_some_and(|high| next > *high) {
+	    return false;
+	}
+	let following = next.checked_add(&Integer::from(1))
+	    .filter(|following| high.as_ref().is_none_or(|high| following <= high));
+	if let Some(following) = following {
+	    self.choicepoints.push(ChoicePoint {
+		goals: self.goals.clone(),
+		trail: self.bindings.mark(),
+		alternative: Alternative::Range { target: target.clone(), next: following, high },
+	    });
+	}
+	self.unify(target, Term::Int(next))
+    }
+
+    /// Unifies `target` with the first candidate that matches, leaving a
+    /// choicepoint to generate the rest on backtracking. Like `unify_range`,
+    /// the candidates are made as they are needed. They must have no
+    /// variables.
+    pub(crate) fn unify_lazily(&mut self, target: Term, candidates: impl Iterator<Item = Term> + 'static) -> bool {
+	let candidates: Box<dyn Iterator<Item = Term>> = Box::new(candidates);
+	self.unify_generated(target, candidates.peekable())
+    }
+
+    fn unify_generated(&mut self, target: Term, mut candidates: Peekable<Box<dyn Iterator<Item = Term>>>) -> bool {
+	let trail = self.bindings.mark();
+	while let Some(candidate) = candidates.next() {
+	    if unify(&target, &candidate, &mut self.bindings, false) {
+		if candidates.peek().is_some() {
+		    self.choicepoints.push(ChoicePoint {
+			goals: self.goals.clone(),
+			trail,
+			alternative: Alternative::Generate { target, candidates },
+		    });
+		} else {
+		    self.bindings.release(trail);
+		}
+		return true;
+	    }
+	}
+	self.bindings.release(trail);
+	false
+    }
+
+    /// Replaces every bound variable of the term by its value
+    pub(crate) fn substitute(&self, term: &Term) -> Term {
+	subst_bindings(&self.bindings, term)
+    }
+
+    pub(crate) fn unify(&mut self, x: Term, y: Term) -> bool {
+	unify(&x, &y, &mut self.bindings, false)
+    }
+
+    /// Unifies two terms checking that no variable is bound to a term that
+    /// contains it as the `occurs_check` flag says
+    pub(crate) fn unify_with(&mut self, x: &Term, y: &Term, occurs_check: OccursCheck) -> Result<bool, PrologError> {
+	match occurs_check {
+	    OccursCheck::False => Ok(unify(x, y, &mut self.bindings, false)),
+	    OccursCheck::True => Ok(unify(x, y, &mut self.bindings, true)),
+	    OccursCheck::Error => {
+		let mark = self.bindings.mark();
+		match unify_or_cycle(x, y, &mut self.bindings) {
+		    Ok(unified) => {
+			self.bindings.release(mark);
+			Ok(unified)
+		    }
+		    Err((var, term)) => {
+			let error = occurs_check_error(Term::Var(var), self.substitute(&term));
+			self.bindings.undo(mark);
+			Err(error)
+		    }
+		}
+	    }
+	}
+    }
+
+    /// Whether two terms unify, without binding their variables
+    pub(crate) fn unifiable(&mut self, x: &Term, y: &Term) -> Result<bool, PrologError> {
+	let mark = self.bindings.mark();
+	let occurs_check = self.machine.flags().occurs_check;
+	let unified = self.unify_with(x, y, occurs_check);
+	self.bindings.undo(mark);
+	unified
+    }
+
+    /// Follows the bindings of a variable, without substituting inside the
+    /// value like `substitute` does
+    pub(crate) fn deref(&self, term: Term) -> Term {
+	walk(term, &self.bindings)
+    }
+
+    /// A variable that doesn't appear anywhere else
+    pub(crate) fn fresh_variable(&mut self) -> Term {
+	self.bindings.fresh_variable()
+    }
+
+    /// A copy of a term with fresh variables
+    pub(crate) fn fresh_copy(&mut self, term: &Term) -> Term {
+	rename_term(&self.substitute(term), &mut HashMap::new(), &mut self.bindings)
+    }
+
+    /// Reads a term from the text, which has no final `.`, with the
+    /// operators of the machine. Its variables are fresh. Syntax errors are
+    /// raised as `syntax_error(Message)`.
+    pub(crate) fn read_term(&mut self, text: &str) -> Result<Term, PrologError> {
+	let text = format!("{} .", text);
+	let mut parser = Parser::new(&text);
+	let term = match parser.read_term(self.machine.operators()) {
+	    Ok(Some(term)) => term,
+	    Ok(None) => return Err(syntax_error("term expected, found end of input")),
+	    Err(PrologError::SyntaxError(error)) => return Err(syntax_error(&error.message)),
+	    Err(error) => return Err(error),
+	};
+	if !matches!(parser.read_term(self.machine.operators()), Ok(None)) {
+	    return Err(syntax_error("end of text expected"));
+	}
+	Ok(rename_term(&term, &mut HashMap::new(), &mut self.bindings))
+    }
+
+    /// Replaces an argument of the compound term `term` is bound to, giving
+    /// its variable the new term, as `setarg/3` does. Backtracking undoes it
+    /// if `backtrackable`. Terms not given through a variable can't change.
+    pub(crate) fn set_argument(&mut self, term: Term, index: usize, value: Term, backtrackable: bool) {
+	let mut var = None;
+	let mut term = term;
+	while let Term::Var(next) = term {
+	    match self.bindings.get(next) {
+		Some(value) => {
+		    var = Some(next);
+		    term = value.clone();
+		}
+		None => break,
+	    }
+	}
+	if let (Some(var), Term::Str(name, args)) = (var, term) {
+	    let mut args = args.into_vec();
+	    args[index] = value;
+	    let term = Term::Str(name, args.into());
+	    if backtrackable {
+		self.bindings.rebind(var, term);
 	    } else {
-		Term::Var(x.clone())
+		self.bindings.set(var, term);
 	    }
 	}
-	Term::Str(f, args) => {
-	    Term::Str(f.clone(), args.iter().map(|t| subst_bindings(Some(bindings.clone()), t.clone())).collect())
+    }
+
+    /// The values of the query variables, leaving out the ones still unbound
+    fn answer(&self) -> Answer {
+	// A query variable bound to a fresh variable gives it its name
+	let mut variable_names = self.variable_names.clone();
+	for (name, var) in &self.variable_names {
+	    if let Term::Var(other) = walk(Term::Var(*var), &self.bindings) {
+		if !variable_names.iter().any(|(_, named)| *named == other) {
+		    variable_names.push((name.clone(), other));
+		}
+	    }
+	}
+	let name_of = |var: usize| variable_names.iter().find(|(_, named)| *named == var).map(|(name, _)| name);
+	Answer {
+	    // Variables starting with an underscore are not reported
+	    bindings: self.variable_names.iter()
+		.filter(|(name, _)| !name.starts_with('_'))
+		.map(|(name, var)| (name, subst_bindings(&self.bindings, &Term::Var(*var))))
+		.filter(|(name, value)| match value {
+		    Term::Var(other) => name_of(*other).is_some_and(|other| other != *name),
+		    _ => true,
+		})
+		.map(|(name, value)| (name.clone(), value))
+		.collect(),
+	    variable_names,
 	}
     }
 }
 
-pub fn find_variables_in_goals(goals: &Vec<Term>) -> HashSet<String> {
-    let mut vars = HashSet::new();
-    for goal in goals {
-	match goal {
-	    Term::Var(var) => {
-		vars.insert(var.clone());
-	    },
-	    Term::Atom(_) => (),
-	    Term::Str(_, args) => vars.extend(find_variables_in_goals(&args))
+impl Iterator for Solutions<'_> {
+    type Item = Result<Answer, PrologError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	if let Some(error) = self.error.take() {
+	    self.started = true;
+	    return Some(Err(error));
+	}
+	let found = if self.started {
+	    if self.backtrack() { self.solve() } else { Ok(false) }
+	} else {
+	    self.started = true;
+	    self.solve()
+	};
+	match found {
+	    Ok(true) => Some(Ok(self.answer())),
+	    Ok(false) => {
+		self.choicepoints.clear();
+		None
+	    }
+	    Err(error) => {
+		self.goals = Goals::default();
+		self.choicepoints.clear();
+		Some(Err(error))
+	    }
+	}
+    }
+}
+
+/// A step of copying a term with an explicit stack
+enum CopyStep {
+    /// Copy this term
+    Visit(Term),
+    /// A term that is already copied
+    Output(Term),
+    /// Copy the value bound to a variable in its place, or leave the
+    /// variable if its value is already being copied, as in `X = f(X)`
+    Expand(usize, Term),
+    /// The value of the variable is copied
+    Expanded(usize),
+    /// Build a compound term from the last copied arguments
+    Build(Atom, usize),
+}
+
+/// Copies a term, replacing each variable by what `variable` returns: a
+/// term to copy in turn or one to use as it is. The term is copied bottom
+/// up with an explicit stack, so long lists don't exhaust the Rust stack.
+fn copy_term(term: &Term, mut variable: impl FnMut(usize) -> CopyStep) -> Term {
+    let mut pending = vec![CopyStep::Visit(term.clone())];
+    let mut copied = Vec::new();
+    let mut expanding = HashSet::new();
+    while let Some(step) = pending.pop() {
+	match step {
+	    CopyStep::Visit(Term::Var(var)) => pending.push(variable(var)),
+	    CopyStep::Expand(_, value @ (Term::Atom(_) | Term::Int(_) | Term::Float(_))) => copied.push(value),
+	    CopyStep::Expand(var, value) if expanding.insert(var) => {
+		pending.push(CopyStep::Expanded(var));
+		pending.push(CopyStep::Visit(value));
+	    }
+	    CopyStep::Expand(var, _) => copied.push(Term::Var(var)),
+	    CopyStep::Expanded(var) => {
+		expanding.remove(&var);
+	    }
+	    CopyStep::Visit(Term::Str(name, args)) => {
+		pending.push(CopyStep::Build(name, args.len()));
+		pending.extend(args.iter().rev().map(|arg| CopyStep::Visit(arg.clone())));
+	    }
+	    CopyStep::Visit(term) | CopyStep::Output(term) => copied.push(term),
+	    CopyStep::Build(name, arity) => {
+		let args = copied.split_off(copied.len() - arity);
+		copied.push(Term::Str(name, args.into()));
+	    }
+	}
+    }
+    copied.pop().expect("copied term")
+}
+
+/// Renames the variables of a clause to fresh ones. They are numbered from
+/// 0 in the database, so renaming just adds the first fresh number to them.
+fn rename_clause(clause: &Clause, bindings: &mut Bindings) -> Clause {
+    let first = bindings.next_variable();
+    let mut count = 0;
+    let mut rename = |var: usize| {
+	count = count.max(var + 1);
+	CopyStep::Output(Term::Var(first + var))
+    };
+    let head = copy_term(&clause.head, &mut rename);
+    let body = clause.body.iter().map(|term| copy_term(term, &mut rename)).collect();
+    bindings.fresh_variables(count);
+    Clause { head, body }
+}
+
+/// Numbers the variables of a clause from 0, as the database keeps them
+pub(crate) fn number_variables(clause: &Clause) -> Clause {
+    let mut numbers: HashMap<usize, usize> = HashMap::new();
+    let mut number = |var: usize| {
+	let next = numbers.len();
+	CopyStep::Output(Term::Var(*numbers.entry(var).or_insert(next)))
+    };
+    let head = copy_term(&clause.head, &mut number);
+    let body = clause.body.iter().map(|term| copy_term(term, &mut number)).collect();
+    Clause { head, body }
+}
+
+/// Renames the variables of a term to fresh ones, keeping in `renamed`
+/// which one each variable got
+fn rename_term(term: &Term, renamed: &mut HashMap<usize, usize>, bindings: &mut Bindings) -> Term {
+    copy_term(term, |var| {
+	let fresh = *renamed.entry(var).or_insert_with(|| bindings.fresh_variables(1));
+	CopyStep::Output(Term::Var(fresh))
+    })
+}
+
+#[cfg(test)]
+fn top_level_prove(goals: Vec<Term>, variable_names: &[(&str, usize)], machine: &mut Machine) -> String {
+    let variable_names: Vec<(String, usize)> = variable_names.iter().map(|(name, var)| (name.to_string(), *var)).collect();
+    let solutions: Vec<String> = Solutions::new(machine, goals, &variable_names)
+	.map(|answer| {
+	    let answer = answer.unwrap();
+	    answer.bindings().iter().map(|(var, value)| format!("{} = {}", var, value)).collect::<Vec<String>>().join(",")
+	})
+	.collect();
+
+    if solutions.is_empty() {
+	"false.".to_string()
+    } else {
+	solutions.join(";\n")
+    }
+}
+
+fn subst_bindings(bindings: &Bindings, term: &Term) -> Term {
+    copy_term(term, |var| match bindings.get(var) {
+	Some(value) => CopyStep::Expand(var, value.clone()),
+	None => CopyStep::Output(Term::Var(var)),
+    })
+}
+
+pub fn find_variables_in_goals(goals: &[Term]) -> Vec<usize> {
+    let mut vars = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending: Vec<&Term> = goals.iter().rev().collect();
+    while let Some(term) = pending.pop() {
+	match term {
+	    Term::Var(var) if seen.insert(*var) => vars.push(*var),
+	    Term::Str(_, args) => pending.extend(args.iter().rev()),
+	    _ => (),
 	}
     }
     vars
@@ -209,12 +1090,12 @@ pub fn find_variables_in_goals(goals: &Vec<Term>) -> HashSet<String> {
 fn socrates_test() {
     let mut db = Database::new();
     let clause = Clause {
-	head: Term::Str("mortal".into(), vec![Term::Var("X".into())]),
-	body: vec![Term::Str("human".into(), vec![Term::Var("X".into())])],
+	head: Term::Str("mortal".into(), vec![Term::Var(0)].into()),
+	body: vec![Term::Str("human".into(), vec![Term::Var(0)].into())],
     };
     db.add_clause(clause);
     let clause = Clause {
-	head: Term::Str("human".into(), vec![Term::Atom("socrates".into())]),
+	head: Term::Str("human".into(), vec![Term::Atom("socrates".into())].into()),
 	body: vec![Term::Atom("true".into())],
     };
     db.add_clause(clause);
@@ -225,24 +1106,25 @@ fn socrates_test() {
     };
     db.add_clause(clause);
 
-    let query1 = Term::Str("human".into(), vec![Term::Atom("socrates".into())]);
-    let result = top_level_prove(vec![query1], &db);
+    let mut machine = Machine::from_database(db);
+    let query1 = Term::Str("human".into(), vec![Term::Atom("socrates".into())].into());
+    let result = top_level_prove(vec![query1], &[], &mut machine);
     assert_eq!(result, "");
 
-    let query2 = Term::Str("mortal".into(), vec![Term::Atom("socrates".into())]);
-    let result = top_level_prove(vec![query2], &db);
+    let query2 = Term::Str("mortal".into(), vec![Term::Atom("socrates".into())].into());
+    let result = top_level_prove(vec![query2], &[], &mut machine);
     assert_eq!(result, "");
 
-    let query3 = Term::Str("mortal".into(), vec![Term::Var("X".into())]);
-    let result = top_level_prove(vec![query3], &db);
+    let query3 = Term::Str("mortal".into(), vec![Term::Var(0)].into());
+    let result = top_level_prove(vec![query3], &[("X", 0)], &mut machine);
     assert_eq!(result, "X = socrates");
 
-    let query4 = Term::Str("mrtl".into(), vec![Term::Atom("socrates".into())]);
-    let result = top_level_prove(vec![query4], &db);
-    assert_eq!(result, "false.");
+    let query4 = Term::Str("mrtl".into(), vec![Term::Atom("socrates".into())].into());
+    let result = Solutions::new(&mut machine, vec![query4], &[]).next();
+    assert_eq!(result, Some(Err(existence_error("procedure", indicator("mrtl", 1)))));
 
-    let query5 = Term::Str("mortal".into(), vec![Term::Atom("gepeto".into())]);
-    let result = top_level_prove(vec![query5], &db);
+    let query5 = Term::Str("mortal".into(), vec![Term::Atom("gepeto".into())].into());
+    let result = top_level_prove(vec![query5], &[], &mut machine);
     assert_eq!(result, "false.");
 }
 
@@ -250,66 +1132,66 @@ fn socrates_test() {
 fn likes() {
     let mut db = Database::new();
     db.add_clause(Clause {
-	head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Atom("robin".into())]),
+	head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Atom("robin".into())].into()),
 	body: vec![],
     });
     db.add_clause(Clause {
-	head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("lee".into())]),
+	head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("lee".into())].into()),
 	body: vec![],
     });
     db.add_clause(Clause {
-	head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("kim".into())]),
+	head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Atom("kim".into())].into()),
 	body: vec![],
     });
     db.add_clause(Clause {
-	head: Term::Str("likes".into(), vec![Term::Atom("robin".into()), Term::Atom("cats".into())]),
+	head: Term::Str("likes".into(), vec![Term::Atom("robin".into()), Term::Atom("cats".into())].into()),
 	body: vec![],
     });
     db.add_clause(Clause {
-	head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Var("X".into())]),
-	body: vec![Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("cats".into())])],
+	head: Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Var(0)].into()),
+	body: vec![Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("cats".into())].into())],
     });
     db.add_clause(Clause {
-	head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Var("X".into())]),
+	head: Term::Str("likes".into(), vec![Term::Atom("kim".into()), Term::Var(0)].into()),
 	body: vec![
-	    Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("lee".into())]),
-	    Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Atom("kim".into())])
+	    Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("lee".into())].into()),
+	    Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("kim".into())].into())
 	],
     });
     db.add_clause(Clause {
-	head: Term::Str("likes".into(), vec![Term::Var("X".into()), Term::Var("X".into())]),
+	head: Term::Str("likes".into(), vec![Term::Var(0), Term::Var(0)].into()),
 	body: vec![],
     });
 
-    let query1 = Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Var("Who".into())]);
-    let result = top_level_prove(vec![query1], &db);
+    let mut machine = Machine::from_database(db);
+    let query1 = Term::Str("likes".into(), vec![Term::Atom("sandy".into()), Term::Var(0)].into());
+    let result = top_level_prove(vec![query1], &[("Who", 0)], &mut machine);
     assert_eq!(result, "Who = lee;\nWho = kim;\nWho = robin;\nWho = sandy;\nWho = cats;\nWho = sandy");
 
-    let query2 = Term::Str("likes".into(), vec![Term::Var("Who".into()), Term::Atom("sandy".into())]);
-    let result = top_level_prove(vec![query2], &db);
+    let query2 = Term::Str("likes".into(), vec![Term::Var(0), Term::Atom("sandy".into())].into());
+    let result = top_level_prove(vec![query2], &[("Who", 0)], &mut machine);
     assert_eq!(result, "Who = sandy;\nWho = kim;\nWho = sandy");
 
-    let query3 = Term::Str("likes".into(), vec![Term::Atom("robin".into()), Term::Atom("lee".into())]);
-    let result = top_level_prove(vec![query3], &db);
+    let query3 = Term::Str("likes".into(), vec![Term::Atom("robin".into()), Term::Atom("lee".into())].into());
+    let result = top_level_prove(vec![query3], &[], &mut machine);
     assert_eq!(result, "false.");
 }
 
-/*
 #[test]
 fn backtracking() {
     let mut db = Database::new();
     let clause = Clause {
-	head: Term::Str("human".into(), vec![Term::Atom("socrates".into())]),
+	head: Term::Str("human".into(), vec![Term::Atom("socrates".into())].into()),
 	body: vec![Term::Atom("true".into())],
     };
     db.add_clause(clause);
     let clause = Clause {
-	head: Term::Str("human".into(), vec![Term::Atom("plato".into())]),
+	head: Term::Str("human".into(), vec![Term::Atom("plato".into())].into()),
 	body: vec![Term::Atom("true".into())],
     };
     db.add_clause(clause);
     let clause = Clause {
-	head: Term::Str("human".into(), vec![Term::Atom("aristotle".into())]),
+	head: Term::Str("human".into(), vec![Term::Atom("aristotle".into())].into()),
 	body: vec![Term::Atom("true".into())],
     };
     db.add_clause(clause);
@@ -319,7 +1201,547 @@ fn backtracking() {
 	body: Vec::new(),
     };
     db.add_clause(clause);
-    
-    let query1 = Term::Str("human".into(), vec![Term::Var("X".into())]);
-    top_level_prove_backtracking(vec![query1], &db);
-}*/
+
+    let mut machine = Machine::from_database(db);
+    let query1 = Term::Str("human".into(), vec![Term::Var(0)].into());
+    let mut solutions = Solutions::new(&mut machine, vec![query1], &[("X".into(), 0)]);
+    assert_eq!(solutions.next().unwrap().unwrap().to_string(), "X = socrates");
+    assert_eq!(solutions.next().unwrap().unwrap().to_string(), "X = plato");
+    assert_eq!(solutions.next().unwrap().unwrap().to_string(), "X = aristotle");
+    assert_eq!(solutions.next(), None);
+}
+
+#[cfg(test)]
+fn machine_from(source: &str) -> Machine {
+    let mut machine = Machine::new();
+    machine.consult(source).unwrap();
+    machine
+}
+
+#[cfg(test)]
+fn query_answers(machine: &mut Machine, query: &str) -> Vec<String> {
+    machine.query(query).map(|answer| answer.unwrap().to_string()).collect()
+}
+
+#[test]
+fn cut() {
+    let mut machine = machine_from(r#"member(X, [X|Xs]).
+member(X, [Y|Xs]) :- member(X, Xs).
+first(X, Xs) :- member(X, Xs), !.
+color(red).
+color(green) :- !.
+color(blue).
+pair(X, Y) :- member(X, [a,b]), !, member(Y, [c,d]).
+"#);
+
+    assert_eq!(query_answers(&mut machine, "first(X, [a,b,c])."), vec!["X = a"]);
+    assert_eq!(query_answers(&mut machine, "color(X)."), vec!["X = red", "X = green"]);
+    assert_eq!(query_answers(&mut machine, "pair(X, Y)."), vec!["X = a, Y = c", "X = a, Y = d"]);
+    assert_eq!(query_answers(&mut machine, "member(X, [a,b]), !."), vec!["X = a"]);
+}
+
+#[test]
+fn cut_is_local_to_call() {
+    let mut machine = machine_from(r#"member(X, [X|Xs]).
+member(X, [Y|Xs]) :- member(X, Xs).
+opaque(X) :- member(X, [a,b,c]), call(!).
+transparent(X) :- member(X, [a,b,c]), ','(!, true).
+true.
+"#);
+
+    assert_eq!(query_answers(&mut machine, "opaque(X)."), vec!["X = a", "X = b", "X = c"]);
+    assert_eq!(query_answers(&mut machine, "transparent(X)."), vec!["X = a"]);
+    assert_eq!(query_answers(&mut machine, "call(member(X, [a,b])), !."), vec!["X = a"]);
+}
+
+#[test]
+fn arithmetic() {
+    let mut machine = machine_from(r#"len([], 0).
+len([X|Xs], N) :- len(Xs, M), is(N, +(M, 1)).
+max(X, Y, X) :- >=(X, Y), !.
+max(X, Y, Y).
+"#);
+
+    assert_eq!(query_answers(&mut machine, "is(X, +(1, 2))."), vec!["X = 3"]);
+    assert_eq!(query_answers(&mut machine, "is(X, /(1, 2.0))."), vec!["X = 0.5"]);
+    assert_eq!(query_answers(&mut machine, "is(3, +(1, 2))."), vec!["true"]);
+    assert!(query_answers(&mut machine, "is(3.0, +(1, 2)).").is_empty());
+    assert_eq!(query_answers(&mut machine, "len([a,b,c], N)."), vec!["N = 3"]);
+    assert_eq!(query_answers(&mut machine, "max(3, 7, M)."), vec!["M = 7"]);
+    assert_eq!(query_answers(&mut machine, "=:=(1, 1.0), =\\=(1, 2), <(1, 2), >(2, 1), =<(1, 1), >=(1, 1)."), vec!["true"]);
+    assert!(query_answers(&mut machine, "<(2, 1).").is_empty());
+}
+
+#[test]
+fn arithmetic_errors() {
+    let mut machine = Machine::new();
+    let mut solutions = machine.query("X is foo + 1.");
+    let expected = crate::error::type_error("evaluable", crate::error::indicator("foo", 0));
+    assert_eq!(solutions.next(), Some(Err(expected)));
+    assert_eq!(solutions.next(), None);
+
+    let mut solutions = machine.query("X < 1.");
+    assert_eq!(solutions.next(), Some(Err(crate::error::instantiation_error())));
+}
+
+#[test]
+fn negation() {
+    let mut machine = machine_from(r#"human(socrates).
+human(plato).
+god(zeus).
+mortal(X) :- \+ god(X).
+"#);
+
+    assert_eq!(query_answers(&mut machine, "\\+ human(zeus)."), vec!["true"]);
+    assert!(query_answers(&mut machine, "\\+ human(plato).").is_empty());
+    assert_eq!(query_answers(&mut machine, "human(X), \\+ \\+ X = plato."), vec!["X = plato"]);
+    assert_eq!(query_answers(&mut machine, "\\+ \\+ X = a."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "member(X, [zeus, plato]), mortal(X)."), vec!["X = plato"]);
+    assert_eq!(query_answers(&mut machine, "member(X, [a,b]), \\+ (member(Y, [a,b]), !, fail)."), vec!["X = a", "X = b"]);
+}
+
+#[test]
+fn call_with_extra_arguments() {
+    let mut machine = machine_from(r#"add(X, Y, Z) :- Z is X + Y.
+"#);
+
+    assert_eq!(query_answers(&mut machine, "call(add(1), 2, X)."), vec!["X = 3"]);
+    assert_eq!(query_answers(&mut machine, "G = add, call(G, 1, 2, X)."), vec!["G = add, X = 3"]);
+    assert_eq!(query_answers(&mut machine, "call(member, X, [a,b])."), vec!["X = a", "X = b"]);
+    assert_eq!(query_answers(&mut machine, "call((member(X, [a,b]), !))."), vec!["X = a"]);
+    assert_eq!(machine.query("call(G, 1).").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("call(1, a).").next(), Some(Err(type_error("callable", Term::Int(1.into())))));
+    assert_eq!(machine.query("X.").next(), Some(Err(instantiation_error())));
+}
+
+#[test]
+fn disjunction_and_if_then_else() {
+    let mut machine = machine_from(r#"classify(X, Class) :-
+    (   X < 0 -> Class = negative
+    ;   X =:= 0 -> Class = zero
+    ;   Class = positive
+    ).
+color(red). color(green). color(blue).
+first_color(C) :- ( color(C), ! ; C = none ).
+either(X) :- ( X = a ; X = b ), !.
+soft(X, Y) :- ( member(X, [1,2]) *-> Y = found ; Y = none ).
+soft_none(Y) :- ( member(_, []) *-> Y = found ; Y = none ).
+t1(X) :- G = (member(X, [1,2,3]), !), ( G ; X = 9 ).
+c(X) :- ( X = 1 ; X = 2 ), Y = !, ( Y ; true ).
+"#);
+
+    assert_eq!(query_answers(&mut machine, "X = a ; X = b."), vec!["X = a", "X = b"]);
+    assert_eq!(query_answers(&mut machine, "(X = a ; X = b), (Y = 1 ; Y = 2)."), vec!["X = a, Y = 1", "X = a, Y = 2", "X = b, Y = 1", "X = b, Y = 2"]);
+    assert_eq!(query_answers(&mut machine, "classify(-3, C), classify(0, D), classify(5, E)."), vec!["C = negative, D = zero, E = positive"]);
+    assert_eq!(query_answers(&mut machine, "( color(C) -> true ; C = none )."), vec!["C = red"]);
+    assert_eq!(query_answers(&mut machine, "( fail -> X = then ; X = else )."), vec!["X = else"]);
+    assert!(query_answers(&mut machine, "( fail -> X = then ).").is_empty());
+    assert_eq!(query_answers(&mut machine, "color(C), ( C = green -> true )."), vec!["C = green"]);
+    assert_eq!(query_answers(&mut machine, "first_color(C)."), vec!["C = red"]);
+    assert_eq!(query_answers(&mut machine, "either(X)."), vec!["X = a"]);
+    assert_eq!(query_answers(&mut machine, "soft(X, Y)."), vec!["X = 1, Y = found", "X = 2, Y = found"]);
+    assert_eq!(query_answers(&mut machine, "soft_none(Y)."), vec!["Y = none"]);
+    assert_eq!(query_answers(&mut machine, "( member(X, [1,2,3]), X > 1 -> Y = X ; Y = 0 )."), vec!["X = 2, Y = 2"]);
+    // The cut in the condition is local to it
+    assert_eq!(query_answers(&mut machine, "color(C), ( !, C = blue -> true ; fail )."), vec!["C = blue"]);
+    // Cut in a branch cuts the clause
+    assert_eq!(query_answers(&mut machine, "member(X, [1,2,3]), ( X > 1, ! ; true )."), vec!["X = 1", "X = 2"]);
+    // A cut a variable goal is bound to is local to it
+    assert_eq!(query_answers(&mut machine, "findall(X, t1(X), L)."), vec!["L = [1,9]"]);
+    assert_eq!(query_answers(&mut machine, "findall(X, c(X), L)."), vec!["L = [1,1,2,2]"]);
+    // The soft cut of `*->` can't be called from Prolog
+    assert_eq!(machine.query("'$soft_cut'(100).").next(), Some(Err(existence_error("procedure", indicator("$soft_cut", 1)))));
+}
+
+#[test]
+fn catch_and_throw() {
+    let mut machine = machine_from(r#"risky(X) :- X > 2, throw(too_big(X)).
+risky(X).
+safe(X, R) :- catch(risky(X), too_big(N), R = caught(N)).
+nested(R) :- catch(catch(throw(inner), outer, R = wrong), inner, R = right).
+after_exit(R) :- catch(true, _, R = caught), throw(late).
+"#);
+
+    assert_eq!(query_answers(&mut machine, "safe(1, R)."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "safe(3, R)."), vec!["R = caught(3)"]);
+    assert_eq!(query_answers(&mut machine, "nested(R)."), vec!["R = right"]);
+    assert_eq!(query_answers(&mut machine, "catch(member(X, [1,2,3]), _, true)."), vec!["X = 1", "X = 2", "X = 3"]);
+    assert_eq!(query_answers(&mut machine, "catch((member(X, [1,2,3]), X > 1, throw(found(X))), found(Y), true)."), vec!["Y = 2"]);
+    assert_eq!(query_answers(&mut machine, "catch(after_exit(R), late, R = outside)."), vec!["R = outside"]);
+    // Prolog code can't pass for the marker that keeps a catch active
+    assert_eq!(query_answers(&mut machine, "catch((catch(true, _, R = caught), throw(late), '$exit_catch'(1)), late, R = outside)."), vec!["R = outside"]);
+    assert_eq!(query_answers(&mut machine, "catch(X is foo + 1, error(type_error(T, V), _), true)."), vec!["T = evaluable, V = foo/0"]);
+    assert_eq!(query_answers(&mut machine, "catch(undefined, error(E, _), true)."), vec!["E = existence_error(procedure,undefined/0)"]);
+    assert_eq!(query_answers(&mut machine, "catch(call(1), error(E, _), true)."), vec!["E = type_error(callable,1)"]);
+    assert_eq!(query_answers(&mut machine, "catch(throw(_), error(E, _), true)."), vec!["E = instantiation_error"]);
+    assert_eq!(machine.query("catch(throw(a), b, true).").next(), Some(Err(PrologError::Exception(Term::Atom("a".into())))));
+    // Bindings made before the exception are undone
+    assert_eq!(query_answers(&mut machine, "catch((X = 1, throw(e)), e, true)."), vec!["true"]);
+    // The recovery goal can't cut the clause
+    assert_eq!(query_answers(&mut machine, "member(X, [1,2]), catch(throw(e), e, !)."), vec!["X = 1", "X = 2"]);
+}
+
+#[test]
+fn catch_exits_deterministically() {
+    let mut machine = machine_from(r#"loop(0) :- !.
+loop(N) :- catch(true, _, true), N1 is N - 1, loop(N1).
+"#);
+    // The catch of a goal that exits without choicepoints leaves none behind
+    let query = crate::parser::clause_body("loop(100000).").unwrap();
+    let mut solutions = Solutions::new(&mut machine, query, &[]);
+    assert_eq!(solutions.next(), Some(Ok(Answer { bindings: Vec::new(), variable_names: Vec::new() })));
+    assert!(solutions.choicepoints.is_empty());
+    drop(solutions);
+    let query = crate::parser::clause_body("catch(member(X, [1,2]), _, true).").unwrap();
+    let mut solutions = Solutions::new(&mut machine, query, &[]);
+    assert!(solutions.next().is_some());
+    assert_eq!(solutions.choicepoints.len(), 2);
+    drop(solutions);
+    // The catch stays active until its goal exits, and only then
+    assert_eq!(query_answers(&mut machine, "catch((loop(3), throw(e)), e, true)."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "catch((catch(loop(3), _, fail), throw(e)), e, true)."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "catch((catch(member(X, [1,2]), _, fail), X > 1, throw(e(X))), e(Y), true)."), vec!["Y = 2"]);
+    // The marker that keeps a catch active can't be called from Prolog
+    assert_eq!(machine.query("'$exit_catch'(0).").next(), Some(Err(existence_error("procedure", indicator("$exit_catch", 1)))));
+}
+
+#[test]
+fn unknown_flag() {
+    let mut machine = Machine::new();
+    assert_eq!(query_answers(&mut machine, "current_prolog_flag(unknown, V)."), vec!["V = error"]);
+    assert_eq!(query_answers(&mut machine, "set_prolog_flag(unknown, fail), undefined."), Vec::<String>::new());
+    assert_eq!(query_answers(&mut machine, "current_prolog_flag(unknown, V)."), vec!["V = fail"]);
+    let warnings = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let sink = warnings.clone();
+    machine.set_diagnostics(move |diagnostic| sink.borrow_mut().push(diagnostic.to_string()));
+    assert_eq!(query_answers(&mut machine, "set_prolog_flag(unknown, warning), undefined."), Vec::<String>::new());
+    assert_eq!(*warnings.borrow(), vec!["Warning: unknown procedure undefined/0"]);
+    assert_eq!(machine.query("set_prolog_flag(unknown, maybe).").next(), Some(Err(crate::error::domain_error("flag_value", crate::parser::clause_body("unknown + maybe.").unwrap().remove(0)))));
+    assert_eq!(machine.query("set_prolog_flag(bounded, true).").next(), Some(Err(crate::error::permission_error("modify", "flag", Term::Atom("bounded".into())))));
+    assert_eq!(machine.query("current_prolog_flag(colour, V).").next(), Some(Err(crate::error::domain_error("prolog_flag", Term::Atom("colour".into())))));
+}
+
+#[test]
+fn deep_recursion() {
+    let mut machine = machine_from(r#"count(N, N) :- !.
+count(I, N) :- I1 is I + 1, count(I1, N).
+walk([]).
+walk([_|Xs]) :- walk(Xs).
+"#);
+
+    // Long lists are built, unified, copied and dropped without recursion
+    assert_eq!(query_answers(&mut machine, "length(_L, 1000000), length(_L, N)."), vec!["N = 1000000"]);
+    assert_eq!(query_answers(&mut machine, "length(_L, 100000), _L = _M, length(_M, N)."), vec!["N = 100000"]);
+    assert_eq!(query_answers(&mut machine, "between(1, 100000, X), X >= 100000."), vec!["X = 100000"]);
+    assert_eq!(query_answers(&mut machine, "count(0, 50000)."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "length(_L, 50000), walk(_L)."), vec!["true"]);
+}
+
+#[test]
+fn bounded_bindings() {
+    let mut machine = machine_from(r#"loop(0) :- !.
+loop(N) :- N1 is N - 1, loop(N1).
+"#);
+    // A deterministic loop trails nothing and its bindings are collected,
+    // even with an older choicepoint left
+    for query in ["loop(30000).", "member(X, [a,b]), loop(30000)."] {
+	let query = crate::parser::clause_body(query).unwrap();
+	let mut solutions = Solutions::new(&mut machine, query, &[("X".into(), 0)]);
+	assert!(matches!(solutions.next(), Some(Ok(_))));
+	assert!(solutions.bindings.bound_count() < 10000);
+	assert!(solutions.bindings.trail_len() <= 1);
+    }
+    assert_eq!(query_answers(&mut machine, "member(X, [a,b]), loop(10000)."), vec!["X = a", "X = b"]);
+}
+
+#[test]
+fn length_and_between() {
+    let mut machine = Machine::new();
+    assert_eq!(query_answers(&mut machine, "length([a,b,c], N)."), vec!["N = 3"]);
+    assert_eq!(query_answers(&mut machine, "length([a|T], 3), T = [b,c]."), vec!["T = [b,c]"]);
+    assert_eq!(query_answers(&mut machine, "length(L, N), N >= 2, !, L = [x,y]."), vec!["L = [x,y], N = 2"]);
+    assert!(query_answers(&mut machine, "length([a,b], 1).").is_empty());
+    assert_eq!(machine.query("length(L, -1).").next(), Some(Err(crate::error::domain_error("not_less_than_zero", Term::Int((-1).into())))));
+    assert_eq!(machine.query("length([a|b], N).").next(), Some(Err(type_error("list", Term::Str(".".into(), vec![Term::Atom("a".into()), Term::Atom("b".into())].into())))));
+    assert_eq!(query_answers(&mut machine, "between(1, 3, X)."), vec!["X = 1", "X = 2", "X = 3"]);
+    assert_eq!(query_answers(&mut machine, "between(1, inf, X), X > 2, !."), vec!["X = 3"]);
+    assert_eq!(query_answers(&mut machine, "between(1, 3, 2)."), vec!["true"]);
+    assert!(query_answers(&mut machine, "between(3, 1, X).").is_empty());
+    assert_eq!(machine.query("between(1, a, X).").next(), Some(Err(type_error("integer", Term::Atom("a".into())))));
+}
+
+#[test]
+fn answer_variables() {
+    let mut machine = Machine::new();
+    assert_eq!(query_answers(&mut machine, "X = f(Y, _Z)."), vec!["X = f(Y,_Z)"]);
+    assert_eq!(query_answers(&mut machine, "X = Y."), vec!["X = Y"]);
+    assert_eq!(query_answers(&mut machine, "X = _, Y = a."), vec!["Y = a"]);
+    assert_eq!(query_answers(&mut machine, "X = (a :- b), Y = (1, 2)."), vec!["X = (a :- b), Y = (1,2)"]);
+    let answer = machine.query("append(X, [b], [a, b]).").next().unwrap().unwrap();
+    assert_eq!(answer.get("X"), Some(&Term::Str(".".into(), vec![Term::Atom("a".into()), Term::Atom("[]".into())].into())));
+    // A cyclic binding is written with the variable where it recurs
+    assert_eq!(query_answers(&mut machine, "X = f(X)."), vec!["X = f(X)"]);
+    assert_eq!(query_answers(&mut machine, "X = f(Y), Y = g(X, Y), Z = [X]."), vec!["X = f(g(X,Y)), Y = g(f(Y),Y), Z = [f(g(X,Y))]"]);
+}
+
+#[test]
+fn collect_query_atoms() {
+    let mut machine = Machine::new();
+    let before = crate::atom::atom_count();
+    assert_eq!(query_answers(&mut machine, "X = f(query_only_atom, Y), Y = other_query_atom."), vec!["X = f(query_only_atom,other_query_atom), Y = other_query_atom"]);
+    assert_eq!(crate::atom::atom_count(), before);
+}
+
+#[test]
+fn first_argument_indexing() {
+    let mut machine = Machine::new();
+    machine.consult("color(red, warm). color(blue, cold). color(_, any). color(f(x), odd).").unwrap();
+    assert_eq!(query_answers(&mut machine, "color(blue, T)."), vec!["T = cold", "T = any"]);
+    assert_eq!(query_answers(&mut machine, "color(f(Y), T)."), vec!["T = any", "Y = x, T = odd"]);
+    assert_eq!(query_answers(&mut machine, "color(C, warm)."), vec!["C = red"]);
+    // Only the clause for `red` and the one with a variable are tried
+    let mut solutions = machine.query("color(red, T).");
+    assert!(solutions.next().is_some());
+    assert_eq!(solutions.choicepoints.len(), 1);
+    // Calls that can match a single clause leave no choicepoint
+    machine.consult("size(leaf, 0). size(node(L, R), S) :- size(L, A), size(R, B), S is A + B + 1.").unwrap();
+    let mut solutions = machine.query("size(node(node(leaf, leaf), leaf), S).");
+    assert_eq!(solutions.next().unwrap().unwrap().to_string(), "S = 2");
+    assert!(solutions.choicepoints.is_empty());
+}
+
+#[test]
+fn multi_argument_indexing() {
+    let mut machine = Machine::new();
+    machine.consult("likes(kim, robin). likes(sandy, lee). likes(sandy, kim). likes(robin, cats). likes(lee, sandy).").unwrap();
+    assert!(query_answers(&mut machine, "predicate_property(likes(_, _), indexed(_)).").is_empty());
+    let mut solutions = machine.query("likes(Who, sandy).");
+    assert_eq!(solutions.next().unwrap().unwrap().to_string(), "Who = lee");
+    assert!(solutions.choicepoints.is_empty());
+    drop(solutions);
+    assert_eq!(query_answers(&mut machine, "predicate_property(likes(_, _), indexed(A))."), vec!["A = [2]"]);
+    assert_eq!(query_answers(&mut machine, "likes(sandy, Who)."), vec!["Who = lee", "Who = kim"]);
+    assert_eq!(query_answers(&mut machine, "predicate_property(likes(_, _), indexed(A))."), vec!["A = [1,2]"]);
+    assert_eq!(query_answers(&mut machine, "predicate_property(_P, indexed([1,2])), _P = likes(_, _)."), vec!["true"]);
+    assert_eq!(machine.query("predicate_property(1, P).").next(), Some(Err(type_error("callable", Term::Int(1.into())))));
+}
+
+#[test]
+fn assert_and_retract() {
+    let mut machine = Machine::new();
+    machine.consult(":- dynamic(counter/1). counter(0). increment :- retract(counter(N)), M is N + 1, assert(counter(M)). fixed(a).").unwrap();
+    assert_eq!(query_answers(&mut machine, "increment, increment, counter(N)."), vec!["N = 2"]);
+    assert_eq!(query_answers(&mut machine, "assertz(item(b)), asserta(item(a)), assertz((item(X) :- X = c)), item(Y)."), vec!["Y = a", "Y = b", "Y = c"]);
+    assert_eq!(query_answers(&mut machine, "retract((item(X) :- Body))."), vec!["X = a, Body = true", "X = b, Body = true", "Body = (X=c)"]);
+    assert!(query_answers(&mut machine, "item(X).").is_empty());
+    // The logical update view: a running call doesn't see the changes
+    assert_eq!(query_answers(&mut machine, "assert(n(1)), assert(n(2)), n(X), Y is X + 2, assertz(n(Y)), retract(n(2)), fail ; true."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "n(X)."), vec!["X = 1", "X = 3", "X = 4"]);
+    assert_eq!(query_answers(&mut machine, "abolish(n/1), assert(n(5)), n(X)."), vec!["X = 5"]);
+    let static_procedure = |name: &str, arity| Err(crate::error::permission_error("modify", "static_procedure", indicator(name, arity)));
+    assert_eq!(machine.query("assert(fixed(b)).").next(), Some(static_procedure("fixed", 1)));
+    assert_eq!(machine.query("retract(fixed(a)).").next(), Some(static_procedure("fixed", 1)));
+    assert_eq!(machine.query("abolish(fixed/1).").next(), Some(static_procedure("fixed", 1)));
+    assert_eq!(machine.query("asserta((a = b)).").next(), Some(static_procedure("=", 2)));
+    assert_eq!(machine.query("assert((foo :- 4)).").next(), Some(Err(type_error("callable", crate::parser::clause("x :- (foo :- 4).").unwrap().body[0].clone()))));
+    assert_eq!(machine.query("assert(_).").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("abolish(foo/a).").next(), Some(Err(type_error("integer", Term::Atom("a".into())))));
+    assert!(query_answers(&mut machine, "retract(undefined(_)).").is_empty());
+    // Declared dynamic predicates exist without clauses
+    assert!(query_answers(&mut machine, "dynamic([seen/1]), seen(_).").is_empty());
+}
+
+#[test]
+fn inspect_clauses() {
+    let mut machine = Machine::new();
+    machine.consult("parent(tom, bob). parent(bob, ann). grandparent(X, Z) :- parent(X, Y), parent(Y, Z). :- dynamic(seen/1).").unwrap();
+    assert_eq!(query_answers(&mut machine, "clause(parent(bob, C), B)."), vec!["C = ann, B = true"]);
+    assert_eq!(query_answers(&mut machine, "clause(grandparent(A, B), Body)."), vec!["Body = (parent(A,_G5),parent(_G5,B))"]);
+    assert!(query_answers(&mut machine, "clause(seen(_), _).").is_empty());
+    assert_eq!(machine.query("clause(X, true).").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("clause(f(x), 1).").next(), Some(Err(type_error("callable", Term::Int(1.into())))));
+    let private = Err(crate::error::permission_error("access", "private_procedure", indicator("is", 2)));
+    assert_eq!(machine.query("clause(X is Y, B).").next(), Some(private));
+    assert_eq!(query_answers(&mut machine, "current_predicate(P)."), vec!["P = grandparent/2", "P = parent/2", "P = seen/1"]);
+    assert_eq!(query_answers(&mut machine, "current_predicate(parent/N)."), vec!["N = 2"]);
+    assert!(query_answers(&mut machine, "current_predicate(append/3).").is_empty());
+    assert_eq!(machine.query("current_predicate(4).").next(), Some(Err(type_error("predicate_indicator", Term::Int(4.into())))));
+    assert_eq!(query_answers(&mut machine, "predicate_property(parent(_, _), P)."), vec!["P = static", "P = defined", "P = number_of_clauses(2)", "P = indexed([1])"]);
+    assert_eq!(query_answers(&mut machine, "predicate_property(seen(_), dynamic)."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "predicate_property(_ is _, P)."), vec!["P = built_in", "P = static", "P = defined"]);
+    assert!(query_answers(&mut machine, "predicate_property(undefined, _).").is_empty());
+}
+
+#[test]
+fn all_solutions() {
+    let mut machine = Machine::new();
+    machine.consult("age(peter, 7). age(ann, 11). age(pat, 8). age(tom, 5). age(mike, 11). class(a, peter). class(b, ann). class(a, pat). class(b, tom). class(b, mike).").unwrap();
+    assert_eq!(query_answers(&mut machine, "findall(N-A, age(N, A), L)."), vec!["L = [peter-7,ann-11,pat-8,tom-5,mike-11]"]);
+    assert_eq!(query_answers(&mut machine, "findall(X, fail, L), findall(X, member(X, [a]), M, [b])."), vec!["L = [], M = [a,b]"]);
+    assert_eq!(query_answers(&mut machine, "findall(X, (member(X, [1,2,3]), X > 1, !), L)."), vec!["L = [2]"]);
+    assert_eq!(query_answers(&mut machine, "findall(X-Y, member(X, [A,B]), L)."), vec!["L = [_G15-_G16,_G17-_G18]"]);
+    assert_eq!(query_answers(&mut machine, "member(X, [1,2]), findall(Y, member(Y, [X,3]), L)."), vec!["X = 1, L = [1,3]", "X = 2, L = [2,3]"]);
+    assert_eq!(machine.query("findall(X, G, L).").next(), Some(Err(instantiation_error())));
+    assert_eq!(query_answers(&mut machine, "catch(findall(X, throw(oops), L), E, true)."), vec!["E = oops"]);
+
+    assert_eq!(query_answers(&mut machine, "bagof(N, class(C, N), L)."), vec!["C = a, L = [peter,pat]", "C = b, L = [ann,tom,mike]"]);
+    assert_eq!(query_answers(&mut machine, "bagof(N, C^class(C, N), L)."), vec!["L = [peter,ann,pat,tom,mike]"]);
+    assert_eq!(query_answers(&mut machine, "setof(A-N, age(N, A), L)."), vec!["L = [5-tom,7-peter,8-pat,11-ann,11-mike]"]);
+    assert_eq!(query_answers(&mut machine, "setof(A, N^age(N, A), L)."), vec!["L = [5,7,8,11]"]);
+    assert_eq!(query_answers(&mut machine, "setof(N, A^(age(N, A), A > 7), L)."), vec!["L = [ann,mike,pat]"]);
+    assert!(query_answers(&mut machine, "bagof(X, fail, L).").is_empty());
+
+    assert_eq!(query_answers(&mut machine, "aggregate_all(count, age(_, _), C)."), vec!["C = 5"]);
+    assert_eq!(query_answers(&mut machine, "aggregate_all(sum(A), age(_, A), S), aggregate_all(max(A), age(_, A), M)."), vec!["S = 42, M = 11"]);
+    assert_eq!(query_answers(&mut machine, "aggregate_all(min(A * 2), age(_, A), M)."), vec!["M = 10"]);
+    assert_eq!(query_answers(&mut machine, "aggregate_all(bag(A), age(_, A), B), aggregate_all(set(A), age(_, A), S)."), vec!["B = [7,11,8,5,11], S = [5,7,8,11]"]);
+    assert_eq!(query_answers(&mut machine, "aggregate_all(count, fail, C), aggregate_all(sum(X), fail, S)."), vec!["C = 0, S = 0"]);
+    assert!(query_answers(&mut machine, "aggregate_all(max(X), fail, M).").is_empty());
+    assert_eq!(query_answers(&mut machine, "aggregate_all(max(X-Y), member(X-Y, [1-a, 2-b]), M)."), vec!["M = 2-b"]);
+    assert_eq!(query_answers(&mut machine, "aggregate_all(min(X), member(X, [b, 3, f(a), 2.0]), M)."), vec!["M = 2.0"]);
+    assert_eq!(query_answers(&mut machine, "aggregate_all(max(X), member(X, [b, 3, f(a), 2.0]), M)."), vec!["M = f(a)"]);
+    assert_eq!(machine.query("aggregate_all(avg(X), fail, M).").next(), Some(Err(crate::error::domain_error("aggregate_spec", Term::Str("avg".into(), vec![Term::Var(0)].into())))));
+}
+
+#[test]
+fn compare_and_sort() {
+    let mut machine = Machine::new();
+    machine.consult("by_length(O, X, Y) :- length(X, N), length(Y, M), compare(O, N, M). never(_, _, _) :- fail.").unwrap();
+    assert_eq!(query_answers(&mut machine, "compare(O, 1, 1.0), compare(P, f(a), g), compare(Q, X, X)."), vec!["O = >, P = >, Q = ="]);
+    assert_eq!(query_answers(&mut machine, "X == X, X \\== Y, a @< b, f(b) @> f(a), 1 @=< 1, Y @>= X."), vec!["true"]);
+    assert!(query_answers(&mut machine, "X == Y.").is_empty());
+    assert!(query_answers(&mut machine, "1 == 1.0.").is_empty());
+    assert_eq!(machine.query("compare(foo, a, b).").next(), Some(Err(crate::error::domain_error("order", Term::Atom("foo".into())))));
+    assert_eq!(machine.query("compare(1, a, b).").next(), Some(Err(type_error("atom", Term::Int(1.into())))));
+
+    assert_eq!(query_answers(&mut machine, "sort([c,a,b,a,1,f(x)], S), msort([c,a,b,a], M)."), vec!["S = [1,a,b,c,f(x)], M = [a,a,b,c]"]);
+    assert_eq!(query_answers(&mut machine, "keysort([b-1,a-2,b-0,a-1], S)."), vec!["S = [a-2,a-1,b-1,b-0]"]);
+    assert_eq!(query_answers(&mut machine, "sort(1, @>=, [f(1,a),f(2,b),f(1,c)], S)."), vec!["S = [f(2,b),f(1,a),f(1,c)]"]);
+    assert_eq!(query_answers(&mut machine, "sort(1, @<, [f(1,a),f(2,b),f(1,c)], S)."), vec!["S = [f(1,a),f(2,b)]"]);
+    assert_eq!(query_answers(&mut machine, "sort(0, @>, [b,a,c,a], S)."), vec!["S = [c,b,a]"]);
+    assert_eq!(query_answers(&mut machine, "predsort(by_length, [[a,b],[c],[d,e,f],[g]], S)."), vec!["S = [[c],[a,b],[d,e,f]]"]);
+    assert!(query_answers(&mut machine, "predsort(never, [b,a], S).").is_empty());
+    assert_eq!(machine.query("sort([a|_], S).").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("sort([a], foo).").next(), Some(Err(type_error("list", Term::Atom("foo".into())))));
+    assert_eq!(machine.query("keysort([a], S).").next(), Some(Err(type_error("pair", Term::Atom("a".into())))));
+    assert_eq!(machine.query("sort(1, @<, [a], S).").next(), Some(Err(type_error("compound", Term::Atom("a".into())))));
+    assert_eq!(machine.query("sort(0, foo, [a], S).").next(), Some(Err(crate::error::domain_error("order", Term::Atom("foo".into())))));
+}
+
+#[test]
+fn inspect_terms() {
+    use crate::error::domain_error;
+    let mut machine = Machine::new();
+    assert_eq!(query_answers(&mut machine, "functor(f(a, b), N, A), functor(foo, M, B), functor(1.5, O, C)."), vec!["N = f, A = 2, M = foo, B = 0, O = 1.5, C = 0"]);
+    assert_eq!(query_answers(&mut machine, "functor(T, f, 2), functor(U, 7, 0)."), vec!["T = f(_G2,_G3), U = 7"]);
+    assert_eq!(machine.query("functor(T, N, 2).").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("functor(T, f(a), 1).").next(), Some(Err(type_error("atomic", Term::Str("f".into(), vec![Term::Atom("a".into())].into())))));
+    assert_eq!(machine.query("functor(T, 1, 1).").next(), Some(Err(type_error("atom", Term::Int(1.into())))));
+    assert_eq!(machine.query("functor(T, f, -1).").next(), Some(Err(domain_error("not_less_than_zero", Term::Int((-1).into())))));
+
+    assert_eq!(query_answers(&mut machine, "arg(2, f(a, b), X)."), vec!["X = b"]);
+    assert_eq!(query_answers(&mut machine, "arg(N, f(a, b), X)."), vec!["N = 1, X = a", "N = 2, X = b"]);
+    assert!(query_answers(&mut machine, "arg(3, f(a, b), X).").is_empty());
+    assert_eq!(machine.query("arg(1, foo, X).").next(), Some(Err(type_error("compound", Term::Atom("foo".into())))));
+    assert_eq!(machine.query("arg(a, f(a), X).").next(), Some(Err(type_error("integer", Term::Atom("a".into())))));
+
+    assert_eq!(query_answers(&mut machine, "f(a, B) =.. L, foo =.. M, T =.. [g, 1], U =.. [3]."), vec!["L = [f,a,B], M = [foo], T = g(1), U = 3"]);
+    assert_eq!(machine.query("T =.. [].").next(), Some(Err(domain_error("non_empty_list", Term::Atom("[]".into())))));
+    assert_eq!(machine.query("T =.. [f|_].").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("T =.. [1, a].").next(), Some(Err(type_error("atom", Term::Int(1.into())))));
+
+    assert_eq!(query_answers(&mut machine, "X = f(A, B, A), copy_term(X, Y), Y = f(1, 2, Z)."), vec!["X = f(A,B,A), Y = f(1,2,1), Z = 1"]);
+    assert_eq!(query_answers(&mut machine, "term_variables(f(X, g(Y, X), _), Vs)."), vec!["Vs = [X,Y,_G2]"]);
+
+    assert_eq!(query_answers(&mut machine, "T = f(a, b), setarg(1, T, c)."), vec!["T = f(c,b)"]);
+    assert_eq!(query_answers(&mut machine, "T = f(a), (setarg(1, T, b), fail ; true)."), vec!["T = f(a)"]);
+    assert_eq!(query_answers(&mut machine, "T = count(0), (member(_, [x,y,z]), arg(1, T, N0), N is N0 + 1, nb_setarg(1, T, N), fail ; true)."), vec!["T = count(3)"]);
+    assert!(query_answers(&mut machine, "T = f(a), setarg(2, T, b).").is_empty());
+}
+
+#[test]
+fn unification_and_type_checks() {
+    use crate::error::domain_error;
+    let mut machine = machine_from("p(X, X).");
+    assert_eq!(query_answers(&mut machine, "f(X, b) = f(a, Y)."), vec!["X = a, Y = b"]);
+    assert_eq!(query_answers(&mut machine, "f(X, b) \\= f(a, c), \\+ X \\= a, var(X)."), vec!["true"]);
+    assert!(query_answers(&mut machine, "unify_with_occurs_check(X, f(X)).").is_empty());
+    assert_eq!(query_answers(&mut machine, "unify_with_occurs_check(f(X, Y), f(Y, g(Z)))."), vec!["X = g(Z), Y = g(Z)"]);
+
+    assert_eq!(query_answers(&mut machine, "set_prolog_flag(occurs_check, true), current_prolog_flag(occurs_check, F)."), vec!["F = true"]);
+    assert!(query_answers(&mut machine, "X = f(X).").is_empty());
+    assert!(query_answers(&mut machine, "p(Y, f(Y)).").is_empty());
+    assert_eq!(query_answers(&mut machine, "f(X, b) \\= f(c, X), X \\= f(X)."), vec!["true"]);
+    assert_eq!(query_answers(&mut machine, "set_prolog_flag(occurs_check, error), catch(p(Y, f(Y)), error(occurs_check(V, T), _), true), T == f(V)."), vec!["T = f(V)"]);
+    assert_eq!(query_answers(&mut machine, "catch(X = g(a, X), error(occurs_check(V, T), _), true), T == g(a, V)."), vec!["T = g(a,V)"]);
+    assert_eq!(query_answers(&mut machine, "p(Y, g(Z)), set_prolog_flag(occurs_check, false)."), vec!["Y = g(Z)"]);
+    assert_eq!(machine.query("set_prolog_flag(occurs_check, maybe).").next(), Some(Err(domain_error("flag_value", Term::Str("+".into(), vec![Term::Atom("occurs_check".into()), Term::Atom("maybe".into())].into())))));
+
+    assert_eq!(query_answers(&mut machine, "var(X), nonvar(a), atom(a), atom([]), number(1), number(1.5), integer(1), float(1.5), atomic(a), compound(f(x)), callable(a), callable(f(x)), is_list([a, b]), is_list([]), ground(f(a, [b]))."), vec!["true"]);
+    assert!(query_answers(&mut machine, "X = a, var(X).").is_empty());
+    assert!(query_answers(&mut machine, "nonvar(_).").is_empty());
+    assert!(query_answers(&mut machine, "atom(1) ; atom(f(x)) ; number(a) ; integer(1.5) ; float(1) ; atomic(f(x)) ; compound(a) ; callable(1) ; callable(_).").is_empty());
+    assert!(query_answers(&mut machine, "is_list([a|_]) ; is_list([a|b]) ; is_list(_) ; ground(f(_)).").is_empty());
+    assert_eq!(query_answers(&mut machine, "L = [a|T], T = [], is_list(L), X = f(Y), Y = 1, ground(X)."), vec!["L = [a], T = [], X = f(1), Y = 1"]);
+}
+
+#[test]
+fn text_builtins() {
+    use crate::error::{domain_error, representation_error, syntax_error};
+    let mut machine = Machine::new();
+    assert_eq!(query_answers(&mut machine, "atom_codes(abc, L), atom_codes(A, [0'h, 0'i]), atom_codes(12, M)."), vec!["L = [97,98,99], A = hi, M = [49,50]"]);
+    assert_eq!(query_answers(&mut machine, "atom_chars('añb', L), atom_chars(A, [x, y])."), vec!["L = [a,ñ,b], A = xy"]);
+    assert_eq!(machine.query("atom_codes(A, [0'a|_]).").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("atom_chars(A, [a, bc]).").next(), Some(Err(type_error("character", Term::Atom("bc".into())))));
+    assert_eq!(query_answers(&mut machine, "char_code(a, X), char_code(Y, 0'b)."), vec!["X = 97, Y = b"]);
+    assert_eq!(machine.query("char_code(C, -1).").next(), Some(Err(representation_error("character_code"))));
+    assert_eq!(query_answers(&mut machine, "atom_length('añb', N), atom_length('', M), atom_length(123, O)."), vec!["N = 3, M = 0, O = 3"]);
+    assert_eq!(machine.query("atom_length(A, 1).").next(), Some(Err(instantiation_error())));
+    assert_eq!(machine.query("atom_length(abc, -1).").next(), Some(Err(domain_error("not_less_than_zero", Term::Int((-1).into())))));
+
+    assert_eq!(query_answers(&mut machine, "atom_concat(abc, def, X), atom_concat(X, 1, Y)."), vec!["X = abcdef, Y = abcdef1"]);
+    assert_eq!(query_answers(&mut machine, "atom_concat(X, Y, ab)."), vec!["X = '', Y = ab", "X = a, Y = b", "X = ab, Y = ''"]);
+    assert_eq!(query_answers(&mut machine, "atom_concat(X, c, abc)."), vec!["X = ab"]);
+    assert_eq!(query_answers(&mut machine, "sub_atom(abcab, B, 2, A, ab)."), vec!["B = 0, A = 3", "B = 3, A = 0"]);
+    assert_eq!(query_answers(&mut machine, "sub_atom(abc, 1, L, 0, S)."), vec!["L = 2, S = bc"]);
+    assert_eq!(query_answers(&mut machine, "findall(S, sub_atom(abc, _, _, _, S), L)."), vec!["L = ['',a,ab,abc,'',b,bc,'',c,'']"]);
+    assert_eq!(query_answers(&mut machine, "upcase_atom('hello World', U)."), vec!["U = 'HELLO WORLD'"]);
+
+    assert_eq!(query_answers(&mut machine, "number_codes(N, \" 42\"), number_codes(1.5, L), number_codes(M, \"-7\")."), vec!["N = 42, L = [49,46,53], M = -7"]);
+    assert_eq!(machine.query("number_codes(N, \"4a\").").next(), Some(Err(syntax_error("operator expected, found atom a"))));
+    assert_eq!(machine.query("number_codes(N, \"foo\").").next(), Some(Err(syntax_error("illegal_number"))));
+    assert_eq!(query_answers(&mut machine, "atom_number('3.25', N), atom_number(A, 7), atom_number('0x1F', H)."), vec!["N = 3.25, A = '7', H = 31"]);
+    assert!(query_answers(&mut machine, "atom_number(foo, N).").is_empty());
+
+    assert_eq!(query_answers(&mut machine, "atomic_list_concat([a, 1, 'B'], X), atomic_list_concat([a, b, c], '-', Y)."), vec!["X = a1B, Y = 'a-b-c'"]);
+    assert_eq!(query_answers(&mut machine, "atomic_list_concat(L, ',', 'a,b,,c'), atomic_list_concat([x|T], '/', 'x/y')."), vec!["L = [a,b,'',c], T = [y]"]);
+    assert_eq!(machine.query("atomic_list_concat(L, '', abc).").next(), Some(Err(domain_error("non_empty_atom", Term::Atom("".into())))));
+    assert_eq!(machine.query("atomic_list_concat([a, _], X).").next(), Some(Err(instantiation_error())));
+
+    assert_eq!(query_answers(&mut machine, "term_to_atom(f(X, 'A', [1, 2], a + b * c), A)."), vec!["A = 'f(_G0,\\'A\\',[1,2],a+b*c)'"]);
+    assert_eq!(query_answers(&mut machine, "term_to_atom(T, 'foo(X, Y, X)'), T = foo(1, 2, Z)."), vec!["T = foo(1,2,1), Z = 1"]);
+    assert_eq!(machine.query("term_to_atom(T, 'foo(').").next(), Some(Err(syntax_error("term expected, found end of clause"))));
+}
+
+#[test]
+fn sub_atom_lazily() {
+    let mut machine = Machine::new();
+    assert_eq!(query_answers(&mut machine, "sub_atom(aaaa, B, L, A, aa)."), vec!["B = 0, L = 2, A = 2", "B = 1, L = 2, A = 1", "B = 2, L = 2, A = 0"]);
+    assert_eq!(query_answers(&mut machine, "sub_atom(abc, B, 1, 1, S)."), vec!["B = 1, S = b"]);
+    assert!(query_answers(&mut machine, "sub_atom(abc, B, L, 5, S).").is_empty());
+    assert!(query_answers(&mut machine, "sub_atom(abc, B, 9223372036854775807, 9223372036854775807, S).").is_empty());
+    assert!(query_answers(&mut machine, "sub_atom(abc, 1, 9223372036854775807, A, S).").is_empty());
+    // Two bound positions or the last occurrence of Sub leave no choicepoint
+    for query in ["sub_atom(abcab, 1, 2, A, S).", "sub_atom(abcab, B, L, 0, ab)."] {
+	let mut solutions = machine.query(query);
+	assert!(solutions.next().unwrap().is_ok());
+	assert!(solutions.choicepoints.is_empty());
+    }
+    // The answers of a long atom are not made all at once
+    let long = "a".repeat(100_000);
+    let answers = query_answers(&mut machine, &format!("sub_atom('{}x', B, _, 0, x).", long));
+    assert_eq!(answers, vec!["B = 100000"]);
+    let mut solutions = machine.query(&format!("sub_atom('{}', B, L, A, S), L > 1.", long));
+    assert_eq!(solutions.next().unwrap().unwrap().get("A"), Some(&Term::Int(99_998.into())));
+}
+
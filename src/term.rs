@@ -1,29 +1,565 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::atom::Atom;
+use crate::integer::Integer;
+use crate::ops::Operators;
 
 #[derive(Debug, Clone)]
 pub enum Term {
-    Atom(String),
-    Var(String),
-    Str(String, Vec<Term>),
+    Atom(Atom),
+    /// A variable, identified by a number. The variables of a clause are
+    /// numbered from 0, and renamed to fresh numbers every time it is used.
+    Var(usize),
+    Int(Integer),
+    Float(f64),
+    Str(Atom, Args),
+}
+
+/// The arguments of a compound term. They are shared, so copying a term is
+/// cheap however big it is.
+#[derive(Clone, Default)]
+pub struct Args(Rc<Vec<Term>>);
+
+impl Deref for Args {
+    type Target = [Term];
+
+    fn deref(&self) -> &[Term] {
+	&self.0
+    }
+}
+
+impl From<Vec<Term>> for Args {
+    fn from(args: Vec<Term>) -> Self {
+	Args(Rc::new(args))
+    }
+}
+
+impl Args {
+    /// The address of the arguments, which is the same for every copy of
+    /// them
+    pub(crate) fn as_ptr(&self) -> *const Vec<Term> {
+	Rc::as_ptr(&self.0)
+    }
+
+    /// The arguments as a vector, without copying them if they aren't shared
+    pub fn into_vec(mut self) -> Vec<Term> {
+	match Rc::get_mut(&mut self.0) {
+	    Some(args) => std::mem::take(args),
+	    None => self.0.to_vec(),
+	}
+    }
+}
+
+impl fmt::Debug for Args {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	self.0.fmt(f)
+    }
+}
+
+/// Dropping a long list would otherwise recurse once per element and
+/// overflow the stack, so the arguments that are not shared are moved to an
+/// explicit stack and dropped one by one.
+impl Drop for Args {
+    fn drop(&mut self) {
+	let mut pending = match Rc::get_mut(&mut self.0) {
+	    Some(args) => std::mem::take(args),
+	    None => return,
+	};
+	while let Some(term) = pending.pop() {
+	    if let Term::Str(_, mut args) = term {
+		if let Some(args) = Rc::get_mut(&mut args.0) {
+		    pending.append(args);
+		}
+	    }
+	}
+    }
 }
 
 impl PartialEq for Term {
     fn eq(&self, other: &Self) -> bool {
-	match (self, other) {
-	    (Term::Atom(x), Term::Atom(y)) => x == y,
-	    (Term::Var(x), Term::Var(y)) => x == y,
-	    (Term::Str(f_x, args_x), Term::Str(f_y, args_y)) => f_x == f_y && args_x == args_y,
-	    _ => false
+	let mut pending = vec![(self, other)];
+	while let Some(pair) = pending.pop() {
+	    let equal = match pair {
+		(Term::Atom(x), Term::Atom(y)) => x == y,
+		(Term::Var(x), Term::Var(y)) => x == y,
+		(Term::Int(x), Term::Int(y)) => x == y,
+		(Term::Float(x), Term::Float(y)) => x.to_bits() == y.to_bits(),
+		(Term::Str(f_x, args_x), Term::Str(f_y, args_y)) => {
+		    if Rc::ptr_eq(&args_x.0, &args_y.0) {
+			f_x == f_y
+		    } else {
+			pending.extend(args_x.iter().zip(args_y.iter()));
+			f_x == f_y && args_x.len() == args_y.len()
+		    }
+		}
+		_ => false,
+	    };
+	    if !equal {
+		return false;
+	    }
 	}
+	true
     }
 }
 
+impl Eq for Term {}
+
+/// The standard order of terms: variables first, then numbers by value,
+/// atoms alphabetically and compound terms by arity, name and arguments. A
+/// float comes before an integer with the same value. The terms must have
+/// their bindings substituted.
+impl Ord for Term {
+    fn cmp(&self, other: &Self) -> Ordering {
+	let mut pending = vec![(self, other)];
+	while let Some(pair) = pending.pop() {
+	    let ordering = match pair {
+		(Term::Var(x), Term::Var(y)) => x.cmp(y),
+		(Term::Int(x), Term::Int(y)) => x.cmp(y),
+		(Term::Float(x), Term::Float(y)) => x.total_cmp(y),
+		(Term::Float(x), Term::Int(y)) => x.partial_cmp(&y.to_f64()).unwrap_or(Ordering::Equal).then(Ordering::Less),
+		(Term::Int(x), Term::Float(y)) => x.to_f64().partial_cmp(y).unwrap_or(Ordering::Equal).then(Ordering::Greater),
+		(Term::Atom(x), Term::Atom(y)) if x == y => Ordering::Equal,
+		(Term::Atom(x), Term::Atom(y)) => x.name().cmp(&y.name()),
+		(Term::Str(f_x, args_x), Term::Str(f_y, args_y)) => {
+		    let ordering = args_x.len().cmp(&args_y.len())
+			.then_with(|| if f_x == f_y { Ordering::Equal } else { f_x.name().cmp(&f_y.name()) });
+		    pending.extend(args_x.iter().zip(args_y.iter()).rev());
+		    ordering
+		}
+		(x, y) => rank(x).cmp(&rank(y)),
+	    };
+	    if ordering != Ordering::Equal {
+		return ordering;
+	    }
+	}
+	Ordering::Equal
+    }
+}
+
+impl PartialOrd for Term {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+	Some(self.cmp(other))
+    }
+}
+
+/// The position of the kind of a term in the standard order
+fn rank(term: &Term) -> u8 {
+    match term {
+	Term::Var(_) => 0,
+	Term::Int(_) | Term::Float(_) => 1,
+	Term::Atom(_) => 2,
+	Term::Str(_, _) => 3,
+    }
+}
+
+/// Whether two terms are equal but for the names of their variables, like
+/// `f(X, Y, X)` and `f(A, B, A)`
+pub fn is_variant(x: &Term, y: &Term) -> bool {
+    let mut renamed = HashMap::new();
+    let mut renamed_back = HashMap::new();
+    let mut pending = vec![(x, y)];
+    while let Some(pair) = pending.pop() {
+	let variant = match pair {
+	    (Term::Var(x), Term::Var(y)) => *renamed.entry(*x).or_insert(*y) == *y && *renamed_back.entry(*y).or_insert(*x) == *x,
+	    (Term::Str(f_x, args_x), Term::Str(f_y, args_y)) if f_x == f_y && args_x.len() == args_y.len() => {
+		pending.extend(args_x.iter().zip(args_y.iter()));
+		true
+	    }
+	    (Term::Str(_, _), _) | (Term::Var(_), _) => false,
+	    (x, y) => x == y,
+	};
+	if !variant {
+	    return false;
+	}
+    }
+    true
+}
+
 impl fmt::Display for Term {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-	match self {
-	    Term::Atom(x) => write!(f, "{}", x),
-	    Term::Var(x) => write!(f, "{}", x),
-	    Term::Str(functor, args) => write!(f, "{}({})", functor, args.iter().map(|t| format!("{}", t)).collect::<Vec<String>>().join(","))
+	write!(f, "{}", write_term(self, Operators::default_table(), 1200))
+    }
+}
+
+/// Writes a term in standard syntax, using operators and list notation and
+/// quoting atoms when needed, so it can be read back. `max` is the highest
+/// priority the term can have without being parenthesized.
+pub fn write_term(term: &Term, ops: &Operators, max: u32) -> String {
+    Writer { ops, names: &[] }.write(term, max)
+}
+
+/// Like `write_term`, writing the variables in `names` with their name
+/// instead of `_G` and their number
+pub fn write_term_with_names(term: &Term, ops: &Operators, max: u32, names: &[(String, usize)]) -> String {
+    Writer { ops, names }.write(term, max)
+}
+
+/// Writes a clause as source text, the way `listing/1` shows it: the body
+/// goes on its own indented lines, and variables are named `A`, `B`... or
+/// `_` if they appear only once.
+pub fn portray_clause(clause: &Term, ops: &Operators) -> String {
+    let names = clause_variable_names(clause);
+    let writer = Writer { ops, names: &names };
+    match clause {
+	Term::Str(name, args) if name == ":-" && args.len() == 2 => match &args[1] {
+	    Term::Atom(body) if body == "true" => format!("{}.", writer.write(&args[0], 1199)),
+	    body => format!("{} :-\n    {}.", writer.write(&args[0], 1199), writer.body(body, 4)),
+	},
+	Term::Str(name, args) if name == ":-" && args.len() == 1 => format!(":- {}.", writer.write(&args[0], 1199)),
+	head => format!("{}.", writer.write(head, 1199)),
+    }
+}
+
+/// Names the variables of a clause in the order they appear
+fn clause_variable_names(clause: &Term) -> Vec<(String, usize)> {
+    let mut occurrences: Vec<(usize, usize)> = Vec::new();
+    let mut pending = vec![clause];
+    while let Some(term) = pending.pop() {
+	match term {
+	    Term::Var(var) => match occurrences.iter_mut().find(|(other, _)| other == var) {
+		Some((_, count)) => *count += 1,
+		None => occurrences.push((*var, 1)),
+	    },
+	    Term::Str(_, args) => pending.extend(args.iter().rev()),
+	    _ => (),
+	}
+    }
+    let mut named = 0;
+    occurrences.into_iter()
+	.map(|(var, count)| {
+	    if count == 1 {
+		return ("_".to_string(), var);
+	    }
+	    let letter = (b'A' + (named % 26) as u8) as char;
+	    let name = match named / 26 {
+		0 => letter.to_string(),
+		n => format!("{}{}", letter, n),
+	    };
+	    named += 1;
+	    (name, var)
+	})
+	.collect()
+}
+
+struct Writer<'a> {
+    ops: &'a Operators,
+    names: &'a [(String, usize)],
+}
+
+/// A step of writing a term with an explicit stack. The steps after
+/// `Visit` join the texts of the subterms written last.
+enum WriteStep<'t> {
+    /// Write this term with at most this priority
+    Visit(&'t Term, u32),
+    /// An infix operator with its priority and the maximum one of the term
+    Infix(Rc<str>, u32, u32),
+    Prefix(Rc<str>, u32, u32),
+    Postfix(Rc<str>, u32, u32),
+    /// A compound term in canonical form with this many arguments
+    Canonical(Rc<str>, usize),
+    Curly,
+    /// A list with this many elements, and whether it has a tail that
+    /// is not `[]`
+    List(usize, bool),
+}
+
+impl Writer<'_> {
+    /// Writes a term with an explicit stack, so terms as deep as a long
+    /// list don't exhaust the Rust stack
+    fn write(&self, term: &Term, max: u32) -> String {
+	let ops = self.ops;
+	let mut pending = vec![WriteStep::Visit(term, max)];
+	let mut written: Vec<String> = Vec::new();
+	while let Some(step) = pending.pop() {
+	    let text = match step {
+		WriteStep::Visit(term, max) => match term {
+		    Term::Atom(name) => quote_atom(&name.name()),
+		    Term::Var(id) => match self.names.iter().find(|(_, var)| var == id) {
+			Some((name, _)) => name.clone(),
+			None => format!("_G{}", id),
+		    },
+		    Term::Int(x) => x.to_string(),
+		    Term::Float(x) => format_float(*x),
+		    Term::Str(name, args) if name == "." && args.len() == 2 => {
+			let mut elements = Vec::new();
+			let mut rest = term;
+			while let Term::Str(name, args) = rest {
+			    if name != "." || args.len() != 2 {
+				break;
+			    }
+			    elements.push(&args[0]);
+			    rest = &args[1];
+			}
+			let tail = match rest {
+			    Term::Atom(name) if name == "[]" => None,
+			    tail => Some(tail),
+			};
+			pending.push(WriteStep::List(elements.len(), tail.is_some()));
+			pending.extend(tail.map(|tail| WriteStep::Visit(tail, 999)));
+			pending.extend(elements.into_iter().rev().map(|element| WriteStep::Visit(element, 999)));
+			continue;
+		    }
+		    Term::Str(name, args) if name == "{}" && args.len() == 1 => {
+			pending.push(WriteStep::Curly);
+			pending.push(WriteStep::Visit(&args[0], 1200));
+			continue;
+		    }
+		    Term::Str(name, args) => {
+			let name = name.name();
+			match (&args[..], ops.infix(&name), ops.prefix(&name), ops.postfix(&name)) {
+			    ([left, right], Some(op), _, _) => {
+				pending.push(WriteStep::Infix(name, op.priority, max));
+				pending.push(WriteStep::Visit(right, op.right_max()));
+				pending.push(WriteStep::Visit(left, op.left_max()));
+			    }
+			    ([arg], _, Some(op), _) => {
+				pending.push(WriteStep::Prefix(name, op.priority, max));
+				pending.push(WriteStep::Visit(arg, op.right_max()));
+			    }
+			    ([arg], _, None, Some(op)) => {
+				pending.push(WriteStep::Postfix(name, op.priority, max));
+				pending.push(WriteStep::Visit(arg, op.left_max()));
+			    }
+			    _ => {
+				pending.push(WriteStep::Canonical(name, args.len()));
+				pending.extend(args.iter().rev().map(|arg| WriteStep::Visit(arg, 999)));
+			    }
+			}
+			continue;
+		    }
+		},
+		WriteStep::Infix(name, priority, max) => {
+		    let right = written.pop().expect("written operand");
+		    let left = written.pop().expect("written operand");
+		    parenthesize(join_operator(&left, &name, &right), priority, max)
+		}
+		WriteStep::Prefix(name, priority, max) => {
+		    let arg = written.pop().expect("written operand");
+		    // -(1) is not the number -1, and -(2**2) is not (-2)**2
+		    if (&*name == "-" || &*name == "+") && arg.starts_with(|c: char| c.is_ascii_digit()) {
+			format!("{}({})", quote_atom(&name), arg)
+		    } else {
+			let separate = is_alphanumeric_atom(&name) || arg.starts_with(is_symbol_char) || arg.starts_with('(');
+			parenthesize(format!("{}{}{}", quote_atom(&name), if separate { " " } else { "" }, arg), priority, max)
+		    }
+		}
+		WriteStep::Postfix(name, priority, max) => {
+		    let arg = written.pop().expect("written operand");
+		    parenthesize(format!("{}{}", arg, quote_atom(&name)), priority, max)
+		}
+		WriteStep::Canonical(name, arity) => {
+		    let args = written.split_off(written.len() - arity);
+		    format!("{}({})", quote_atom(&name), args.join(","))
+		}
+		WriteStep::Curly => format!("{{{}}}", written.pop().expect("written argument")),
+		WriteStep::List(count, has_tail) => {
+		    let tail = if has_tail { written.pop() } else { None };
+		    let elements = written.split_off(written.len() - count);
+		    match tail {
+			Some(tail) => format!("[{}|{}]", elements.join(","), tail),
+			None => format!("[{}]", elements.join(",")),
+		    }
+		}
+	    };
+	    written.push(text);
+	}
+	written.pop().expect("written term")
+    }
+
+    /// Writes the body of a clause with its goals on separate lines, and
+    /// if-then-else and disjunctions laid out as blocks. The lines after
+    /// the first are indented by `indent` spaces.
+    fn body(&self, term: &Term, indent: usize) -> String {
+	match term {
+	    Term::Str(name, args) if name == "," && args.len() == 2 => {
+		format!("{},\n{}{}", self.body(&args[0], indent), " ".repeat(indent), self.body(&args[1], indent))
+	    }
+	    Term::Str(name, args) if (name == ";" || name == "->" || name == "*->") && args.len() == 2 => {
+		let mut branches = Vec::new();
+		let mut rest = term;
+		while let Term::Str(name, args) = rest {
+		    if name != ";" || args.len() != 2 {
+			break;
+		    }
+		    branches.push(&args[0]);
+		    rest = &args[1];
+		}
+		branches.push(rest);
+		let margin = " ".repeat(indent);
+		let branches: Vec<String> = branches.into_iter().map(|branch| self.branch(branch, indent)).collect();
+		format!("(   {}\n{})", branches.join(&format!("\n{};   ", margin)), margin)
+	    }
+	    goal => self.write(goal, 999),
+	}
+    }
+
+    /// A branch of a disjunction, starting after its `(` or `;`
+    fn branch(&self, term: &Term, indent: usize) -> String {
+	match term {
+	    Term::Str(name, args) if (name == "->" || name == "*->") && args.len() == 2 => {
+		let (condition, then) = (self.body(&args[0], indent + 4), self.body(&args[1], indent + 4));
+		format!("{}\n{}{:<4}{}", condition, " ".repeat(indent), &*name.name(), then)
+	    }
+	    goal => self.body(goal, indent + 4),
+	}
+    }
+}
+
+fn parenthesize(text: String, priority: u32, max: u32) -> String {
+    if priority > max { format!("({})", text) } else { text }
+}
+
+/// Joins the operands of an infix operator. Alphanumeric operators need
+/// spaces, and symbolic ones too when they would merge with an operand.
+fn join_operator(left: &str, name: &str, right: &str) -> String {
+    if name == "," {
+	format!("{},{}", left, right)
+    } else if is_alphanumeric_atom(name) || name == "->" || name == ":-" || name == "-->" || name == ";" || name == "|" {
+	format!("{} {} {}", left, name, right)
+    } else {
+	let name = quote_atom(name);
+	let space_left = if left.ends_with(is_symbol_char) { " " } else { "" };
+	let space_right = if right.starts_with(is_symbol_char) { " " } else { "" };
+	format!("{}{}{}{}{}", left, space_left, name, space_right, right)
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    "#$&*+-./:<=>?@^~\\".contains(c)
+}
+
+fn is_alphanumeric_atom(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+	Some(c) if c.is_lowercase() => chars.all(|c| c.is_alphanumeric() || c == '_'),
+	_ => false,
+    }
+}
+
+/// Quotes an atom when it can't be read back as written
+pub fn quote_atom(name: &str) -> String {
+    // `.` alone ends a clause and `/*` starts a comment
+    let plain = is_alphanumeric_atom(name)
+	|| (!name.is_empty() && name.chars().all(is_symbol_char) && name != "." && !name.starts_with("/*"))
+	|| matches!(name, "[]" | "!" | ";" | "{}");
+    if plain {
+	return name.to_string();
+    }
+    let mut quoted = String::from("'");
+    for c in name.chars() {
+	match c {
+	    '\'' => quoted.push_str("\\'"),
+	    '\\' => quoted.push_str("\\\\"),
+	    '\n' => quoted.push_str("\\n"),
+	    '\t' => quoted.push_str("\\t"),
+	    c => quoted.push(c),
+	}
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Floats are always written with a fractional part, so they can't be read
+/// back as integers: `1.0`, `1.0e20`, `1.5e-7`
+pub fn format_float(x: f64) -> String {
+    if x.is_nan() {
+	"nan".into()
+    } else if x.is_infinite() {
+	if x > 0.0 { "inf".into() } else { "-inf".into() }
+    } else {
+	let text = format!("{:?}", x);
+	match text.find('e') {
+	    Some(exp) if !text[..exp].contains('.') => format!("{}.0{}", &text[..exp], &text[exp..]),
+	    _ => text,
 	}
     }
 }
+
+#[test]
+fn write_operators() {
+    let read = |input: &str| {
+	let mut parser = crate::parser::Parser::new(input);
+	let term = parser.read_term(Operators::default_table()).unwrap().unwrap();
+	(term, parser.variable_names().to_vec())
+    };
+    let roundtrip = |input: &str, expected: &str| {
+	let (term, names) = read(input);
+	assert_eq!(write_term_with_names(&term, Operators::default_table(), 1200, &names), expected);
+	assert_eq!(read(&format!("{}.", expected)).0, term);
+    };
+
+    roundtrip("a :- b, c ; d -> e.", "a :- b,c ; d -> e");
+    roundtrip("X = 1 + 2 * 3.", "X=1+2*3");
+    roundtrip("X = (1 + 2) * 3.", "X=(1+2)*3");
+    roundtrip("X = 1 - (2 - 3).", "X=1-(2-3)");
+    roundtrip("X = 1 - -1.", "X=1- -1");
+    roundtrip("X = - (1).", "X= -(1)");
+    roundtrip("X = -(2 ** 2).", "X= -(2**2)");
+    roundtrip("X = -(1.5 + a).", "X= - (1.5+a)");
+    roundtrip("X = 1 - -(2 ** 2).", "X=1- -(2**2)");
+    roundtrip("X = f('|', '.', '/*', '/**/', '*/').", "X=f('|','.','/*','/**/',*/)");
+    roundtrip("X = -(-(a)).", "X= - -a");
+    roundtrip("X = (\\+ a).", "X=(\\+a)");
+    roundtrip("X = \\ a.", "X= \\a");
+    roundtrip("X is Y mod 2.", "X is Y mod 2");
+    roundtrip("X = [a, 'B', \"c\" | T].", "X=[a,'B',[99]|T]");
+    roundtrip("X = f((a, b), 'hello world', [], {x}).", "X=f((a,b),'hello world',[],{x})");
+    roundtrip("X = ','(a).", "X=','(a)");
+    roundtrip("X = 'it''s'.", "X='it\\'s'");
+    roundtrip("X = (a :- b).", "X=(a :- b)");
+    assert_eq!(read("f(X, _, Y).").0.to_string(), "f(_G0,_G1,_G2)");
+}
+
+#[test]
+fn write_deep_terms() {
+    let mut list = Term::Atom("[]".into());
+    let mut nested = Term::Atom("a".into());
+    for i in 0..20000 {
+	list = Term::Str(".".into(), vec![list, Term::Int(i.into())].into());
+	nested = Term::Str("-".into(), vec![nested, Term::Int(1.into())].into());
+    }
+    let text = list.to_string();
+    assert!(text.starts_with("[[[[") && text.ends_with("]|19998]|19999]"));
+    let text = nested.to_string();
+    assert!(text.starts_with("a-1-1-") && text.len() == 1 + 2 * 20000);
+}
+
+#[test]
+fn portray_clauses() {
+    let portray = |input: &str| {
+	let term = crate::parser::Parser::new(input).read_term(Operators::default_table()).unwrap().unwrap();
+	portray_clause(&term, Operators::default_table())
+    };
+    assert_eq!(portray("f('A', [X|_], \"b\")."), "f('A',[_|_],[98]).");
+    assert_eq!(portray("p(X, Y) :- q(X, Z), r(Z, Y), !."), "p(A,B) :-\n    q(A,C),\n    r(C,B),\n    !.");
+    assert_eq!(portray("p(X) :- true."), "p(_).");
+    assert_eq!(portray("max(X, Y, Z) :- ( X >= Y -> Z = X ; Z = Y )."), "max(A,B,C) :-\n    (   A>=B\n    ->  C=A\n    ;   C=B\n    ).");
+    assert_eq!(
+	portray("p(X) :- a, ( b(X) ; c, ( d -> e ; f ) ; g ), h."),
+	"p(A) :-\n    a,\n    (   b(A)\n    ;   c,\n        (   d\n        ->  e\n        ;   f\n        )\n    ;   g\n    ),\n    h.",
+    );
+    assert_eq!(portray("((a :- b) :- c)."), "(a :- b) :-\n    c.");
+    assert_eq!(portray(":- dynamic(foo/1)."), ":- dynamic foo/1.");
+}
+
+#[test]
+fn standard_order() {
+    let terms: Vec<Term> = ["f(b)", "1.0", "Z", "g(a)", "1", "f(a, b)", "b", "0.5", "[a]", "a", "f(a)", "2"].iter()
+	.map(|text| crate::parser::Parser::new(&format!("{}.", text)).read_term(Operators::default_table()).unwrap().unwrap())
+	.collect();
+    let mut sorted = terms.clone();
+    sorted.sort();
+    let sorted: Vec<String> = sorted.iter().map(|term| term.to_string()).collect();
+    assert_eq!(sorted, vec!["_G0", "0.5", "1.0", "1", "2", "a", "b", "f(a)", "f(b)", "g(a)", "[a]", "f(a,b)"]);
+
+    let x = |var| Term::Var(var);
+    let f = |args: Vec<Term>| Term::Str("f".into(), args.into());
+    assert!(is_variant(&f(vec![x(0), x(1), x(0)]), &f(vec![x(5), x(6), x(5)])));
+    assert!(!is_variant(&f(vec![x(0), x(1), x(0)]), &f(vec![x(5), x(5), x(5)])));
+    assert!(!is_variant(&f(vec![x(0), x(0)]), &f(vec![x(5), x(6)])));
+    assert!(!is_variant(&f(vec![x(0)]), &f(vec![Term::Atom("a".into())])));
+}
@@ -1,162 +1,483 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
 use std::iter::zip;
 
 use crate::term::Term;
 
-pub type Bindings = Option<HashMap<String, Term>>;
-
-pub fn unify(x: Term, y: Term, bindings: Bindings, occurs_check: bool) -> Bindings {
-    if x == y {
-	bindings
-    } else if let Term::Var(var) = x {
-	unify_variable(var, y, bindings, occurs_check)
-    } else if let Term::Var(var) = y {
-	unify_variable(var, x, bindings, occurs_check)
-    } else if let (Term::Str(f_x, args_x), Term::Str(f_y, args_y)) = (x, y) {
-	if f_x == f_y && args_x.len() == args_y.len() {
-	    zip(args_x, args_y).fold(bindings, |acc_bindings, (t_x, t_y)| {
-		unify(t_x, t_y, acc_bindings, occurs_check)
-	    })
+/// The values of the bound variables. The bindings that backtracking has
+/// to undo are recorded in a trail, so it undoes just the bindings made
+/// since a choicepoint instead of restoring a copy of all of them.
+///
+/// Only the variables that existed when the newest mark was taken are
+/// trailed: the ones made after it can't be reached once its bindings are
+/// undone. A deterministic computation leaves no marks, so its trail stays
+/// empty. The bindings that can't be reached anymore are dropped with
+/// `retain`, which the prover calls when `needs_collection` says so.
+#[derive(Debug, Default)]
+pub struct Bindings {
+    values: HashMap<usize, Term, BuildHasherDefault<VariableHasher>>,
+    trail: Vec<usize>,
+    /// The old values of the variables bound again, with their position in
+    /// the trail
+    overwritten: Vec<(usize, Term)>,
+    /// The number of the next fresh variable
+    next_variable: usize,
+    /// The variables numbered below this are trailed
+    boundary: usize,
+    /// The number of bindings after the last garbage collection
+    collected: usize,
+    /// The variables bound by the running unification that are not
+    /// trailed, to unbind them if it fails
+    untrailed: Vec<usize>,
+}
+
+/// Hashes variable numbers. They are consecutive integers, so they don't
+/// need a hash function that resists collisions made on purpose.
+#[derive(Default)]
+struct VariableHasher(u64);
+
+impl Hasher for VariableHasher {
+    fn finish(&self) -> u64 {
+	self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+	for byte in bytes {
+	    self.0 = (self.0 << 8 | *byte as u64).wrapping_mul(0x9e3779b97f4a7c15);
+	}
+    }
+
+    fn write_usize(&mut self, var: usize) {
+	self.0 = (var as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    }
+}
+
+/// A point to undo the bindings to, taken by `Bindings::mark`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mark {
+    /// The height of the trail
+    trail: usize,
+    /// The boundary of the trailed variables before the mark
+    boundary: usize,
+}
+
+/// Garbage is not collected until there are at least these many bindings
+const MIN_COLLECTION: usize = 1 << 12;
+
+impl Bindings {
+    pub fn new() -> Self {
+	Self::default()
+    }
+
+    pub fn get(&self, var: usize) -> Option<&Term> {
+	self.values.get(&var)
+    }
+
+    pub fn bind(&mut self, var: usize, value: Term) {
+	self.values.insert(var, value);
+	if var < self.boundary {
+	    self.trail.push(var);
+	}
+    }
+
+    /// Gives a bound variable another value. Backtracking gives it back
+    /// its old value.
+    pub fn rebind(&mut self, var: usize, value: Term) {
+	let old = self.values.insert(var, value);
+	if var < self.boundary {
+	    if let Some(old) = old {
+		self.overwritten.push((self.trail.len(), old));
+	    }
+	    self.trail.push(var);
+	}
+    }
+
+    /// Gives a bound variable another value that backtracking doesn't undo
+    pub fn set(&mut self, var: usize, value: Term) {
+	self.values.insert(var, value);
+    }
+
+    /// A variable that doesn't appear anywhere else
+    pub fn fresh_variable(&mut self) -> Term {
+	Term::Var(self.fresh_variables(1))
+    }
+
+    /// Reserves the numbers of `count` fresh variables and returns the first
+    /// one. A clause with variables numbered from 0 is renamed adding it to
+    /// them.
+    pub fn fresh_variables(&mut self, count: usize) -> usize {
+	let first = self.next_variable;
+	self.next_variable += count;
+	first
+    }
+
+    /// The number the next fresh variable will get
+    pub fn next_variable(&self) -> usize {
+	self.next_variable
+    }
+
+    /// Marks the point to undo the bindings made after now to. Until the
+    /// mark is undone or released, the bindings of every variable that
+    /// exists now are trailed.
+    pub fn mark(&mut self) -> Mark {
+	let mark = Mark { trail: self.trail.len(), boundary: self.boundary };
+	self.boundary = self.next_variable;
+	mark
+    }
+
+    /// Unbinds the variables bound since `mark`, and gives the ones bound
+    /// again their old values
+    pub fn undo(&mut self, mark: Mark) {
+	self.undo_trail(mark.trail);
+	self.boundary = mark.boundary;
+    }
+
+    fn undo_trail(&mut self, height: usize) {
+	while self.trail.len() > height {
+	    let var = self.trail.pop().expect("the trail is not empty");
+	    match self.overwritten.last() {
+		Some((position, _)) if *position == self.trail.len() => {
+		    let (_, old) = self.overwritten.pop().expect("an overwritten value");
+		    self.values.insert(var, old);
+		}
+		_ => {
+		    self.values.remove(&var);
+		}
+	    }
+	}
+    }
+
+    /// Binds a variable during a unification, which may still fail
+    fn bind_unifying(&mut self, var: usize, value: Term) {
+	self.values.insert(var, value);
+	if var < self.boundary {
+	    self.trail.push(var);
 	} else {
-	    None
+	    self.untrailed.push(var);
+	}
+    }
+
+    /// Unbinds the variables bound by a unification that failed, which
+    /// started with the trail at `height`
+    fn rollback(&mut self, height: usize) {
+	self.undo_trail(height);
+	for var in self.untrailed.drain(..) {
+	    self.values.remove(&var);
 	}
+    }
+
+    /// Keeps the bindings made since `mark`, which won't be undone to it.
+    /// The trail entries of the variables made after the marks that are
+    /// left are not needed anymore, so they are dropped.
+    pub fn release(&mut self, mark: Mark) {
+	self.boundary = mark.boundary;
+	let mut kept = mark.trail;
+	let mut kept_overwritten = self.overwritten.partition_point(|(position, _)| *position < mark.trail);
+	let mut next_overwritten = kept_overwritten;
+	for position in mark.trail..self.trail.len() {
+	    let var = self.trail[position];
+	    let overwritten = self.overwritten.get(next_overwritten).is_some_and(|(overwritten, _)| *overwritten == position);
+	    if overwritten {
+		next_overwritten += 1;
+	    }
+	    if var < self.boundary {
+		if overwritten {
+		    self.overwritten.swap(kept_overwritten, next_overwritten - 1);
+		    self.overwritten[kept_overwritten].0 = kept;
+		    kept_overwritten += 1;
+		}
+		self.trail[kept] = var;
+		kept += 1;
+	    }
+	}
+	self.trail.truncate(kept);
+	self.overwritten.truncate(kept_overwritten);
+    }
+
+    /// Whether there are enough new bindings since the last garbage
+    /// collection to do another one
+    pub fn needs_collection(&self) -> bool {
+	self.values.len() >= MIN_COLLECTION.max(2 * self.collected)
+    }
+
+    /// The old values of the variables bound again, which backtracking can
+    /// give back
+    pub fn overwritten_values(&self) -> impl Iterator<Item = &Term> {
+	self.overwritten.iter().map(|(_, old)| old)
+    }
+
+    /// Drops the bindings of the variables that are not in `live`
+    pub fn retain(&mut self, live: &HashSet<usize>) {
+	self.values.retain(|var, _| live.contains(var));
+	self.collected = self.values.len();
+    }
+
+    /// The number of bound variables
+    pub fn bound_count(&self) -> usize {
+	self.values.len()
+    }
+
+    /// The height of the trail
+    pub fn trail_len(&self) -> usize {
+	self.trail.len()
+    }
+}
+
+/// Unifies two terms, binding their variables. If they don't unify, the
+/// bindings are left as they were. It uses an explicit stack, so terms as
+/// deep as a long list don't exhaust the Rust stack.
+pub fn unify(x: &Term, y: &Term, bindings: &mut Bindings, occurs_check: bool) -> bool {
+    let height = bindings.trail.len();
+    if let Ok(true) = unify_terms(x, y, bindings, occurs_check) {
+	true
     } else {
-	None
+	bindings.rollback(height);
+	false
     }
 }
 
-#[inline]
-fn unify_variable(var: String, y: Term, bindings: Bindings, occurs_check_flag: bool) -> Bindings {
-    let bindings = bindings?;
-    match bindings.get(&var) {
-	Some(value) => unify(value.clone(), y, Some(bindings), occurs_check_flag),
-	None => {
-	    if let Term::Var(ref y_var) = y {
-		if let Some(y_val) = bindings.get(y_var) {
-		    return unify(Term::Var(var), y_val.clone(), Some(bindings), occurs_check_flag)
+/// Unifies two terms with the occurs check. If they don't unify because a
+/// variable would be bound to a term that contains it, it returns them and
+/// keeps the bindings made until then, which the caller undoes to a mark
+/// it took before after writing the term.
+pub fn unify_or_cycle(x: &Term, y: &Term, bindings: &mut Bindings) -> Result<bool, (usize, Term)> {
+    let height = bindings.trail.len();
+    let unified = unify_terms(x, y, bindings, true)?;
+    if !unified {
+	bindings.rollback(height);
+    }
+    Ok(unified)
+}
+
+/// Unifies two terms. If they don't unify the caller rolls back the
+/// bindings made.
+fn unify_terms(x: &Term, y: &Term, bindings: &mut Bindings, occurs_check: bool) -> Result<bool, (usize, Term)> {
+    bindings.untrailed.clear();
+    let mut pending = vec![(x.clone(), y.clone())];
+    while let Some((x, y)) = pending.pop() {
+	let unified = match (walk(x, bindings), walk(y, bindings)) {
+	    (Term::Var(x), Term::Var(y)) if x == y => true,
+	    (Term::Var(var), value) | (value, Term::Var(var)) => {
+		if occurs_check && occurs(var, &value, bindings) {
+		    return Err((var, value));
+		} else {
+		    bindings.bind_unifying(var, value);
+		    true
 		}
 	    }
+	    (Term::Str(f_x, args_x), Term::Str(f_y, args_y)) => {
+		if f_x == f_y && args_x.len() == args_y.len() {
+		    // The first arguments are unified first
+		    pending.extend(zip(args_x.iter().cloned(), args_y.iter().cloned()).rev());
+		    true
+		} else {
+		    false
+		}
+	    }
+	    (x, y) => x == y,
+	};
+	if !unified {
+	    return Ok(false);
+	}
+    }
+    Ok(true)
+}
 
-	    occurs_check(var.clone(), y.clone(), Some(bindings), occurs_check_flag).and_then(|mut bindings| {
-		bindings.insert(var, y.clone());
-		Some(bindings)
-	    })
-	   
+/// Follows the bindings of a variable until a value or an unbound variable
+pub fn walk(term: Term, bindings: &Bindings) -> Term {
+    let mut term = term;
+    while let Term::Var(ref var) = term {
+	match bindings.get(*var) {
+	    Some(value) => term = value.clone(),
+	    None => break,
 	}
     }
+    term
 }
 
-#[inline]
-fn occurs_check(var: String, y: Term, bindings: Bindings, occurs_check_flag: bool) -> Bindings {
-    if !occurs_check_flag {
-	bindings
-    } else if Term::Var(var.clone()) == y {
-	None
-    } else if let Term::Var(y_var) = y {
-	match bindings.clone()?.get(&y_var) {
-	    Some(y_val) => {
-		return occurs_check(var, y_val.clone(), bindings, occurs_check_flag);
-	    }
-	    None => bindings
+/// Whether the variable appears in the term
+fn occurs(var: usize, term: &Term, bindings: &Bindings) -> bool {
+    let mut pending = vec![term.clone()];
+    while let Some(term) = pending.pop() {
+	match walk(term, bindings) {
+	    Term::Var(y) if y == var => return true,
+	    Term::Str(_, args) => pending.extend(args.iter().cloned()),
+	    _ => (),
 	}
-    } else if let Term::Str(_, args) = y {
-	args.iter().fold(bindings, |acc_bindings, t_y| {
-	    occurs_check(var.clone(), t_y.clone(), acc_bindings, occurs_check_flag)
-	})
-    } else {
-	bindings
     }
+    false
+}
+
+#[cfg(test)]
+fn unify_new(x: Term, y: Term, occurs_check: bool) -> Option<Bindings> {
+    let mut bindings = Bindings::new();
+    if unify(&x, &y, &mut bindings, occurs_check) { Some(bindings) } else { None }
+}
+
+#[cfg(test)]
+fn bound(bindings: &Option<Bindings>) -> Option<Vec<(usize, Term)>> {
+    let mut values: Vec<(usize, Term)> = bindings.as_ref()?.values.clone().into_iter().collect();
+    values.sort_by_key(|(var, _)| *var);
+    Some(values)
 }
 
 #[test]
 fn unify_atoms() {
     let x = Term::Atom("duero".into());
     let y = Term::Atom("duero".into());
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    let expected = Some(HashMap::new());
-    assert_eq!(expected, bindings);
+    let bindings = unify_new(x, y, false);
+    assert_eq!(bound(&bindings), Some(vec![]));
 }
 
 #[test]
 fn unify_atoms_fail() {
     let x = Term::Atom("duero".into());
     let y = Term::Atom("pisuerga".into());
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    let expected = None;
-    assert_eq!(expected, bindings);
+    let bindings = unify_new(x, y, false);
+    assert!(bindings.is_none());
 }
 
 #[test]
 fn unify_atom_var() {
-    let x = Term::Var("River".into());
+    let x = Term::Var(0);
     let y = Term::Atom("duero".into());
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    let mut expected = HashMap::new();
-    expected.insert("River".into(), Term::Atom("duero".into()));
-    assert_eq!(Some(expected), bindings);
+    let bindings = unify_new(x, y, false);
+    let expected = vec![(0, Term::Atom("duero".into()))];
+    assert_eq!(bound(&bindings), Some(expected));
 }
 
 #[test]
 fn unify_atom_var_2() {
-    let y = Term::Var("River".into());
+    let y = Term::Var(0);
     let x = Term::Atom("duero".into());
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    let mut expected = HashMap::new();
-    expected.insert("River".into(), Term::Atom("duero".into()));
-    assert_eq!(Some(expected), bindings);
+    let bindings = unify_new(x, y, false);
+    let expected = vec![(0, Term::Atom("duero".into()))];
+    assert_eq!(bound(&bindings), Some(expected));
 }
 
 #[test]
 fn unify_var() {
-    let x = Term::Var("X".into());
-    let y = Term::Var("Y".into());
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    let mut expected = HashMap::new();
-    expected.insert("X".into(), Term::Var("Y".into()));
-    assert_eq!(Some(expected), bindings);
+    let x = Term::Var(0);
+    let y = Term::Var(1);
+    let bindings = unify_new(x, y, false);
+    let expected = vec![(0, Term::Var(1))];
+    assert_eq!(bound(&bindings), Some(expected));
 }
 
 #[test]
 fn unify_str() {
-    let x = Term::Str("f".into(), vec![Term::Var("X".into()), Term::Atom("b".into())]);
-    let y = Term::Str("f".into(), vec![Term::Atom("a".into()), Term::Var("Y".into())]);
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    let mut expected = HashMap::new();
-    expected.insert("X".into(), Term::Atom("a".into()));
-    expected.insert("Y".into(), Term::Atom("b".into()));    
-    assert_eq!(Some(expected), bindings);
+    let x = Term::Str("f".into(), vec![Term::Var(0), Term::Atom("b".into())].into());
+    let y = Term::Str("f".into(), vec![Term::Atom("a".into()), Term::Var(1)].into());
+    let bindings = unify_new(x, y, false);
+    let expected = vec![(0, Term::Atom("a".into())), (1, Term::Atom("b".into()))];
+    assert_eq!(bound(&bindings), Some(expected));
 }
 
 #[test]
 fn unify_str_fail() {
-    let x = Term::Str("f".into(), vec![Term::Var("X".into()), Term::Atom("b".into())]);
-    let y = Term::Str("g".into(), vec![Term::Atom("a".into()), Term::Var("Y".into())]);
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    assert_eq!(None, bindings);
+    let x = Term::Str("f".into(), vec![Term::Var(0), Term::Atom("b".into())].into());
+    let y = Term::Str("g".into(), vec![Term::Atom("a".into()), Term::Var(1)].into());
+    let bindings = unify_new(x, y, false);
+    assert!(bindings.is_none());
 }
 
 #[test]
 fn unify_str_fail_2() {
-    let x = Term::Str("f".into(), vec![Term::Var("X".into()), Term::Atom("b".into())]);
-    let y = Term::Str("f".into(), vec![Term::Atom("a".into())]);
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    assert_eq!(None, bindings);
+    let x = Term::Str("f".into(), vec![Term::Var(0), Term::Atom("b".into())].into());
+    let y = Term::Str("f".into(), vec![Term::Atom("a".into())].into());
+    let bindings = unify_new(x, y, false);
+    assert!(bindings.is_none());
 }
 
 #[test]
 fn unify_fxy_norvig_bug() {
-    let x = Term::Str("f".into(), vec![Term::Var("X".into()), Term::Var("Y".into())]);
-    let y = Term::Str("f".into(), vec![Term::Var("Y".into()), Term::Var("X".into())]);
-    let bindings = unify(x, y, Some(HashMap::new()), false);
-    let mut expected = HashMap::new();
-    expected.insert("X".into(), Term::Var("Y".into()));
-    assert_eq!(Some(expected), bindings);
+    let x = Term::Str("f".into(), vec![Term::Var(0), Term::Var(1)].into());
+    let y = Term::Str("f".into(), vec![Term::Var(1), Term::Var(0)].into());
+    let bindings = unify_new(x, y, false);
+    let expected = vec![(0, Term::Var(1))];
+    assert_eq!(bound(&bindings), Some(expected));
 }
 
 #[test]
 fn unify_cyclic() {
-    let x = Term::Var("X".into());
-    let y = Term::Str("f".into(), vec![Term::Var("X".into())]);
-    let bindings = unify(x, y, Some(HashMap::new()), true);
-    assert_eq!(None, bindings);
+    let x = Term::Var(0);
+    let y = Term::Str("f".into(), vec![Term::Var(0)].into());
+    let bindings = unify_new(x, y, true);
+    assert!(bindings.is_none());
+}
+
+#[test]
+fn undo_bindings() {
+    let mut bindings = Bindings::new();
+    bindings.fresh_variables(3);
+    assert!(unify(&Term::Var(0), &Term::Atom("a".into()), &mut bindings, false));
+    let mark = bindings.mark();
+    let x = Term::Str("f".into(), vec![Term::Var(1), Term::Atom("b".into())].into());
+    let y = Term::Str("f".into(), vec![Term::Atom("a".into()), Term::Atom("c".into())].into());
+    // A failed unification leaves no bindings behind
+    assert!(!unify(&x, &y, &mut bindings, false));
+    assert_eq!((bindings.get(1), bindings.trail_len()), (None, 0));
+    assert!(unify(&Term::Var(1), &Term::Var(2), &mut bindings, false));
+    bindings.undo(mark);
+    assert_eq!(bindings.get(1), None);
+    assert_eq!(bindings.get(0), Some(&Term::Atom("a".into())));
+}
+
+#[test]
+fn trail_only_older_variables() {
+    let a = Term::Atom("a".into());
+    let mut bindings = Bindings::new();
+    let old = bindings.fresh_variables(2);
+    // Without marks nothing is trailed
+    bindings.bind(old, a.clone());
+    assert_eq!(bindings.trail_len(), 0);
+    let mark = bindings.mark();
+    let new = bindings.fresh_variables(1);
+    bindings.bind(new, a.clone());
+    bindings.bind(old + 1, a.clone());
+    assert_eq!(bindings.trail_len(), 1);
+    bindings.undo(mark);
+    assert_eq!((bindings.get(old), bindings.get(old + 1)), (Some(&a), None));
+    // Released marks leave the trail as it was before them
+    let outer = bindings.mark();
+    let inner = bindings.mark();
+    bindings.bind(old + 1, a.clone());
+    bindings.rebind(old, a.clone());
+    bindings.release(inner);
+    assert_eq!(bindings.trail_len(), 2);
+    bindings.release(outer);
+    assert_eq!(bindings.trail_len(), 0);
+    assert_eq!((bindings.get(old), bindings.get(old + 1)), (Some(&a), Some(&a)));
+}
+
+#[test]
+fn find_cycles() {
+    let x = Term::Str("f".into(), vec![Term::Var(0), Term::Atom("a".into())].into());
+    let y = Term::Str("f".into(), vec![Term::Str("g".into(), vec![Term::Var(0)].into()), Term::Var(1)].into());
+    let mut bindings = Bindings::new();
+    bindings.fresh_variables(2);
+    let mark = bindings.mark();
+    assert_eq!(unify_or_cycle(&x, &y, &mut bindings), Err((0, Term::Str("g".into(), vec![Term::Var(0)].into()))));
+    bindings.undo(mark);
+    assert_eq!(unify_or_cycle(&x, &Term::Var(1), &mut bindings), Ok(true));
+}
+
+#[test]
+fn rebind_variables() {
+    let (a, b, c) = (Term::Atom("a".into()), Term::Atom("b".into()), Term::Atom("c".into()));
+    let mut bindings = Bindings::new();
+    bindings.fresh_variables(2);
+    let first = bindings.mark();
+    bindings.bind(0, a.clone());
+    let mark = bindings.mark();
+    bindings.rebind(0, b.clone());
+    bindings.bind(1, c.clone());
+    bindings.rebind(1, a.clone());
+    bindings.rebind(0, c.clone());
+    assert_eq!((bindings.get(0), bindings.get(1)), (Some(&c), Some(&a)));
+    bindings.undo(mark);
+    assert_eq!((bindings.get(0), bindings.get(1)), (Some(&a), None));
+    bindings.set(0, b.clone());
+    bindings.undo(first);
+    assert_eq!(bindings.get(0), None);
 }
@@ -1,13 +1,37 @@
-member(X, [X|Xs]).
-member(X, [Y|Xs]) :- member(X, Xs).
+% The zebra puzzle: who drinks water and who owns the zebra?
+%
+%   ?- zebra(Houses, WaterDrinker, ZebraOwner).
 
-nextto(X, Y, List) :- iright(X, Y, List).
-nextto(X, Y, List) :- iright(Y, X, List).
+member(X, [X|_]).
+member(X, [_|Xs]) :-
+    member(X, Xs).
 
-iright(Left, Right, [Left|[Right|Xs]]).
-iright(Left, Right, [X|Xs]) :- iright(Left, Right, Xs).
+nextto(X, Y, List) :-
+    iright(X, Y, List).
+nextto(X, Y, List) :-
+    iright(Y, X, List).
 
-eq(X, X).
+/* iright(Left, Right, List): Right is immediately to the right of Left */
+iright(Left, Right, [Left, Right|_]).
+iright(Left, Right, [_|Xs]) :-
+    iright(Left, Right, Xs).
 
+eq(X, X).
 
-zebra(H, W, Z) :- eq(H, [house(norwegian, X1, X2, X3, X4), X5, house(X6, X7, X8, milk, X9), X10, X11]), member(house(englishman, A1, A2, A3, red), H), member(house(spaniard, dog, B1, B2, B3), H), member(house(C1, C2, C3, coffee, green), H), member(house(ukrainian, D1, D2, tea, D3), H), iright(house(E1, E2, E3, E4, ivory), house(F1, F2, F3, F4, green), H), member(house(G1, snails, winston, G2, G3), H), member(house(H1, H2, kools, H3, yellow), H), nextto(house(I1, I2, chesterfield, I3, I4), house(J1, fox, J2, J3, J4), H), nextto(house(K1, K2, kools, K3, K4), house(L1, horse, L2, L3, L4), H), member(house(M1, M2, luckystrike, orangejuice, M3), H), member(house(japanese, N1, parliaments, N2, N3), H), nextto(house(norwegian, O1, O2, O3, O4), house(P1, P2, P3, P4, blue), H), member(house(W, R1, R2, water, R3), H), member(house(Z, zebra, S1, S2, S3), H).
+% Every house is house(Nationality, Pet, Smokes, Drinks, Color)
+zebra(H, W, Z) :-
+    eq(H, [house(norwegian, _, _, _, _), _, house(_, _, _, milk, _), _, _]),
+    member(house(englishman, _, _, _, red), H),
+    member(house(spaniard, dog, _, _, _), H),
+    member(house(_, _, _, coffee, green), H),
+    member(house(ukrainian, _, _, tea, _), H),
+    iright(house(_, _, _, _, ivory), house(_, _, _, _, green), H),
+    member(house(_, snails, winston, _, _), H),
+    member(house(_, _, kools, _, yellow), H),
+    nextto(house(_, _, chesterfield, _, _), house(_, fox, _, _, _), H),
+    nextto(house(_, _, kools, _, _), house(_, horse, _, _, _), H),
+    member(house(_, _, luckystrike, orangejuice, _), H),
+    member(house(japanese, _, parliaments, _, _), H),
+    nextto(house(norwegian, _, _, _, _), house(_, _, _, _, blue), H),
+    member(house(W, _, _, water, _), H),
+    member(house(Z, zebra, _, _, _), H).